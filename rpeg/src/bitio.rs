@@ -0,0 +1,125 @@
+use crate::error::RpegError;
+
+
+/*
+bit streams
+-------------------------
+bits are written most significant first, filling each byte from its high
+bit down; the last byte is padded with zero bits. signed values are
+stored in two's complement in the given number of bits
+*/
+
+
+// accumulates values of arbitrary bit widths into bytes
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    // bits not yet flushed to `bytes`, right-aligned
+    pending: u64,
+    pending_bits: u64,
+}
+
+impl BitWriter {
+
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+
+    // append the low `width` bits of an unsigned value (width at most 57)
+    pub fn write_unsigned(&mut self, value: u64, width: u64) {
+        debug_assert!(width <= 57);
+        if width == 0 {
+            return;
+        }
+        self.pending = (self.pending << width) | (value & ((1 << width) - 1));
+        self.pending_bits += width;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    // append a signed value in two's complement
+    pub fn write_signed(&mut self, value: i64, width: u64) {
+        self.write_unsigned(value as u64, width);
+    }
+
+    // number of bits written so far
+    pub fn bit_len(&self) -> u64 {
+        self.bytes.len() as u64 * 8 + self.pending_bits
+    }
+
+    // pad the last byte with zeros and return everything written
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.bytes.push((self.pending << (8 - self.pending_bits)) as u8);
+        }
+        self.bytes
+    }
+}
+
+
+// reads values of arbitrary bit widths back out of bytes
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    // position of the next bit to read
+    pos: u64,
+}
+
+impl<'a> BitReader<'a> {
+
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    // read `width` bits as an unsigned value (width at most 64)
+    pub fn read_unsigned(&mut self, width: u64) -> Result<u64, RpegError> {
+        if self.pos + width > self.bytes.len() as u64 * 8 {
+            return Err(RpegError::DimensionMismatch("payload ended in the middle of a value".to_string()));
+        }
+        let mut value = 0u64;
+        for _ in 0..width {
+            let byte = self.bytes[(self.pos / 8) as usize];
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    // read `width` bits as a two's complement signed value
+    pub fn read_signed(&mut self, width: u64) -> Result<i64, RpegError> {
+        let value = self.read_unsigned(width)?;
+        if width == 0 || width >= 64 {
+            return Ok(value as i64);
+        }
+        // sign-extend from the top bit of the field
+        let shift = 64 - width;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    // read a single bit
+    pub fn read_bit(&mut self) -> Result<bool, RpegError> {
+        Ok(self.read_unsigned(1)? == 1)
+    }
+
+    // number of bits read so far
+    pub fn bit_pos(&self) -> u64 {
+        self.pos
+    }
+
+    // check that nothing but the padding of the last byte is left unread
+    pub fn expect_end(&self) -> Result<(), RpegError> {
+        let used_bytes = self.pos.div_ceil(8) as usize;
+        if used_bytes != self.bytes.len() {
+            return Err(RpegError::DimensionMismatch(format!(
+                "expected {} bytes of payload, found {}",
+                used_bytes,
+                self.bytes.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+/*
+checksums used by the file formats
+-------------------------
+crc32 is the ieee 802.3 polynomial (reflected 0xEDB88320), the same
+one used by zlib and png
+*/
+
+
+// lookup table for one byte of crc32
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+
+// continue a crc32 over more bytes; start from 0
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &byte in bytes {
+        c = CRC32_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+
+// crc32 of a whole buffer
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
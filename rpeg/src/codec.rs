@@ -1,8 +1,19 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use crate::compression::*;
 use crate::decompression::*;
+use crate::error::RpegError;
+use crate::format::*;
+use crate::huffman;
+use crate::jpeg::{is_jpeg, read_jpeg, write_jpeg};
+use crate::png::{is_png, read_png, write_png};
+use crate::rangecoder;
+use crate::layout::CodewordLayout;
+use crate::metrics;
+use crate::structures::{BlockTransform, ChromaQuantizer, ChromaUpsampling, CHROMA_BITS, ColorSpace, Crt, DcPrediction, DctBlock, Dither, EncodedQuanta, EntropyCoding, QuantMatrix, QuantParams, Subsampling};
+use crate::pnm::{gray_to_rgb, is_pgm, read_pgm, read_ppm, rgb_to_gray, write_pgm, write_ppm};
 use array2::Array2;
-use csc411_image::{RgbImage, Rgb, Read, Write};
-use csc411_rpegio::{input_rpeg_data, output_rpeg_data};
+use csc411_image::{RgbImage, Rgb};
 
 
 // trim an array2 to ensure it has even dimensions
@@ -17,9 +28,9 @@ pub fn trim_array(pixel_array: Array2<Rgb>) -> Array2<Rgb> {
     for (c, r, pixel) in pixel_array.iter_row_major() {
         if c < new_width && r < new_height {
             *trimmed_pixel_array.get_mut(c, r).unwrap() = Rgb {
-                red: (*pixel).red,
-                green: (*pixel).green,
-                blue: (*pixel).blue,
+                red: pixel.red,
+                green: pixel.green,
+                blue: pixel.blue,
             };
         }
     }
@@ -27,109 +38,1010 @@ pub fn trim_array(pixel_array: Array2<Rgb>) -> Array2<Rgb> {
 }
 
 
-// complete compress function
-// input: filename of ppm from stdin
-// output: saved rpeg image (from codewords) to stdout (into file)
-pub fn compress(filename: Option<&str>) {
-    
-    // read image from file
-    let img = RgbImage::read((filename).as_deref()).unwrap();
-    // create array of pixels from image
-    let pixels = Array2::from_row_major(img.width as usize, img.height as usize, img.pixels).unwrap();
+// pad an array2 so both dimensions are a multiple of `multiple`,
+// filling the new rows and columns by replicating the last row and column
+pub fn pad_array<T: Clone>(array: Array2<T>, multiple: usize) -> Array2<T> {
+
+    // round width and height up to the next multiple
+    let new_width = array.width().div_ceil(multiple) * multiple;
+    let new_height = array.height().div_ceil(multiple) * multiple;
+    if new_width == array.width() && new_height == array.height() {
+        return array;
+    }
+
+    let mut values = Vec::with_capacity(new_width * new_height);
+    for r in 0..new_height {
+        for c in 0..new_width {
+            // clamp to the last real row and column
+            let source_c = c.min(array.width() - 1);
+            let source_r = r.min(array.height() - 1);
+            values.push(array.get(source_c, source_r).unwrap().clone());
+        }
+    }
+    Array2::from_row_major(new_width, new_height, values).unwrap()
+}
+
+
+// crop an array2 down to its top-left `width` x `height` elements
+pub fn crop_array<T: Clone>(array: Array2<T>, width: usize, height: usize) -> Array2<T> {
+    if width == array.width() && height == array.height() {
+        return array;
+    }
+    let values = array
+        .iter_row_major()
+        .filter(|(c, r, _)| *c < width && *r < height)
+        .map(|(_, _, value)| value.clone())
+        .collect();
+    Array2::from_row_major(width, height, values).unwrap()
+}
+
+
+// options for encoding an image
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    // 1 (smallest) to 100 (best); selects the coefficient quantizer
+    pub quality: u8,
+    // where each field goes in a codeword; None picks one to suit the other options
+    pub layout: Option<CodewordLayout>,
+    // 2x2 codewords, or 8x8 dct blocks with a quality-scaled matrix
+    pub transform: BlockTransform,
+    // fixed-width fields, huffman coded, or range coded
+    pub entropy: EntropyCoding,
+    // code a (or dc) as residuals from neighbouring blocks; needs entropy coding
+    pub prediction: DcPrediction,
+    // collapse runs of flat 2x2 blocks with repeating chroma; needs entropy coding
+    pub run_length: bool,
+    // code luma only and decode to pgm; the chosen layout becomes CodewordLayout::luma_only
+    pub grayscale: bool,
+    // resolution of the stored pb/pr planes
+    pub subsampling: Subsampling,
+    // bits per pb/pr index (4 to 8) with evenly spaced levels; None keeps the csc411 table.
+    // the chosen layout widens its pb and pr fields to match, and a given one must fit them
+    pub chroma_bits: Option<u64>,
+    // fit the pb/pr levels to the image and store them in the header
+    pub adaptive_chroma: bool,
+    // color matrix between rgb and y/pb/pr
+    pub colorspace: ColorSpace,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        EncoderConfig {
+            quality: 100,
+            layout: None,
+            transform: BlockTransform::default(),
+            entropy: EntropyCoding::default(),
+            prediction: DcPrediction::default(),
+            run_length: false,
+            grayscale: false,
+            subsampling: Subsampling::default(),
+            chroma_bits: None,
+            adaptive_chroma: false,
+            colorspace: ColorSpace::default(),
+        }
+    }
+}
+
+
+// options for decoding an image
+#[derive(Debug, Clone, Default)]
+pub struct DecoderConfig {
+    // smooth block edges after the inverse transform with this strength
+    // (1.0 as designed, higher to smooth more); None leaves them alone
+    pub deblock: Option<f32>,
+    // how subsampled chroma is spread back over the pixels
+    pub upsampling: ChromaUpsampling,
+    // how rounding error is spread when pixels are turned into integers
+    pub dither: Dither,
+}
+
+
+// summary of an encoded image
+#[derive(Debug, Clone)]
+pub struct EncodeStats {
+    pub width: usize,
+    pub height: usize,
+    // number of luma blocks coded: 2x2 codewords, or 8x8 dct blocks
+    pub codewords: usize,
+    pub bytes_written: usize,
+}
+
+
+// summary of a decoded image
+#[derive(Debug, Clone)]
+pub struct DecodeStats {
+    pub width: usize,
+    pub height: usize,
+    pub bytes_written: usize,
+    // red, green and blue samples that decoded outside the output range and were clamped
+    pub clipped: [usize; 3],
+}
+
+
+// average the pb/pr planes down to the resolution `subsampling` keeps, and index them with `quantizer`
+// input: Array2<Crt> with even dimensions
+// output: Array2<EncodedQuanta> holding the pb/pr indices of each chroma sample
+pub fn compress_chroma(crt_array: Array2<Crt>, subsampling: Subsampling, quantizer: &ChromaQuantizer) -> Result<Array2<EncodedQuanta>, RpegError> {
+    let (width, height) = subsampling.plane_size(crt_array.width(), crt_array.height());
+    // average pb and pr values
+    let quantized_array = average_pb_pr_in(crt_array, new_quantized_array(width, height), subsampling)?;
+    // index each pb and pr value
+    index_of_chroma_array(quantized_array, new_encoded_quanta_array(width, height), quantizer)
+}
+
+
+// reverse compress_chroma, spreading every chroma sample over the crts that share it
+// input: Array2<EncodedQuanta> of chroma samples, Array2<Crt> the size of the image
+// (with luma already set when upsampling follows it), the subsampling, quantizer and upsampling
+// output: Array2<Crt> (updated)
+pub fn decompress_chroma(chroma: Array2<EncodedQuanta>, crt_array: Array2<Crt>, subsampling: Subsampling, quantizer: &ChromaQuantizer, upsampling: ChromaUpsampling) -> Result<Array2<Crt>, RpegError> {
+    // reverse the indexing with the quantizer's levels
+    let decoded_quanta_array = new_quantized_array(chroma.width(), chroma.height());
+    let decoded_quanta_array = chroma_of_index_array(chroma, decoded_quanta_array, quantizer)?;
+    // set pb and pr values of crt array to corresponding averages, or interpolate between them
+    match upsampling {
+        ChromaUpsampling::Replicate => set_pb_pr_in(decoded_quanta_array, crt_array, subsampling),
+        ChromaUpsampling::Bilinear => upsample_pb_pr(decoded_quanta_array, crt_array, subsampling, false),
+        ChromaUpsampling::LumaGuided => upsample_pb_pr(decoded_quanta_array, crt_array, subsampling, true),
+    }
+}
+
+
+// fit adaptive pb/pr levels to the chroma samples an image will store
+// input: Array2<Rgb> with even dimensions and samples up to maxval, the color matrix,
+// subsampling and bits per index it will be coded with
+// output: ChromaQuantizer::Adaptive with at most 2^bits levels per plane
+pub fn fit_chroma_quantizer(pixels: &Array2<Rgb>, maxval: u16, colorspace: ColorSpace, subsampling: Subsampling, bits: u64) -> Result<ChromaQuantizer, RpegError> {
+    let crt_array = rgb_float_array_to_crt_array_in(normalize_rgb_int_array(pixels.clone(), maxval), colorspace);
+    let (width, height) = subsampling.plane_size(crt_array.width(), crt_array.height());
+    let averages = average_pb_pr_in(crt_array, new_quantized_array(width, height), subsampling)?;
+    let pb_values: Vec<f32> = averages.get_data().iter().map(|quanta| quanta.pb_avg).collect();
+    let pr_values: Vec<f32> = averages.get_data().iter().map(|quanta| quanta.pr_avg).collect();
+    Ok(ChromaQuantizer::Adaptive {
+        bits,
+        pb_levels: fit_chroma_levels(&pb_values, bits),
+        pr_levels: fit_chroma_levels(&pr_values, bits),
+    })
+}
+
+
+// run the compression pipeline on an array of pixels, up to quantization
+// input: Array2<Rgb> with even dimensions and samples up to maxval, converted with the
+// given color matrix; grayscale images skip chroma entirely
+// output: (Array2<EncodedQuanta>, one per 2x2 block with the chroma of its top-left
+// sample, Array2<EncodedQuanta> holding every chroma sample)
+pub fn compress_to_quanta(pixels: Array2<Rgb>, maxval: u16, colorspace: ColorSpace, quant: &QuantParams, grayscale: bool, subsampling: Subsampling, quantizer: &ChromaQuantizer) -> Result<(Array2<EncodedQuanta>, Array2<EncodedQuanta>), RpegError> {
 
-    // trim array to ensure even dimensions
-    let pixels = trim_array(pixels);
-    // save width and height for later
-    let final_width = pixels.width() as usize;
-    let final_height = pixels.height() as usize;
     // convert array of rgbs (int) to rgb floats
-    let rgb_int_array = rgb_int_array_to_rgb_float_array(pixels.clone());
+    let rgb_int_array = normalize_rgb_int_array(pixels, maxval);
     // convert array of rgb floats to crts
-    let crt_array = rgb_float_array_to_crt_array(rgb_int_array.clone());
+    let crt_array = rgb_float_array_to_crt_array_in(rgb_int_array, colorspace);
     // create empty unencoded quantized array
     let quantized_array = new_quantized_array(crt_array.width() / 2, crt_array.height() / 2);
     // perform dct on crts
-    let quantized_array = dct(crt_array.clone(), quantized_array.clone());
-    // average pb and pr values
-    let quantized_array = average_pb_pr(crt_array.clone(), quantized_array.clone());
-
+    let quantized_array = try_dct(crt_array.clone(), quantized_array)?;
     // create new empty encoded quantized array
     let encoded_quanta_array = new_encoded_quanta_array(quantized_array.width(), quantized_array.height());
-    // index_of_chroma on each pb and pr value
-    let encoded_quanta_array = index_of_chroma_array(quantized_array.clone(), encoded_quanta_array.clone());
     // encode coefficients
-    let encoded_quanta_array = encode_coefficients_array(quantized_array.clone(), encoded_quanta_array.clone());
-    
+    let mut encoded_quanta_array = encode_coefficients_array(quantized_array, encoded_quanta_array, quant)?;
+
+    // chroma indices of grayscale images stay zero
+    let chroma = if grayscale {
+        let (width, height) = subsampling.plane_size(crt_array.width(), crt_array.height());
+        new_encoded_quanta_array(width, height)
+    } else {
+        compress_chroma(crt_array, subsampling, quantizer)?
+    };
+    set_block_chroma(&mut encoded_quanta_array, &chroma, subsampling);
+    Ok((encoded_quanta_array, chroma))
+}
+
+
+// run the compression pipeline on an array of pixels, with 4:2:0 csc411 chroma
+// input: Array2<Rgb> with even dimensions
+// output: Array2<u64> of codewords, one per 2x2 block
+pub fn compress_pixels(pixels: Array2<Rgb>, quant: &QuantParams, layout: &CodewordLayout) -> Result<Array2<u64>, RpegError> {
     // create codewords from quantized array
-    let codewords = pack_encoded_quanta(encoded_quanta_array.clone());
+    let (quanta, _) = compress_to_quanta(pixels, 255, ColorSpace::Bt601, quant, !layout.has_chroma(), Subsampling::Yuv420, &ChromaQuantizer::Csc411)?;
+    pack_encoded_quanta(quanta, layout)
+}
 
-    // make vec of .to_be_bytes() from codewords
-    let mut codewords_vec: Vec<[u8; 4]> = Vec::new();
-    for (_, _, codeword) in codewords.iter_row_major() {
-        codewords_vec.push(codeword.to_be_bytes());
-    }
-    output_rpeg_data(&codewords_vec, final_width, final_height).unwrap();
+
+// run the decompression pipeline on an array of codewords with 4:2:0 csc411 chroma
+// input: Array2<u64> of codewords, one per 2x2 block, and the quantizer and layout they were made with
+// output: Array2<Rgb> twice the width and height of the codeword array
+pub fn decompress_codewords(codewords: Array2<u64>, quant: &QuantParams, layout: &CodewordLayout) -> Result<Array2<Rgb>, RpegError> {
+    // unpack codewords to encoded quanta array; with 4:2:0 each block holds its only chroma sample
+    let quanta = extract_encoded_data(codewords, layout)?;
+    let crt_array = decompress_quanta(quanta.clone(), quanta, quant, !layout.has_chroma(), Subsampling::Yuv420, &ChromaQuantizer::Csc411, &DecoderConfig::default())?;
+    Ok(rgb_float_array_to_rgb_int_array(crt_array_to_rgb_float_array(crt_array)))
 }
 
 
-// complete decompress function
-// input: filename from stdin
-// output: saved ppm image (from codewords) to stdout (into file)
-pub fn decompress(filename: Option<&str>) {
-    
-    // read rpeg from file
-    let (compressed_data, width, height) = input_rpeg_data(filename).unwrap();
-
-    // convert rpeg data (vec of 4-length arrays of u8s) to codewords (Array2 of u32s)
-    let mut codewords_vec: Vec<u32> = Vec::new();
-    for i in 0..compressed_data.len() {
-        let mut bytes = [0; 4];
-        bytes.copy_from_slice(&compressed_data[i]);
-        codewords_vec.push(u32::from_be_bytes(bytes));
-    }
-
-    // convert codewords (vec of u32s) to Array2 struct
-    let codewords = Array2::from_row_major(width as usize / 2, height as usize / 2, codewords_vec).unwrap();
-    
-    // unpack codewords to encoded quanta array
-    let encoded_quanta_array = extract_encoded_data(codewords);
+// run the decompression pipeline from quantized values
+// input: Array2<EncodedQuanta>, one per 2x2 block, the chroma samples, the quantizer
+// they were made with, whether they carry chroma at all, the chroma subsampling and quantizer,
+// and the decoder options
+// output: Array2<Crt> twice the width and height of the quanta array, with zero chroma if
+// grayscale, to be turned into pixels with the image's color matrix once any padding is cropped
+pub fn decompress_quanta(encoded_quanta_array: Array2<EncodedQuanta>, chroma: Array2<EncodedQuanta>, quant: &QuantParams, grayscale: bool, subsampling: Subsampling, quantizer: &ChromaQuantizer, config: &DecoderConfig) -> Result<Array2<Crt>, RpegError> {
+
+    let width = encoded_quanta_array.width() * 2;
+    let height = encoded_quanta_array.height() * 2;
+
     // make a new empty decoded quantized array
     let decoded_quanta_array = new_quantized_array(encoded_quanta_array.width(), encoded_quanta_array.height());
-    // reverse index_of_chroma by using chroma_of_index
-    let decoded_quanta_array = chroma_of_index_array(encoded_quanta_array.clone(), decoded_quanta_array.clone());
     // decode coefficients
-    let decoded_quanta_array = decode_coefficients_array(encoded_quanta_array.clone(), decoded_quanta_array.clone());
+    let decoded_quanta_array = decode_coefficients_array(encoded_quanta_array, decoded_quanta_array, quant)?;
 
     // create empty crt array
     let crt_array = new_crt_array(width, height);
-    // set pb and pr values of crt array to corresponding averages
-    let crt_array = set_pb_pr(decoded_quanta_array.clone(), crt_array.clone());
+    // b, c and d say how much detail each block has, for deblocking
+    let detail = quanta_detail(&decoded_quanta_array);
     // perform inverse dct
-    let crt_array = inverse_dct(decoded_quanta_array.clone(), crt_array.clone());
-
-    // convert crt array to rgb float array
-    let rgb_float_array = crt_array_to_rgb_float_array(crt_array.clone());
-    // convert rgb float array to rgb int array
-    let rgb_int_array = rgb_float_array_to_rgb_int_array(rgb_float_array.clone());
-
-    // convert rgb int array to vector of rgb ints
-    let mut pixels: Vec<Rgb> = Vec::new();
-    for (_, _, rgb_int) in rgb_int_array.iter_row_major() {
-        pixels.push(Rgb{
-            red: rgb_int.red,
-            green: rgb_int.green,
-            blue: rgb_int.blue
-        });
+    let crt_array = try_inverse_dct(decoded_quanta_array, crt_array)?;
+    // set pb and pr values of crt array from the chroma samples, once luma is there to guide
+    // them; gray pixels keep zero chroma
+    let crt_array = if grayscale { crt_array } else { decompress_chroma(chroma, crt_array, subsampling, quantizer, config.upsampling)? };
+    // one level of b, c or d moves a pixel by this much
+    let luma_step = quant.bcd_range / quant.bcd_levels.max(1) as f32;
+    deblock(crt_array, &detail, 2, luma_step, (!grayscale).then_some((subsampling, quantizer)), config)
+}
+
+
+// run the 8x8 dct pipeline on an array of pixels
+// input: Array2<Rgb> with dimensions a multiple of 8 and samples up to maxval, converted with
+// the given color matrix; grayscale images skip chroma entirely
+// output: (Array2<DctBlock> of quantized luma, Array2<EncodedQuanta> holding the pb/pr indices of each chroma sample)
+pub fn compress_pixels_dct(pixels: Array2<Rgb>, maxval: u16, colorspace: ColorSpace, matrix: &QuantMatrix, grayscale: bool, subsampling: Subsampling, quantizer: &ChromaQuantizer) -> Result<(Array2<DctBlock>, Array2<EncodedQuanta>), RpegError> {
+
+    // convert pixels to crts
+    let crt_array = rgb_float_array_to_crt_array_in(normalize_rgb_int_array(pixels, maxval), colorspace);
+    // transform and quantize luma in 8x8 blocks
+    let blocks = dct_8x8(crt_array.clone(), matrix)?;
+
+    // chroma goes through the same averaging and indexing as the 2x2 transform
+    let chroma = if grayscale {
+        let (width, height) = subsampling.plane_size(crt_array.width(), crt_array.height());
+        new_encoded_quanta_array(width, height)
+    } else {
+        compress_chroma(crt_array, subsampling, quantizer)?
+    };
+    Ok((blocks, chroma))
+}
+
+
+// run the 8x8 dct decompression pipeline
+// input: Array2<DctBlock>, Array2<EncodedQuanta> with pb/pr indices per chroma sample, quantization matrix,
+// whether the image carries chroma at all, the chroma subsampling and quantizer, and the decoder options
+// output: Array2<Crt> 8 times the width and height of the block array, with zero chroma if grayscale
+pub fn decompress_dct_blocks(blocks: Array2<DctBlock>, chroma: Array2<EncodedQuanta>, matrix: &QuantMatrix, grayscale: bool, subsampling: Subsampling, quantizer: &ChromaQuantizer, config: &DecoderConfig) -> Result<Array2<Crt>, RpegError> {
+
+    // dequantize and inverse transform luma
+    let crt_array = new_crt_array(blocks.width() * 8, blocks.height() * 8);
+    let detail = dct_block_detail(&blocks, matrix);
+    let crt_array = inverse_dct_8x8(blocks, matrix, crt_array)?;
+    // reverse the chroma indexing and spread each sample over the pixels that share it
+    let crt_array = if grayscale { crt_array } else { decompress_chroma(chroma, crt_array, subsampling, quantizer, config.upsampling)? };
+    // a level of the average frequency moves the block's mean by about this much
+    let luma_step = matrix.steps.iter().map(|&step| step as f32).sum::<f32>() / (64.0 * 8.0 * 255.0);
+    deblock(crt_array, &detail, 8, luma_step, (!grayscale).then_some((subsampling, quantizer)), config)
+}
+
+
+// deblock luma, and chroma if the image has any (as its subsampling and quantizer),
+// if the decoder options ask for it; interpolated chroma has no block edges to smooth
+fn deblock(crt_array: Array2<Crt>, detail: &Array2<f32>, block_size: usize, luma_step: f32, chroma: Option<(Subsampling, &ChromaQuantizer)>, config: &DecoderConfig) -> Result<Array2<Crt>, RpegError> {
+    let Some(strength) = config.deblock else {
+        return Ok(crt_array);
+    };
+    let crt_array = deblock_luma(crt_array, detail, block_size, luma_step, strength)?;
+    match chroma {
+        Some((subsampling, quantizer)) if config.upsampling == ChromaUpsampling::Replicate => deblock_chroma(crt_array, subsampling, quantizer.step(), strength),
+        _ => Ok(crt_array),
+    }
+}
+
+
+// entropy code field streams for a block grid `grid_width` blocks wide,
+// replacing the first stream (a, or dc) with its prediction residuals if
+// the header asks for it. grayscale images leave out the pb and pr streams
+fn encode_field_streams(mut streams: Vec<Vec<i64>>, grid_width: usize, header: &Header) -> Result<Vec<u8>, RpegError> {
+    if header.grayscale {
+        streams.truncate(streams.len() - 2);
+    }
+    if header.prediction == DcPrediction::Med {
+        streams[0] = dc_prediction_residuals(&streams[0], grid_width);
+    }
+    match header.entropy {
+        EntropyCoding::Huffman => huffman::encode_streams(&streams),
+        EntropyCoding::RangeCoder => Ok(rangecoder::encode_streams(&streams)),
+        EntropyCoding::Fixed => unreachable!("fixed-width payloads are not coded as field streams"),
+    }
+}
+
+
+// decode the field streams written by encode_field_streams
+fn decode_field_streams(payload: &[u8], counts: &[usize], grid_width: usize, header: &Header) -> Result<Vec<Vec<i64>>, RpegError> {
+    // chroma is always the last two streams
+    let coded = if header.grayscale { &counts[..counts.len() - 2] } else { counts };
+    let mut streams = match header.entropy {
+        EntropyCoding::Huffman => huffman::decode_streams(payload, coded)?,
+        EntropyCoding::RangeCoder => rangecoder::decode_streams(payload, coded)?,
+        EntropyCoding::Fixed => unreachable!("fixed-width payloads are not coded as field streams"),
+    };
+    if header.prediction == DcPrediction::Med {
+        streams[0] = dc_from_prediction_residuals(&streams[0], grid_width);
+    }
+    // grayscale chroma indices are all zero
+    streams.extend(counts[coded.len()..].iter().map(|&count| vec![0; count]));
+    Ok(streams)
+}
+
+
+// serialize the quanta and chroma samples of the 2x2 transform as the header describes
+fn quanta_to_payload(quanta: Array2<EncodedQuanta>, chroma: &Array2<EncodedQuanta>, header: &Header) -> Result<Vec<u8>, RpegError> {
+    match header.entropy {
+        EntropyCoding::Fixed => {
+            let extra_chroma = extra_chroma_to_bytes(chroma, header.subsampling, header.chroma_bits());
+            let mut payload = codewords_to_bytes(pack_encoded_quanta(quanta, &header.layout)?.get_data(), &header.layout);
+            payload.extend(extra_chroma);
+            Ok(payload)
+        }
+        _ if header.run_length => {
+            let rle = run_length_encode(&quanta);
+            // the decoder needs the number of literals to know how long the streams are
+            let literals = u32::try_from(rle.literals.len())
+                .map_err(|_| RpegError::DimensionMismatch("too many blocks for an rpeg file".to_string()))?;
+            let mut payload = literals.to_be_bytes().to_vec();
+            payload.extend(encode_field_streams(run_length_to_streams(&rle), quanta.width(), header)?);
+            Ok(payload)
+        }
+        _ => encode_field_streams(quanta_to_streams(&quanta, chroma), quanta.width(), header),
+    }
+}
+
+
+// parse the quanta and chroma samples of the 2x2 transform for a `width` x `height` block grid
+fn payload_to_quanta(payload: &[u8], header: &Header, width: usize, height: usize) -> Result<(Array2<EncodedQuanta>, Array2<EncodedQuanta>), RpegError> {
+    let blocks = checked_area(width, height)?;
+    match header.entropy {
+        EntropyCoding::Fixed => {
+            // any chroma samples that do not fit in the codewords follow them
+            let codeword_bytes = blocks.checked_mul(header.layout.word_bytes())
+                .ok_or_else(|| RpegError::DimensionMismatch(format!("a {}x{} block grid is too large", width, height)))?;
+            if payload.len() < codeword_bytes {
+                return Err(RpegError::DimensionMismatch(format!(
+                    "expected {} bytes of codewords, found {}",
+                    codeword_bytes,
+                    payload.len()
+                )));
+            }
+            let (payload, extra_chroma) = payload.split_at(codeword_bytes);
+            let codewords_vec = bytes_to_codewords(payload, &header.layout, blocks)?;
+            // convert codewords (vec of u64s) to Array2 struct
+            let codewords = Array2::from_row_major(width, height, codewords_vec)
+                .map_err(RpegError::DimensionMismatch)?;
+            let quanta = extract_encoded_data(codewords, &header.layout)?;
+            let chroma = bytes_to_chroma(extra_chroma, &quanta, header.subsampling, header.chroma_bits())?;
+            Ok((quanta, chroma))
+        }
+        _ if header.run_length => {
+            let (literals, payload) = payload.split_first_chunk::<4>()
+                .ok_or_else(|| RpegError::DimensionMismatch("payload is missing its literal count".to_string()))?;
+            let literals = u32::from_be_bytes(*literals) as usize;
+            if literals > blocks {
+                return Err(RpegError::DimensionMismatch(format!("{} literal blocks in a {}x{} grid", literals, width, height)));
+            }
+            let streams = decode_field_streams(payload, &run_length_stream_counts(blocks, literals), width, header)?;
+            // run-length coding is only used with 4:2:0, where each block holds its only chroma sample
+            let quanta = expand_runs(streams_to_run_length(&streams, blocks, literals)?, width, height)?;
+            Ok((quanta.clone(), quanta))
+        }
+        _ => {
+            let streams = decode_field_streams(payload, &quanta_stream_counts(width, height, header.subsampling)?, width, header)?;
+            streams_to_quanta(&streams, width, height, header.subsampling)
+        }
+    }
+}
+
+
+// serialize the blocks and chroma of the 8x8 dct as the header describes
+fn dct_blocks_to_payload(blocks: &Array2<DctBlock>, chroma: &Array2<EncodedQuanta>, matrix: &QuantMatrix, header: &Header) -> Result<Vec<u8>, RpegError> {
+    match header.entropy {
+        EntropyCoding::Fixed => Ok(dct_blocks_to_bytes(blocks, chroma, matrix, header.chroma_bits())),
+        _ => encode_field_streams(dct_blocks_to_streams(blocks, chroma), blocks.width(), header),
+    }
+}
+
+
+// parse the blocks and chroma of the 8x8 dct for an image padded to `width` x `height`
+fn payload_to_dct_blocks(payload: &[u8], matrix: &QuantMatrix, header: &Header, width: usize, height: usize) -> Result<(Array2<DctBlock>, Array2<EncodedQuanta>), RpegError> {
+    let (blocks, chroma) = match header.entropy {
+        EntropyCoding::Fixed => bytes_to_dct_blocks(payload, matrix, width, height, header.chroma_bits(), header.subsampling)?,
+        _ => {
+            let streams = decode_field_streams(payload, &dct_stream_counts(width, height, header.subsampling)?, width / 8, header)?;
+            streams_to_dct_blocks(&streams, width, height, header.subsampling)?
+        }
+    };
+    check_dct_levels(&blocks, matrix)?;
+    Ok((blocks, chroma))
+}
+
+
+// image file formats that decoded images can be written as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+
+    // the format for an output file, by its extension (netpbm unless it ends in .png)
+    pub fn from_filename(filename: &str) -> Self {
+        if filename.to_ascii_lowercase().ends_with(".png") {
+            ImageFormat::Png
+        } else {
+            ImageFormat::Ppm
+        }
+    }
+}
+
+
+// read the pixels of a ppm, pgm, png or jpeg image, told apart by their magic numbers,
+// along with the image's maxval
+fn read_image(bytes: &[u8]) -> Result<(Array2<Rgb>, u16), RpegError> {
+    if is_jpeg(bytes) {
+        return Ok((read_jpeg(bytes)?, 255));
     }
+    let img = if is_png(bytes) {
+        read_png(bytes)?
+    } else if is_pgm(bytes) {
+        gray_to_rgb(read_pgm(&mut &bytes[..])?)
+    } else {
+        read_ppm(&mut &bytes[..])?
+    };
+
+    // create array of pixels from image
+    let pixels = Array2::from_row_major(img.width as usize, img.height as usize, img.pixels)
+        .map_err(RpegError::DimensionMismatch)?;
+    Ok((pixels, img.denominator.max(1)))
+}
+
+
+// rescale pixels with samples up to maxval to 8 bits
+fn to_8_bits(pixels: Array2<Rgb>, maxval: u16) -> Array2<Rgb> {
+    if maxval == 255 {
+        return pixels;
+    }
+    let denominator = maxval.max(1) as u32;
+    let scale = |sample: u16| ((sample.min(maxval) as u32 * 255 + denominator / 2) / denominator) as u16;
+    let scaled = pixels.get_data().iter().map(|pixel| Rgb { red: scale(pixel.red), green: scale(pixel.green), blue: scale(pixel.blue) }).collect();
+    Array2::from_row_major(pixels.width(), pixels.height(), scaled).unwrap()
+}
+
+
+// read the pixels of an image rescaled to 8 bits, as the metrics measure them
+fn read_pixels(bytes: &[u8]) -> Result<Array2<Rgb>, RpegError> {
+    let (pixels, maxval) = read_image(bytes)?;
+    Ok(to_8_bits(pixels, maxval))
+}
+
+
+// encode a ppm, pgm, png or jpeg image read from `reader` into rpeg data written to `writer`
+pub fn encode<R: Read, W: Write>(mut reader: R, mut writer: W, config: &EncoderConfig) -> Result<EncodeStats, RpegError> {
+
+    if config.grayscale && (config.chroma_bits.is_some() || config.adaptive_chroma) {
+        return Err(RpegError::InvalidConfig("grayscale images have no chroma to quantize".to_string()));
+    }
+    let chroma_bits = config.chroma_bits.unwrap_or(4);
+    if !CHROMA_BITS.contains(&chroma_bits) {
+        return Err(RpegError::InvalidConfig(format!("chroma indices need 4 to 8 bits, got {}", chroma_bits)));
+    }
+
+    // a given layout is used as it is; otherwise grayscale images leave the chroma
+    // fields out, and wider chroma indices widen them
+    let layout = match config.layout {
+        Some(layout) => layout,
+        None if config.grayscale => CodewordLayout::luma_only(),
+        None => CodewordLayout::with_chroma_bits(chroma_bits),
+    };
+    layout.validate()?;
+    if config.grayscale == layout.has_chroma() {
+        return Err(RpegError::InvalidConfig(format!(
+            "{} images need a layout {} pb and pr fields, got \"{}\"",
+            if config.grayscale { "grayscale" } else { "color" },
+            if config.grayscale { "without" } else { "with" },
+            layout
+        )));
+    }
+    if layout.has_chroma() && layout.pb.width.min(layout.pr.width) < chroma_bits {
+        return Err(RpegError::InvalidConfig(format!("{}-bit chroma indices do not fit the layout \"{}\"", chroma_bits, layout)));
+    }
+    if config.prediction != DcPrediction::None && config.entropy == EntropyCoding::Fixed {
+        return Err(RpegError::InvalidConfig("dc prediction needs huffman or range coding".to_string()));
+    }
+    if config.run_length && (config.entropy == EntropyCoding::Fixed || config.transform != BlockTransform::Haar2x2) {
+        return Err(RpegError::InvalidConfig("run-length coding needs the 2x2 transform and huffman or range coding".to_string()));
+    }
+    if config.subsampling != Subsampling::Yuv420 && (config.grayscale || config.run_length) {
+        return Err(RpegError::InvalidConfig("grayscale images and run-length coding need 4:2:0 subsampling".to_string()));
+    }
+    let quant = quant_params_for_quality(config.quality, &layout)?;
+
+    // read image
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let (pixels, maxval) = read_image(&input)?;
+
+    // save the true width and height for the header
+    let final_width = pixels.width();
+    let final_height = pixels.height();
+    let chroma_quant = if config.adaptive_chroma {
+        fit_chroma_quantizer(&pad_array(pixels.clone(), 2), maxval, config.colorspace, config.subsampling, chroma_bits)?
+    } else if config.chroma_bits.is_some() {
+        ChromaQuantizer::Uniform { bits: chroma_bits }
+    } else {
+        ChromaQuantizer::Csc411
+    };
+    let dct_matrix = match config.transform {
+        BlockTransform::Haar2x2 => None,
+        BlockTransform::Dct8x8 => Some(quant_matrix_for_quality(config.quality)?),
+    };
+    let header = Header {
+        width: final_width,
+        height: final_height,
+        quant,
+        layout,
+        colorspace: config.colorspace,
+        subsampling: config.subsampling,
+        transform: config.transform,
+        dct_matrix,
+        entropy: config.entropy,
+        prediction: config.prediction,
+        run_length: config.run_length,
+        grayscale: config.grayscale,
+        chroma_quant,
+        maxval,
+    };
+
+    let (payload, codewords) = match dct_matrix {
+        None => {
+            // pad array to ensure even dimensions
+            let pixels = pad_array(pixels, 2);
+            let (quanta, chroma) = compress_to_quanta(pixels, maxval, header.colorspace, &quant, header.grayscale, header.subsampling, &header.chroma_quant)?;
+            let count = quanta.get_data().len();
+            (quanta_to_payload(quanta, &chroma, &header)?, count)
+        }
+        Some(matrix) => {
+            // pad array to a whole number of 8x8 blocks
+            let pixels = pad_array(pixels, 8);
+            let (blocks, chroma) = compress_pixels_dct(pixels, maxval, header.colorspace, &matrix, header.grayscale, header.subsampling, &header.chroma_quant)?;
+            (dct_blocks_to_payload(&blocks, &chroma, &matrix, &header)?, blocks.get_data().len())
+        }
+    };
+
+    // write codewords in the rpeg format
+    let mut bytes = Vec::new();
+    write_rpeg(&mut bytes, &header, &payload)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+
+    Ok(EncodeStats {
+        width: final_width,
+        height: final_height,
+        codewords,
+        bytes_written: bytes.len(),
+    })
+}
+
+
+// decode rpeg data read from `reader` into an image
+pub fn decode_image<R: Read>(reader: R) -> Result<RgbImage, RpegError> {
+    decode_image_with(reader, &DecoderConfig::default())
+}
+
+
+// decode rpeg data read from `reader` into an image with the given decoder options
+pub fn decode_image_with<R: Read>(reader: R, config: &DecoderConfig) -> Result<RgbImage, RpegError> {
+    Ok(decode_with_header(reader, config)?.1)
+}
+
+
+// decode rpeg data read from `reader` into an image, along with the header it was stored with
+// and the number of red, green and blue samples that were clamped
+fn decode_with_header<R: Read>(mut reader: R, config: &DecoderConfig) -> Result<(Header, RgbImage, [usize; 3]), RpegError> {
+
+    // read rpeg data
+    let mut compressed_data = Vec::new();
+    reader.read_to_end(&mut compressed_data)?;
+    let (header, payload) = read_rpeg(&compressed_data)?;
+    let (width, height) = (header.width, header.height);
+    let crt_array = match (header.transform, header.dct_matrix) {
+        (BlockTransform::Dct8x8, Some(matrix)) => {
+            // dimensions were padded to a whole number of 8x8 blocks
+            let (blocks, chroma) = payload_to_dct_blocks(&payload, &matrix, &header, width.div_ceil(8) * 8, height.div_ceil(8) * 8)?;
+            decompress_dct_blocks(blocks, chroma, &matrix, header.grayscale, header.subsampling, &header.chroma_quant, config)?
+        }
+        _ => {
+            // odd dimensions were padded to a whole number of blocks
+            let (quanta, chroma) = payload_to_quanta(&payload, &header, width.div_ceil(2), height.div_ceil(2))?;
+            decompress_quanta(quanta, chroma, &header.quant, header.grayscale, header.subsampling, &header.chroma_quant, config)?
+        }
+    };
+    // drop the padding added by the encoder, then convert with the image's color matrix
+    // and round to whole levels
+    let crt_array = crop_array(crt_array, width, height);
+    let rgb_float_array = crt_array_to_rgb_float_array_in(crt_array, header.colorspace);
+    let (rgb_int_array, clipped) = quantize_rgb_float_array(rgb_float_array, header.maxval, config.dither);
 
     // create image from pixels
     let img = RgbImage {
         width: width as u32,
         height: height as u32,
-        pixels: pixels,
-        denominator: 255
+        pixels: rgb_int_array.get_data().clone(),
+        denominator: header.maxval
     };
+    Ok((header, img, clipped))
+}
+
+
+// decode rpeg data read from `reader` into a ppm image written to `writer`
+pub fn decode<R: Read, W: Write>(reader: R, writer: W) -> Result<DecodeStats, RpegError> {
+    decode_as(reader, writer, ImageFormat::Ppm, &DecoderConfig::default())
+}
+
+
+// decode rpeg data read from `reader` with the given decoder options into an image of
+// the given format written to `writer`. grayscale images are written as pgm rather than ppm
+pub fn decode_as<R: Read, W: Write>(reader: R, mut writer: W, format: ImageFormat, config: &DecoderConfig) -> Result<DecodeStats, RpegError> {
+
+    let (header, img, clipped) = decode_with_header(reader, config)?;
+
     // write image
-    img.write(None).unwrap();
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Ppm if header.grayscale => write_pgm(&mut bytes, &rgb_to_gray(&img))?,
+        ImageFormat::Ppm => write_ppm(&mut bytes, &img)?,
+        ImageFormat::Png => write_png(&mut bytes, &img)?,
+    }
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+
+    Ok(DecodeStats {
+        width: img.width as usize,
+        height: img.height as usize,
+        bytes_written: bytes.len(),
+        clipped,
+    })
+}
+
+
+// decode rpeg data read from `reader` and write it to `writer` as a baseline jpeg
+// quality (1 to 100) scales the jpeg quantization tables
+pub fn export_jpeg<R: Read, W: Write>(reader: R, mut writer: W, quality: u8) -> Result<DecodeStats, RpegError> {
+
+    let (_, img, clipped) = decode_with_header(reader, &DecoderConfig::default())?;
+    let (width, height) = (img.width as usize, img.height as usize);
+    let pixels = Array2::from_row_major(width, height, img.pixels)
+        .map_err(RpegError::DimensionMismatch)?;
+    // baseline jpeg samples are 8 bits
+    let pixels = to_8_bits(pixels, img.denominator);
+
+    let mut bytes = Vec::new();
+    write_jpeg(&mut bytes, pixels, quality)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+
+    Ok(DecodeStats {
+        width,
+        height,
+        bytes_written: bytes.len(),
+        clipped,
+    })
+}
+
+
+// a size budget for rate-controlled encoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateTarget {
+    // the whole rpeg file, header included
+    Bytes(usize),
+    // bits of rpeg file per image pixel
+    BitsPerPixel(f64),
+}
+
+impl RateTarget {
+
+    // the largest rpeg file, in bytes, that meets the target for a `width` x `height` image
+    pub fn max_bytes(&self, width: usize, height: usize) -> usize {
+        match *self {
+            RateTarget::Bytes(bytes) => bytes,
+            // the nudge keeps a rate worked out from a byte count from rounding down past it
+            RateTarget::BitsPerPixel(bpp) => (bpp * (width * height) as f64 / 8.0 + 1e-6).floor() as usize,
+        }
+    }
+}
+
+
+// the payload codings a search may switch between, best compression last
+const ENTROPY_CANDIDATES: [(EntropyCoding, DcPrediction); 5] = [
+    (EntropyCoding::Fixed, DcPrediction::None),
+    (EntropyCoding::Huffman, DcPrediction::None),
+    (EntropyCoding::Huffman, DcPrediction::Med),
+    (EntropyCoding::RangeCoder, DcPrediction::None),
+    (EntropyCoding::RangeCoder, DcPrediction::Med),
+];
+
+
+// the configs a search tries: `config` itself, or, if `search_entropy` is set,
+// `config` with each payload coding it allows
+fn search_candidates(config: &EncoderConfig, search_entropy: bool) -> Vec<EncoderConfig> {
+    if !search_entropy {
+        return vec![config.clone()];
+    }
+    ENTROPY_CANDIDATES
+        .iter()
+        // run-length coding needs an entropy coder
+        .filter(|(entropy, _)| !(config.run_length && *entropy == EntropyCoding::Fixed))
+        .map(|&(entropy, prediction)| EncoderConfig { entropy, prediction, ..config.clone() })
+        .collect()
+}
+
+
+// encode an image with the highest quality that fits `target`, searching the
+// quality of each candidate config (see search_candidates) and keeping the one
+// with the highest quality, then the smallest size
+// input: image read from `reader`, rpeg data written to `writer`
+// output: the config chosen and the stats of the encoded image
+pub fn encode_to_size<R: Read, W: Write>(mut reader: R, mut writer: W, config: &EncoderConfig, target: RateTarget, search_entropy: bool) -> Result<(EncoderConfig, EncodeStats), RpegError> {
+
+    // the image is encoded many times, so read it once
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let encode_at = |candidate: &EncoderConfig, quality: u8| {
+        let mut bytes = Vec::new();
+        let stats = encode(input.as_slice(), &mut bytes, &EncoderConfig { quality, ..candidate.clone() })?;
+        Ok::<_, RpegError>((bytes, stats))
+    };
+
+    let mut best: Option<(EncoderConfig, Vec<u8>, EncodeStats)> = None;
+    let mut smallest: Option<usize> = None;
+    for candidate in search_candidates(config, search_entropy) {
+        let (bytes, stats) = encode_at(&candidate, 1)?;
+        let budget = target.max_bytes(stats.width, stats.height);
+        smallest = Some(smallest.map_or(bytes.len(), |size| size.min(bytes.len())));
+        if bytes.len() > budget {
+            continue;
+        }
 
-}
\ No newline at end of file
+        // binary search for the highest quality that still fits; quality 1 does
+        let (mut fits, mut fitting) = (1, (bytes, stats));
+        let mut too_big = 101;
+        while too_big - fits > 1 {
+            let quality = (fits + too_big) / 2;
+            let (bytes, stats) = encode_at(&candidate, quality)?;
+            if bytes.len() <= budget {
+                fits = quality;
+                fitting = (bytes, stats);
+            } else {
+                too_big = quality;
+            }
+        }
+
+        let better = best.as_ref().is_none_or(|(chosen, bytes, _)| {
+            (fits, std::cmp::Reverse(fitting.0.len())) > (chosen.quality, std::cmp::Reverse(bytes.len()))
+        });
+        if better {
+            best = Some((EncoderConfig { quality: fits, ..candidate }, fitting.0, fitting.1));
+        }
+    }
+
+    match best {
+        Some((chosen, bytes, stats)) => {
+            writer.write_all(&bytes)?;
+            writer.flush()?;
+            Ok((chosen, stats))
+        }
+        None => Err(RpegError::TargetNotMet(match smallest {
+            Some(size) => format!("the smallest encoding is {} bytes, at quality 1", size),
+            None => "no encoder settings to search".to_string(),
+        })),
+    }
+}
+
+
+// a quality bar for target-quality encoding, measured against the input image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityTarget {
+    // peak signal-to-noise ratio over all channels, in db
+    Psnr(f64),
+    // mean structural similarity of the luma, up to 1
+    Ssim(f64),
+}
+
+impl QualityTarget {
+
+    // the target's measure of `decoded` against `original`
+    pub fn measure(&self, original: &Array2<Rgb>, decoded: &Array2<Rgb>) -> Result<f64, RpegError> {
+        match self {
+            QualityTarget::Psnr(_) => metrics::psnr(original, decoded),
+            QualityTarget::Ssim(_) => metrics::ssim(original, decoded),
+        }
+    }
+
+    // whether a measured value meets the target
+    pub fn is_met_by(&self, value: f64) -> bool {
+        match *self {
+            QualityTarget::Psnr(target) | QualityTarget::Ssim(target) => value >= target,
+        }
+    }
+}
+
+
+// encode an image with the lowest quality whose reconstruction meets `target`,
+// then the smallest of the candidate configs (see search_candidates) at that
+// quality; payload codings are lossless, so they all decode to the same image
+// input: image read from `reader`, rpeg data written to `writer`
+// output: the config chosen, the stats of the encoded image, and its measured quality
+pub fn encode_to_quality<R: Read, W: Write>(mut reader: R, mut writer: W, config: &EncoderConfig, target: QualityTarget, search_entropy: bool) -> Result<(EncoderConfig, EncodeStats, f64), RpegError> {
+
+    // the image is encoded many times, so read and parse it once
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input)?;
+    let original = read_pixels(&input)?;
+    let encode_at = |candidate: &EncoderConfig, quality: u8| {
+        let mut bytes = Vec::new();
+        let stats = encode(input.as_slice(), &mut bytes, &EncoderConfig { quality, ..candidate.clone() })?;
+        Ok::<_, RpegError>((bytes, stats))
+    };
+    // decode with the usual pipeline and measure against the original
+    let measure_at = |quality: u8| {
+        let (bytes, _) = encode_at(config, quality)?;
+        let img = decode_image(bytes.as_slice())?;
+        let decoded = Array2::from_row_major(img.width as usize, img.height as usize, img.pixels)
+            .map_err(RpegError::DimensionMismatch)?;
+        target.measure(&original, &to_8_bits(decoded, img.denominator))
+    };
+
+    let best = measure_at(100)?;
+    if !target.is_met_by(best) {
+        let measured = match target {
+            QualityTarget::Psnr(_) => format!("a psnr of {:.2} db", best),
+            QualityTarget::Ssim(_) => format!("an ssim of {:.4}", best),
+        };
+        return Err(RpegError::TargetNotMet(format!("the best reconstruction has {}, at quality 100", measured)));
+    }
+    // binary search for the lowest quality that still meets the target; quality 100 does
+    let (mut meets, mut achieved) = (100, best);
+    let mut falls_short = 0;
+    while meets - falls_short > 1 {
+        let quality = (meets + falls_short) / 2;
+        let value = measure_at(quality)?;
+        if target.is_met_by(value) {
+            meets = quality;
+            achieved = value;
+        } else {
+            falls_short = quality;
+        }
+    }
+
+    let mut smallest: Option<(EncoderConfig, Vec<u8>, EncodeStats)> = None;
+    for candidate in search_candidates(config, search_entropy) {
+        let (bytes, stats) = encode_at(&candidate, meets)?;
+        if smallest.as_ref().is_none_or(|(_, chosen, _)| bytes.len() < chosen.len()) {
+            smallest = Some((EncoderConfig { quality: meets, ..candidate }, bytes, stats));
+        }
+    }
+    let (chosen, bytes, stats) = smallest
+        .ok_or_else(|| RpegError::TargetNotMet("no encoder settings to search".to_string()))?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok((chosen, stats, achieved))
+}
+
+
+// open a file for reading, or stdin if no filename is given
+fn open_input(filename: Option<&str>) -> Result<Box<dyn Read>, RpegError> {
+    match filename {
+        Some(filename) => Ok(Box::new(BufReader::new(File::open(filename)?))),
+        None => Ok(Box::new(io::stdin())),
+    }
+}
+
+
+// create a file for writing, or use stdout if no filename is given
+fn open_output(filename: Option<&str>) -> Result<Box<dyn Write>, RpegError> {
+    match filename {
+        Some(filename) => Ok(Box::new(BufWriter::new(File::create(filename)?))),
+        None => Ok(Box::new(io::stdout().lock())),
+    }
+}
+
+
+// write finished bytes to a new file, or to stdout if no filename is given
+fn write_output(filename: Option<&str>, bytes: &[u8]) -> Result<(), RpegError> {
+    let mut writer = open_output(filename)?;
+    writer.write_all(bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+
+// complete compress function
+// input: filename of a ppm, pgm, png or jpeg image, or stdin, encoder options, and output filename
+// output: saved rpeg image (from codewords) to the output file, or stdout
+pub fn compress(filename: Option<&str>, output: Option<&str>, config: &EncoderConfig) -> Result<EncodeStats, RpegError> {
+    // encode in memory, so a bad input leaves an existing output file alone
+    let mut compressed = Vec::new();
+    let stats = encode(open_input(filename)?, &mut compressed, config)?;
+    write_output(output, &compressed)?;
+    Ok(stats)
+}
+
+
+// complete compress function with a size target
+// input: filename of a ppm, pgm, png or jpeg image, or stdin, encoder options, the target,
+// whether to search payload codings, and output filename
+// output: saved rpeg image to the output file, or stdout, and the config chosen
+pub fn compress_to_size(filename: Option<&str>, output: Option<&str>, config: &EncoderConfig, target: RateTarget, search_entropy: bool) -> Result<(EncoderConfig, EncodeStats), RpegError> {
+    // search in memory, so a target that cannot be met leaves an existing output file alone
+    let mut compressed = Vec::new();
+    let chosen = encode_to_size(open_input(filename)?, &mut compressed, config, target, search_entropy)?;
+    write_output(output, &compressed)?;
+    Ok(chosen)
+}
+
+
+// complete compress function with a quality target
+// input: filename of a ppm, pgm, png or jpeg image, or stdin, encoder options, the target,
+// whether to search payload codings, and output filename
+// output: saved rpeg image to the output file, or stdout, the config chosen, and the quality measured
+pub fn compress_to_quality(filename: Option<&str>, output: Option<&str>, config: &EncoderConfig, target: QualityTarget, search_entropy: bool) -> Result<(EncoderConfig, EncodeStats, f64), RpegError> {
+    // search in memory, so a target that cannot be met leaves an existing output file alone
+    let mut compressed = Vec::new();
+    let chosen = encode_to_quality(open_input(filename)?, &mut compressed, config, target, search_entropy)?;
+    write_output(output, &compressed)?;
+    Ok(chosen)
+}
+
+
+// complete compare function
+// input: filenames of two ppm, pgm, png or jpeg images of the same size
+// output: every metric of the second image measured against the first
+pub fn compare(original: &str, reconstruction: &str) -> Result<metrics::Comparison, RpegError> {
+    let mut images = Vec::new();
+    for filename in [original, reconstruction] {
+        let mut bytes = Vec::new();
+        open_input(Some(filename))?.read_to_end(&mut bytes)?;
+        images.push(read_pixels(&bytes)?);
+    }
+    metrics::compare(&images[0], &images[1])
+}
+
+
+// complete decompress function
+// input: filename of rpeg, or stdin, output filename, and decoder options
+// output: saved image to the output file (png if it ends in .png, ppm or pgm otherwise), or ppm/pgm to stdout
+pub fn decompress(filename: Option<&str>, output: Option<&str>, config: &DecoderConfig) -> Result<DecodeStats, RpegError> {
+    let format = output.map(ImageFormat::from_filename).unwrap_or_default();
+    // decode in memory, so a bad input leaves an existing output file alone
+    let mut image = Vec::new();
+    let stats = decode_as(open_input(filename)?, &mut image, format, config)?;
+    write_output(output, &image)?;
+    Ok(stats)
+}
+
+
+// complete export function
+// input: filename of rpeg, or stdin, output filename, and the jpeg quality
+// output: baseline jpeg to the output file, or stdout
+pub fn export(filename: Option<&str>, output: Option<&str>, quality: u8) -> Result<DecodeStats, RpegError> {
+    // export in memory, so a bad input leaves an existing output file alone
+    let mut jpeg = Vec::new();
+    let stats = export_jpeg(open_input(filename)?, &mut jpeg, quality)?;
+    write_output(output, &jpeg)?;
+    Ok(stats)
+}
@@ -1,8 +1,9 @@
 use array2::Array2;
 use csc411_image::Rgb;
 use bitpack::bitpack;
-use csc411_arith::index_of_chroma;
 use crate::structures::*;
+use crate::error::{RpegError, expect_dimensions};
+use crate::layout::{CodewordLayout, Field};
 
 
 /*
@@ -10,30 +11,41 @@ data formats for COMPRESSION, in order
 -------------------------
 ppm image
 rgb int
-rgb float
+rgb float (samples divided by the image's maxval)
 crt (Y Pb Pr)
 packed crt blocks:
 - discrete cosine function on 4 Y values to get a, b, c, d coefficients
 - average Pb and Pr values
-    - convert Pb and Pr averages to 4-bit values using index_of_chroma
+    - convert Pb and Pr averages to indices with the chroma quantizer
+      (4-bit index_of_chroma by default, see ChromaQuantizer)
 - convert b, c, d coefficients to 5-bit values
 pack all of the data from each block into codewords
+
+in 8x8 dct mode the chroma path is the same, but Y is instead transformed
+in 8x8 blocks and each coefficient is divided by its step in a
+quantization matrix (see dct_8x8)
 */
 
 
-// rgb int to rgb float
-fn rgb_int_to_rgb_float(pixel: Rgb) -> RgbFloat {
-    let new_pixel = RgbFloat {
-        red: pixel.red as f32 / 255.0,
-        green: pixel.green as f32 / 255.0,
-        blue: pixel.blue as f32 / 255.0,
-    };
-    new_pixel
+// rgb int to rgb float, as fractions of maxval
+fn rgb_int_to_rgb_float(pixel: Rgb, maxval: u16) -> RgbFloat {
+    let maxval = maxval.max(1) as f32;
+    RgbFloat {
+        red: pixel.red as f32 / maxval,
+        green: pixel.green as f32 / maxval,
+        blue: pixel.blue as f32 / maxval,
+    }
 }
 
 
-// converts a whole array2 of rgb ints to an array2 of rgb floats
+// converts a whole array2 of 8-bit rgb ints to an array2 of rgb floats
 pub fn rgb_int_array_to_rgb_float_array(int_array2: Array2<Rgb>) -> Array2<RgbFloat> {
+    normalize_rgb_int_array(int_array2, 255)
+}
+
+
+// converts a whole array2 of rgb ints with samples from 0 to maxval to an array2 of rgb floats
+pub fn normalize_rgb_int_array(int_array2: Array2<Rgb>, maxval: u16) -> Array2<RgbFloat> {
     
     // define new array2 of empty rgb floats
     let mut float_array2 = Array2::new(int_array2.width(), int_array2.height(), RgbFloat { red: 0.0, green: 0.0, blue: 0.0 });
@@ -41,11 +53,11 @@ pub fn rgb_int_array_to_rgb_float_array(int_array2: Array2<Rgb>) -> Array2<RgbFl
     // iterate through each pixel in the array2, converting each to an rgb float using helper function
     for (c, r, pixel) in int_array2.iter_row_major() {
         let pixel_clone = Rgb {
-            red: (*pixel).red,
-            green: (*pixel).green,
-            blue: (*pixel).blue,
+            red: pixel.red,
+            green: pixel.green,
+            blue: pixel.blue,
         };
-        *float_array2.get_mut(c, r).unwrap() = rgb_int_to_rgb_float(pixel_clone);
+        *float_array2.get_mut(c, r).unwrap() = rgb_int_to_rgb_float(pixel_clone, maxval);
     }
 
     float_array2
@@ -55,24 +67,48 @@ pub fn rgb_int_array_to_rgb_float_array(int_array2: Array2<Rgb>) -> Array2<RgbFl
 
 // turn rgb float into crt
 // TODO: double check
-fn rgb_float_to_crt(pixel: RgbFloat) -> Crt {
-    Crt {
-        y: 0.299 * pixel.red + 0.587 * pixel.green + 0.114 * pixel.blue,
-        pb: -0.168736 * pixel.red - 0.331264 * pixel.green + 0.5 * pixel.blue,
-        pr: 0.5 * pixel.red - 0.418688 * pixel.green - 0.081312 * pixel.blue,
+fn rgb_float_to_crt(pixel: RgbFloat, colorspace: ColorSpace) -> Crt {
+    match (colorspace, colorspace.luma_weights()) {
+        // bt.601 keeps its published coefficients
+        (ColorSpace::Bt601, _) => Crt {
+            y: 0.299 * pixel.red + 0.587 * pixel.green + 0.114 * pixel.blue,
+            pb: -0.168736 * pixel.red - 0.331264 * pixel.green + 0.5 * pixel.blue,
+            pr: 0.5 * pixel.red - 0.418688 * pixel.green - 0.081312 * pixel.blue,
+        },
+        // pb and pr are blue and red minus luma, scaled to [-0.5, 0.5]
+        (_, Some((kr, kb))) => {
+            let y = kr * pixel.red + (1.0 - kr - kb) * pixel.green + kb * pixel.blue;
+            Crt {
+                y,
+                pb: (pixel.blue - y) / (2.0 * (1.0 - kb)),
+                pr: (pixel.red - y) / (2.0 * (1.0 - kr)),
+            }
+        }
+        // ycocg, with co in pb and cg in pr
+        (_, None) => Crt {
+            y: 0.25 * pixel.red + 0.5 * pixel.green + 0.25 * pixel.blue,
+            pb: 0.5 * pixel.red - 0.5 * pixel.blue,
+            pr: -0.25 * pixel.red + 0.5 * pixel.green - 0.25 * pixel.blue,
+        },
     }
 }
 
 
-// turn rgb float array into crt array
+// turn rgb float array into crt array with the bt.601 matrix
 pub fn rgb_float_array_to_crt_array(float_array: Array2<RgbFloat>) -> Array2<Crt> {
+    rgb_float_array_to_crt_array_in(float_array, ColorSpace::Bt601)
+}
+
+
+// turn rgb float array into crt array with the given color matrix
+pub fn rgb_float_array_to_crt_array_in(float_array: Array2<RgbFloat>, colorspace: ColorSpace) -> Array2<Crt> {
     
     // define new array2 of crt structs
     let mut crt_array2 = Array2::new(float_array.width(), float_array.height(), Crt { y: 0.0, pb: 0.0, pr: 0.0 });
 
     // iterate through float_array and convert each pixel to crt using helper function
     for (c, r, pixel) in float_array.iter_row_major() {
-        *crt_array2.get_mut(c, r).unwrap() = rgb_float_to_crt(*pixel);
+        *crt_array2.get_mut(c, r).unwrap() = rgb_float_to_crt(*pixel, colorspace);
     }
 
     crt_array2
@@ -93,16 +129,18 @@ pub fn new_quantized_array(width: usize, height: usize) -> Array2<Quantized> {
     };
 
     // define new array2 of empty quantized structs, and return it
-    let quantized_array = Array2::new(width, height, empty_quantized);
-    quantized_array
+    Array2::new(width, height, empty_quantized)
 }
 
 
 // perform discrete cosine transform (dct) on blocks of 4 Y values
 // input: Array2<Crt>, Array2<Quantized>
 // output: Array2<Quantized> (updated)
-pub fn dct(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Array2<Quantized> {
-    
+pub fn try_dct(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Result<Array2<Quantized>, RpegError> {
+
+    // each quantized struct covers a 2x2 block of crts
+    expect_dimensions("crt array", (quantized_array.width() * 2, quantized_array.height() * 2), (crt_array.width(), crt_array.height()))?;
+
     let mut new_quantized_array = quantized_array.clone();
 
     // iterate through crt_array in 2x2 blocks
@@ -135,53 +173,60 @@ pub fn dct(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Array2
             };
         }
     }
-    new_quantized_array
+    Ok(new_quantized_array)
 }
 
 
-// average Pb and Pr values 
-// input: Array2<Crt>, Array2<Quantized>
-// output: Array2<Quantized> (updated)
-pub fn average_pb_pr(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Array2<Quantized> {
-    
-    // define new array2 of quantized structs
-    let mut new_quantized_array = quantized_array.clone();
-
-    // iterate through crt_array in 2x2 blocks
-    for (c, r, _) in crt_array.iter_row_major() {
+// try_dct, kept for the baseline tests
+// panics if the arrays do not line up
+#[cfg(test)]
+pub(crate) fn dct(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Array2<Quantized> {
+    try_dct(crt_array, quantized_array).unwrap()
+}
 
-        // if we're at both an even column and row, we are at the start of a block
-        if c % 2 == 0 && r % 2 == 0 {
 
-            // get pb vaues in current block
-            let pb1 = crt_array.get(c, r).unwrap().pb;
-            let pb2 = crt_array.get(c + 1, r).unwrap().pb;
-            let pb3 = crt_array.get(c, r + 1).unwrap().pb;
-            let pb4 = crt_array.get(c + 1, r + 1).unwrap().pb;
+// average Pb and Pr values over the pixels that share them
+// input: Array2<Crt>, Array2<Quantized> the size of the chroma planes, chroma subsampling
+// output: Array2<Quantized> (updated)
+pub fn average_pb_pr_in(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>, subsampling: Subsampling) -> Result<Array2<Quantized>, RpegError> {
 
-            // get pr values in current block
-            let pr1 = crt_array.get(c, r).unwrap().pr;
-            let pr2 = crt_array.get(c + 1, r).unwrap().pr;
-            let pr3 = crt_array.get(c, r + 1).unwrap().pr;
-            let pr4 = crt_array.get(c + 1, r + 1).unwrap().pr;
+    // each quantized struct covers fx x fy crts: 2x2 for 4:2:0, 2x1 for 4:2:2, 1x1 for 4:4:4
+    let (fx, fy) = subsampling.factors();
+    expect_dimensions("crt array", (quantized_array.width() * fx, quantized_array.height() * fy), (crt_array.width(), crt_array.height()))?;
 
-            // calculate average pb and pr values for current block
-            let new_pb_avg = (pb1 + pb2 + pb3 + pb4) / 4.0;
-            let new_pr_avg = (pr1 + pr2 + pr3 + pr4) / 4.0;
+    // define new array2 of quantized structs
+    let mut new_quantized_array = quantized_array.clone();
 
-            // update quantized_array with new pb and pr values while keeping a, b, c, and d values the same
-            // quantized array is a quarter the size of the crt array, so divide c and r by 2
-            *new_quantized_array.get_mut(c / 2, r / 2).unwrap() = Quantized {
-                a: quantized_array.get(c / 2, r / 2).unwrap().a,
-                b: quantized_array.get(c / 2, r / 2).unwrap().b,
-                c: quantized_array.get(c / 2, r / 2).unwrap().c,
-                d: quantized_array.get(c / 2, r / 2).unwrap().d,
-                pb_avg: new_pb_avg,
-                pr_avg: new_pr_avg,
-            };
+    // iterate through quantized_array normally
+    for (c, r, quantized) in quantized_array.iter_row_major() {
+
+        // sum the pb and pr values of the covered crts, row by row
+        let mut pb_sum = 0.0;
+        let mut pr_sum = 0.0;
+        for dr in 0..fy {
+            for dc in 0..fx {
+                let crt = crt_array.get(c * fx + dc, r * fy + dr).unwrap();
+                pb_sum += crt.pb;
+                pr_sum += crt.pr;
+            }
         }
+
+        // update quantized_array with new pb and pr values while keeping a, b, c, and d values the same
+        *new_quantized_array.get_mut(c, r).unwrap() = Quantized {
+            pb_avg: pb_sum / (fx * fy) as f32,
+            pr_avg: pr_sum / (fx * fy) as f32,
+            ..quantized.clone()
+        };
     }
-    new_quantized_array
+    Ok(new_quantized_array)
+}
+
+
+// average_pb_pr_in with 4:2:0 subsampling, kept for the baseline tests
+// panics if the arrays do not line up
+#[cfg(test)]
+pub(crate) fn average_pb_pr(crt_array: Array2<Crt>, quantized_array: Array2<Quantized>) -> Array2<Quantized> {
+    average_pb_pr_in(crt_array, quantized_array, Subsampling::Yuv420).unwrap()
 }
 
 
@@ -199,60 +244,306 @@ pub fn new_encoded_quanta_array(width: usize, height: usize) -> Array2<EncodedQu
     };
 
     // define new array2 of empty encoded quanta structs, and return it
-    let encoded_quanta_array = Array2::new(width, height, empty_encoded_quanta);
-    encoded_quanta_array
+    Array2::new(width, height, empty_encoded_quanta)
 }
 
 
-// convert average Pb and Pr values to 4-bit values using index_of_chroma
-// input: Array2<Quantized>
-// output: Array2<Quantized> (updated)
-pub fn index_of_chroma_array(quantized_array: Array2<Quantized>, encoded_quanta_array: Array2<EncodedQuanta>) -> Array2<EncodedQuanta> {
-    
+// convert average Pb and Pr values to indices with the chroma quantizer
+// input: Array2<Quantized>, Array2<EncodedQuanta>, chroma quantizer from the header
+// output: Array2<EncodedQuanta> (updated)
+pub fn index_of_chroma_array(quantized_array: Array2<Quantized>, encoded_quanta_array: Array2<EncodedQuanta>, quantizer: &ChromaQuantizer) -> Result<Array2<EncodedQuanta>, RpegError> {
+
+    expect_dimensions("encoded quanta array", (quantized_array.width(), quantized_array.height()), (encoded_quanta_array.width(), encoded_quanta_array.height()))?;
+
     // define new array2 of quantized structs
     let mut new_encoded_quanta_array = encoded_quanta_array.clone();
 
     // iterate through quantized_array normally
     for (c, r, encoded_quanta) in encoded_quanta_array.iter_row_major() {
 
-        // convert pb and pr averages to 4-bit values using index_of_chroma
+        // convert pb and pr averages to indices of their closest levels
         // update quantized_array with new pb and pr values while keeping a, b, c, and d values the same
         *new_encoded_quanta_array.get_mut(c, r).unwrap() = EncodedQuanta {
             a: encoded_quanta.a,
             b: encoded_quanta.b,
             c: encoded_quanta.c,
             d: encoded_quanta.d,
-            pb_avg: index_of_chroma(quantized_array.get(c, r).unwrap().pb_avg) as u64,
-            pr_avg: index_of_chroma(quantized_array.get(c, r).unwrap().pr_avg) as u64,
+            pb_avg: quantizer.index_of(quantized_array.get(c, r).unwrap().pb_avg, false),
+            pr_avg: quantizer.index_of(quantized_array.get(c, r).unwrap().pr_avg, true),
         };
     }
-    new_encoded_quanta_array
+    Ok(new_encoded_quanta_array)
+}
+
+
+// bins of the chroma histogram fit_chroma_levels works on, over [-0.5, 0.5]
+const CHROMA_BINS: usize = 4096;
+// rounds of lloyd's algorithm run by fit_chroma_levels
+const CHROMA_FIT_ROUNDS: usize = 20;
+
+
+// fit up to 2^bits chroma levels to the values of one image with lloyd's
+// algorithm, so that busy ranges (skies, skin tones) get finer levels
+// input: pb or pr averages, bits per index
+// output: levels in increasing order
+pub fn fit_chroma_levels(values: &[f32], bits: u64) -> Vec<f32> {
+
+    let count = 1usize << bits;
+    if values.is_empty() {
+        return vec![0.0];
+    }
+
+    // histogram of the values, keeping each bin's sum so that centroids are exact
+    let mut bin_counts = vec![0u64; CHROMA_BINS];
+    let mut bin_sums = vec![0f64; CHROMA_BINS];
+    for value in values {
+        let bin = (((value + 0.5) * CHROMA_BINS as f32) as usize).min(CHROMA_BINS - 1);
+        bin_counts[bin] += 1;
+        bin_sums[bin] += *value as f64;
+    }
+    let bins: Vec<(u64, f64)> = bin_counts
+        .into_iter()
+        .zip(bin_sums)
+        .filter(|(bin_count, _)| *bin_count > 0)
+        .map(|(bin_count, sum)| (bin_count, sum / bin_count as f64))
+        .collect();
+    if bins.len() <= count {
+        // few enough distinct values to give each its own level
+        return bins.iter().map(|(_, mean)| *mean as f32).collect();
+    }
+
+    // start from evenly spaced quantiles
+    let total = values.len() as u64;
+    let mut levels = Vec::with_capacity(count);
+    let mut seen = 0;
+    for (bin_count, mean) in bins.iter() {
+        seen += bin_count;
+        while levels.len() < count && seen * count as u64 > (2 * levels.len() as u64 + 1) * total / 2 {
+            levels.push(*mean);
+        }
+    }
+    levels.resize(count, bins[bins.len() - 1].1);
+
+    // move each level to the centroid of the bins closest to it
+    for _ in 0..CHROMA_FIT_ROUNDS {
+        let mut sums = vec![(0u64, 0f64); count];
+        let mut level = 0;
+        for (bin_count, mean) in bins.iter() {
+            // levels stay sorted, so the closest one only moves up
+            while level + 1 < count && (levels[level + 1] - mean).abs() <= (levels[level] - mean).abs() {
+                level += 1;
+            }
+            sums[level].0 += bin_count;
+            sums[level].1 += *bin_count as f64 * mean;
+        }
+        for (level, (weight, sum)) in levels.iter_mut().zip(sums) {
+            if weight > 0 {
+                *level = sum / weight as f64;
+            }
+        }
+        levels.sort_by(f64::total_cmp);
+    }
+    levels.iter().map(|level| *level as f32).collect()
+}
+
+
+// choose quantizer parameters for a quality between 1 and 100
+// quality 100 uses every level the layout's fields can hold (which, for the
+// default layout, reproduces the original fixed parameters); lower qualities
+// use fewer levels over a wider clamp range, and a coarser scale for a
+pub fn quant_params_for_quality(quality: u8, layout: &CodewordLayout) -> Result<QuantParams, RpegError> {
+    if !(1..=100).contains(&quality) {
+        return Err(RpegError::InvalidConfig(format!("quality must be between 1 and 100, got {}", quality)));
+    }
+
+    // position of the quality within its range, 0.0 at 1 and 1.0 at 100
+    let t = (quality - 1) as f32 / 99.0;
+    let max_a_scale = layout.max_a();
+    let max_bcd_levels = layout.max_bcd_level();
+    let min_a_scale = max_a_scale.min(63);
+    let min_bcd_levels = max_bcd_levels.min(3);
+
+    Ok(QuantParams {
+        a_scale: min_a_scale + ((max_a_scale - min_a_scale) as f64 * t as f64).round() as u64,
+        bcd_range: 0.5 - (0.5 - QuantParams::default().bcd_range) * t,
+        bcd_levels: min_bcd_levels + ((max_bcd_levels - min_bcd_levels) as f64 * t as f64).round() as i64,
+    })
+}
+
+
+// luminance quantization table from annex k of the jpeg standard
+const JPEG_LUMA_STEPS: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+
+// scale a quantization table for a quality between 1 and 100 the way
+// libjpeg does it, so quality 50 is the table itself and quality 100
+// quantizes every frequency by 1
+pub fn scale_quant_table(table: &[u16; 64], quality: u8) -> Result<QuantMatrix, RpegError> {
+    if !(1..=100).contains(&quality) {
+        return Err(RpegError::InvalidConfig(format!("quality must be between 1 and 100, got {}", quality)));
+    }
+
+    let quality = quality as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+    let steps = table.map(|step| ((step as u32 * scale + 50) / 100).clamp(1, 255) as u16);
+    Ok(QuantMatrix { steps })
+}
+
+
+// choose the 8x8 dct quantization matrix for a quality between 1 and 100
+// by scaling the jpeg luminance table
+pub fn quant_matrix_for_quality(quality: u8) -> Result<QuantMatrix, RpegError> {
+    scale_quant_table(&JPEG_LUMA_STEPS, quality)
+}
+
+
+// perform an 8x8 type-ii dct on Y and quantize it
+// input: Array2<Crt> with dimensions a multiple of 8, quantization matrix
+// output: Array2<DctBlock>, one per 8x8 block
+pub fn dct_8x8(crt_array: Array2<Crt>, matrix: &QuantMatrix) -> Result<Array2<DctBlock>, RpegError> {
+
+    if !crt_array.width().is_multiple_of(8) || !crt_array.height().is_multiple_of(8) {
+        return Err(RpegError::DimensionMismatch(format!(
+            "expected crt array dimensions to be multiples of 8, found {}x{}",
+            crt_array.width(),
+            crt_array.height()
+        )));
+    }
+
+    let basis = dct_basis();
+    let max_levels = matrix.max_levels();
+    let mut blocks = Array2::new(crt_array.width() / 8, crt_array.height() / 8, DctBlock { levels: [0; 64] });
+
+    for br in 0..blocks.height() {
+        for bc in 0..blocks.width() {
+
+            // level-shift Y from [0, 1] to [-128, 127] like jpeg does
+            let mut samples = [[0.0f64; 8]; 8];
+            for (y, row) in samples.iter_mut().enumerate() {
+                for (x, sample) in row.iter_mut().enumerate() {
+                    *sample = crt_array.get(bc * 8 + x, br * 8 + y).unwrap().y as f64 * 255.0 - 128.0;
+                }
+            }
+
+            // separable transform: rows first, then columns
+            let rows = samples.map(|row| basis.map(|frequency| (0..8).map(|x| frequency[x] * row[x]).sum::<f64>()));
+            let mut levels = [0; 64];
+            for (i, level) in levels.iter_mut().enumerate() {
+                let (u, v) = (i % 8, i / 8);
+                let coefficient: f64 = (0..8).map(|y| basis[v][y] * rows[y][u]).sum();
+                // rounding can push a level one past the analytic bound
+                *level = ((coefficient / matrix.steps[i] as f64).round() as i64).clamp(-max_levels[i], max_levels[i]);
+            }
+            *blocks.get_mut(bc, br).unwrap() = DctBlock { levels };
+        }
+    }
+    Ok(blocks)
+}
+
+
+// collapse runs of blocks with zero b, c and d whose chroma repeats the previous block's
+// input: Array2<EncodedQuanta>
+// output: RunLengthQuanta holding every a, the blocks stored in full, and the run after each
+pub fn run_length_encode(encoded_quanta_array: &Array2<EncodedQuanta>) -> RunLengthQuanta {
+
+    let mut rle = RunLengthQuanta { a: Vec::new(), literals: Vec::new(), runs: Vec::new() };
+    let mut previous: Option<&EncodedQuanta> = None;
+
+    for quanta in encoded_quanta_array.get_data() {
+        rle.a.push(quanta.a);
+
+        // a flat block with the same chroma extends the current run
+        let repeats = previous.is_some_and(|previous| {
+            quanta.b == 0 && quanta.c == 0 && quanta.d == 0
+                && quanta.pb_avg == previous.pb_avg && quanta.pr_avg == previous.pr_avg
+        });
+        if repeats {
+            *rle.runs.last_mut().unwrap() += 1;
+        } else {
+            rle.literals.push(EncodedQuanta { a: 0, ..quanta.clone() });
+            rle.runs.push(0);
+        }
+        previous = Some(quanta);
+    }
+    rle
+}
+
+
+// median edge detector (med) prediction of a value from its neighbours:
+// picks the smaller neighbour above an edge, the larger below one, and
+// the planar estimate left + top - top_left otherwise
+pub fn med_predict(left: i64, top: i64, top_left: i64) -> i64 {
+    if top_left >= left.max(top) {
+        left.min(top)
+    } else if top_left <= left.min(top) {
+        left.max(top)
+    } else {
+        left.wrapping_add(top).wrapping_sub(top_left)
+    }
+}
+
+
+// predict a block's dc value from the blocks already coded around it
+// input: row-major values of a grid `width` blocks wide, index of the block
+// output: prediction (0 for the first block, the left or top neighbour on the edges)
+pub fn predict_dc(values: &[i64], width: usize, index: usize) -> i64 {
+    let (c, r) = (index % width, index / width);
+    match (c, r) {
+        (0, 0) => 0,
+        (_, 0) => values[index - 1],
+        (0, _) => values[index - width],
+        _ => med_predict(values[index - 1], values[index - width], values[index - width - 1]),
+    }
+}
+
+
+// replace the a (or dc) values of a block grid with their prediction residuals
+// input: row-major values, grid width in blocks
+// output: value minus prediction for each block
+pub fn dc_prediction_residuals(values: &[i64], width: usize) -> Vec<i64> {
+    (0..values.len())
+        .map(|i| values[i].wrapping_sub(predict_dc(values, width, i)))
+        .collect()
 }
 
 
 // encoding algorithm for quantization
-fn encode(value: f32) -> i64 {
-    if value < -0.3 {
-        -15
-    } else if value > 0.3 {
-        15
+fn encode(value: f32, params: &QuantParams) -> i64 {
+    if value < -params.bcd_range {
+        -params.bcd_levels
+    } else if value > params.bcd_range {
+        params.bcd_levels
     } else {
         // Scale the float value to the integer range
-        ((value / 0.3) * 15.0).round() as i64 //& 0b11111
+        ((value / params.bcd_range) * params.bcd_levels as f32).round() as i64
     }
 }
 
 
-pub fn encode_coefficients_array(quantized_array: Array2<Quantized>, encoded_quanta_array: Array2<EncodedQuanta>) -> Array2<EncodedQuanta> {
+// quantize coefficients into encoded quanta array
+// input: Array2<Quantized>, Array2<EncodedQuanta>, quantizer parameters
+// output: Array2<EncodedQuanta> (updated)
+pub fn encode_coefficients_array(quantized_array: Array2<Quantized>, encoded_quanta_array: Array2<EncodedQuanta>, params: &QuantParams) -> Result<Array2<EncodedQuanta>, RpegError> {
+
+    expect_dimensions("encoded quanta array", (quantized_array.width(), quantized_array.height()), (encoded_quanta_array.width(), encoded_quanta_array.height()))?;
 
     let mut new_encoded_quanta_array = encoded_quanta_array.clone();
 
     for (c, r, quanta) in quantized_array.iter_row_major() {
 
-        let new_a = (quanta.a * 511.0).round() as u64;
-        let new_b = encode(quanta.b);
-        let new_c = encode(quanta.c);
-        let new_d = encode(quanta.d);
+        let new_a = (quanta.a * params.a_scale as f32).round() as u64;
+        let new_b = encode(quanta.b, params);
+        let new_c = encode(quanta.c, params);
+        let new_d = encode(quanta.d, params);
         let new_pb: u64 = encoded_quanta_array.get(c, r).unwrap().pb_avg;
         let new_pr: u64 = encoded_quanta_array.get(c, r).unwrap().pr_avg;
 
@@ -265,15 +556,29 @@ pub fn encode_coefficients_array(quantized_array: Array2<Quantized>, encoded_qua
             pr_avg: new_pr,
         };
     }
-    new_encoded_quanta_array
+    Ok(new_encoded_quanta_array)
+}
+
+
+// pack an unsigned field into a codeword, reporting values that do not fit
+fn pack_unsigned(word: u64, name: &'static str, field: Field, value: u64) -> Result<u64, RpegError> {
+    bitpack::newu(word, field.width, field.lsb, value)
+        .ok_or(RpegError::FieldOverflow { field: name, value: value as i64, width: field.width })
+}
+
+
+// pack a signed field into a codeword, reporting values that do not fit
+fn pack_signed(word: u64, name: &'static str, field: Field, value: i64) -> Result<u64, RpegError> {
+    bitpack::news(word, field.width, field.lsb, value)
+        .ok_or(RpegError::FieldOverflow { field: name, value, width: field.width })
 }
 
 
 // pack quantized data into codewords
-// input: Array2<Quantized>
+// input: Array2<EncodedQuanta>, a validated codeword layout
 // output: Array2<u64>
-pub fn pack_encoded_quanta(encoded_quanta_array: Array2<EncodedQuanta>) -> Array2<u32> {
-    
+pub fn pack_encoded_quanta(encoded_quanta_array: Array2<EncodedQuanta>, layout: &CodewordLayout) -> Result<Array2<u64>, RpegError> {
+
     // define new array2 of u64s
     let mut codewords_array = Array2::new(encoded_quanta_array.width(), encoded_quanta_array.height(), 0);
 
@@ -282,15 +587,15 @@ pub fn pack_encoded_quanta(encoded_quanta_array: Array2<EncodedQuanta>) -> Array
 
         // pack all quantized values into a single u64
         let mut codeword: u64 = 0;
-        codeword = bitpack::newu(codeword, 9, 23, encoded_quanta.a).unwrap();
-        codeword = bitpack::news(codeword, 5, 18, encoded_quanta.b).unwrap();
-        codeword = bitpack::news(codeword, 5, 13, encoded_quanta.c).unwrap();
-        codeword = bitpack::news(codeword, 5, 8, encoded_quanta.d).unwrap();
-        codeword = bitpack::newu(codeword, 4, 4, encoded_quanta.pb_avg).unwrap();
-        codeword = bitpack::newu(codeword, 4, 0, encoded_quanta.pr_avg).unwrap();
-        
+        codeword = pack_unsigned(codeword, "a", layout.a, encoded_quanta.a)?;
+        codeword = pack_signed(codeword, "b", layout.b, encoded_quanta.b)?;
+        codeword = pack_signed(codeword, "c", layout.c, encoded_quanta.c)?;
+        codeword = pack_signed(codeword, "d", layout.d, encoded_quanta.d)?;
+        codeword = pack_unsigned(codeword, "pb_avg", layout.pb, encoded_quanta.pb_avg)?;
+        codeword = pack_unsigned(codeword, "pr_avg", layout.pr, encoded_quanta.pr_avg)?;
+
         // update codewords_array with new codeword
-        *codewords_array.get_mut(col, row).unwrap() = codeword as u32;
+        *codewords_array.get_mut(col, row).unwrap() = codeword;
     }
-    codewords_array
+    Ok(codewords_array)
 }
\ No newline at end of file
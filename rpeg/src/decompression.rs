@@ -1,8 +1,10 @@
 use array2::Array2;
 use bitpack::bitpack::{getu, gets};
 use csc411_image::Rgb;
-use csc411_arith::chroma_of_index;
 use crate::structures::*;
+use crate::error::{RpegError, expect_dimensions};
+use crate::layout::{CodewordLayout, Field};
+use crate::compression::predict_dc;
 
 
 /*
@@ -15,16 +17,37 @@ expand packed data (1 block to 4 crts)
 - inverse dct
 - chroma_of_index
 crt (Y Pb Pr)
+- chroma replicated over, or interpolated across, the pixels sharing it
+- optional deblocking across block edges
 rgb float
+- rounding, clamping and optional dithering
 rgb int
 ppm image
 */
 
 
+// error for a field that does not fit inside a 64-bit codeword
+fn outside_word(name: &str, field: Field) -> RpegError {
+    RpegError::MalformedHeader(format!("field {} ({} bits at lsb {}) lies outside the codeword", name, field.width, field.lsb))
+}
+
+
+// read an unsigned field from a codeword, reporting fields that lie outside the word
+fn unpack_unsigned(word: u64, name: &'static str, field: Field) -> Result<u64, RpegError> {
+    getu(word, field.width, field.lsb).ok_or_else(|| outside_word(name, field))
+}
+
+
+// read a signed field from a codeword, reporting fields that lie outside the word
+fn unpack_signed(word: u64, name: &'static str, field: Field) -> Result<i64, RpegError> {
+    gets(word, field.width, field.lsb).ok_or_else(|| outside_word(name, field))
+}
+
+
 // extract quantized data from codewords
-// input: Array2<u64>
-// output: Array2<Quantized>
-pub fn extract_encoded_data(codewords_array: Array2<u32>) -> Array2<EncodedQuanta> {
+// input: Array2<u64>, the codeword layout from the header
+// output: Array2<EncodedQuanta>
+pub fn extract_encoded_data(codewords_array: Array2<u64>, layout: &CodewordLayout) -> Result<Array2<EncodedQuanta>, RpegError> {
 
     // define new array2 of quantized structs
     let mut encoded_quanta_array = Array2::new(codewords_array.width(), codewords_array.height(), EncodedQuanta {
@@ -41,23 +64,33 @@ pub fn extract_encoded_data(codewords_array: Array2<u32>) -> Array2<EncodedQuant
 
         // update quantized_array with new coefficients and averages
         *encoded_quanta_array.get_mut(col, row).unwrap() = EncodedQuanta {
-            a: getu(*codeword as u64, 9, 23).unwrap(),
-            b: gets(*codeword as u64, 5, 18).unwrap(),
-            c: gets(*codeword as u64, 5, 13).unwrap(),
-            d: gets(*codeword as u64, 5, 8).unwrap(),
-            pb_avg: getu(*codeword as u64, 4, 4).unwrap(),
-            pr_avg: getu(*codeword as u64, 4, 0).unwrap(),
+            a: unpack_unsigned(*codeword, "a", layout.a)?,
+            b: unpack_signed(*codeword, "b", layout.b)?,
+            c: unpack_signed(*codeword, "c", layout.c)?,
+            d: unpack_signed(*codeword, "d", layout.d)?,
+            pb_avg: unpack_unsigned(*codeword, "pb_avg", layout.pb)?,
+            pr_avg: unpack_unsigned(*codeword, "pr_avg", layout.pr)?,
         };
     }
-    encoded_quanta_array    
+    Ok(encoded_quanta_array)
+}
+
+
+// the chroma level of an index read from a file, which may not be one the quantizer has
+fn checked_chroma_of_index(name: &'static str, index: u64, quantizer: &ChromaQuantizer, pr: bool) -> Result<f32, RpegError> {
+    quantizer
+        .value_of(index, pr)
+        .ok_or(RpegError::FieldOverflow { field: name, value: index as i64, width: quantizer.bits() })
 }
 
 
-// reverse index_of_chroma with chroma_of_index
-// input: Array2<Quantized>
+// reverse index_of_chroma_array with the same chroma quantizer
+// input: Array2<EncodedQuanta>, Array2<Quantized>, chroma quantizer from the header
 // output: Array2<Quantized> (updated)
-pub fn chroma_of_index_array(encoded_quanta_array: Array2<EncodedQuanta>, decoded_quanta_array: Array2<Quantized>) -> Array2<Quantized> {
-    
+pub fn chroma_of_index_array(encoded_quanta_array: Array2<EncodedQuanta>, decoded_quanta_array: Array2<Quantized>, quantizer: &ChromaQuantizer) -> Result<Array2<Quantized>, RpegError> {
+
+    expect_dimensions("decoded quanta array", (encoded_quanta_array.width(), encoded_quanta_array.height()), (decoded_quanta_array.width(), decoded_quanta_array.height()))?;
+
     // define new array2 of quantized structs
     let mut new_decoded_quanta_array = decoded_quanta_array.clone();
 
@@ -70,25 +103,27 @@ pub fn chroma_of_index_array(encoded_quanta_array: Array2<EncodedQuanta>, decode
             b: decoded_quanta_array.get(c, r).unwrap().b,
             c: decoded_quanta_array.get(c, r).unwrap().c,
             d: decoded_quanta_array.get(c, r).unwrap().d,
-            pb_avg: chroma_of_index(encoded_quanta.pb_avg as usize),
-            pr_avg: chroma_of_index(encoded_quanta.pr_avg as usize),
+            pb_avg: checked_chroma_of_index("pb_avg", encoded_quanta.pb_avg, quantizer, false)?,
+            pr_avg: checked_chroma_of_index("pr_avg", encoded_quanta.pr_avg, quantizer, true)?,
         };
     }
-    new_decoded_quanta_array
+    Ok(new_decoded_quanta_array)
 }
 
 
 // decode algorithm for b, c, d coefficients
-fn decode(encoded: i64) -> f32 {
-    encoded as f32 / 15.0 * 0.3
+fn decode(encoded: i64, params: &QuantParams) -> f32 {
+    encoded as f32 / params.bcd_levels as f32 * params.bcd_range
 }
 
 
 // decode coefficients from encoded quanta array
-// input: Array2<EncodedQuanta>, Array2<Quantized>
+// input: Array2<EncodedQuanta>, Array2<Quantized>, quantizer parameters from the header
 // output: Array2<Quantized> (updated)
-pub fn decode_coefficients_array(encoded_quanta_array: Array2<EncodedQuanta>, decoded_quanta_array: Array2<Quantized>) -> Array2<Quantized> {
-    
+pub fn decode_coefficients_array(encoded_quanta_array: Array2<EncodedQuanta>, decoded_quanta_array: Array2<Quantized>, params: &QuantParams) -> Result<Array2<Quantized>, RpegError> {
+
+    expect_dimensions("decoded quanta array", (encoded_quanta_array.width(), encoded_quanta_array.height()), (decoded_quanta_array.width(), decoded_quanta_array.height()))?;
+
     // define new array2 of quantized structs
     let mut new_decoded_quanta_array = decoded_quanta_array.clone();
 
@@ -97,15 +132,63 @@ pub fn decode_coefficients_array(encoded_quanta_array: Array2<EncodedQuanta>, de
 
         // update quantized_array with new coefficients while keeping pb and pr values the same
         *new_decoded_quanta_array.get_mut(c, r).unwrap() = Quantized {
-            a: encoded_quanta.a as f32 / 511.0,
-            b: decode(encoded_quanta.b),
-            c: decode(encoded_quanta.c),
-            d: decode(encoded_quanta.d),
+            a: encoded_quanta.a as f32 / params.a_scale as f32,
+            b: decode(encoded_quanta.b, params),
+            c: decode(encoded_quanta.c, params),
+            d: decode(encoded_quanta.d, params),
             pb_avg: decoded_quanta_array.get(c, r).unwrap().pb_avg,
             pr_avg: decoded_quanta_array.get(c, r).unwrap().pr_avg,
         };
     }
-    new_decoded_quanta_array
+    Ok(new_decoded_quanta_array)
+}
+
+
+// undo dc_prediction_residuals, rebuilding each value from the ones before it
+// input: row-major residuals, grid width in blocks
+// output: the original a (or dc) values
+pub fn dc_from_prediction_residuals(residuals: &[i64], width: usize) -> Vec<i64> {
+    let mut values = Vec::with_capacity(residuals.len());
+    for (i, residual) in residuals.iter().enumerate() {
+        let prediction = predict_dc(&values, width, i);
+        values.push(residual.wrapping_add(prediction));
+    }
+    values
+}
+
+
+// expand the runs of run_length_encode back into one encoded quanta per block
+// input: RunLengthQuanta, dimensions of the block grid
+// output: Array2<EncodedQuanta>
+pub fn expand_runs(rle: RunLengthQuanta, width: usize, height: usize) -> Result<Array2<EncodedQuanta>, RpegError> {
+
+    let mismatch = |what: &str, count: u64| RpegError::DimensionMismatch(format!(
+        "expected {} blocks from the {}, found {}",
+        width * height, what, count
+    ));
+    if rle.a.len() != width * height {
+        return Err(mismatch("a values", rle.a.len() as u64));
+    }
+    if rle.literals.len() != rle.runs.len() {
+        return Err(RpegError::DimensionMismatch("every literal block needs a run length".to_string()));
+    }
+    let total = rle.runs.iter().try_fold(rle.literals.len() as u64, |total, run| total.checked_add(*run));
+    if total != Some((width * height) as u64) {
+        return Err(mismatch("runs", total.unwrap_or(u64::MAX)));
+    }
+
+    let mut blocks = Vec::with_capacity(width * height);
+    for (literal, run) in rle.literals.iter().zip(rle.runs.iter()) {
+        blocks.push(literal.clone());
+        // the blocks in a run repeat the literal's chroma with no detail
+        for _ in 0..*run {
+            blocks.push(EncodedQuanta { b: 0, c: 0, d: 0, ..literal.clone() });
+        }
+    }
+    for (block, a) in blocks.iter_mut().zip(rle.a) {
+        block.a = a;
+    }
+    Array2::from_row_major(width, height, blocks).map_err(RpegError::DimensionMismatch)
 }
 
 
@@ -116,53 +199,115 @@ pub fn new_crt_array(rows: usize, cols: usize) -> Array2<Crt> {
         pb: 0.0,
         pr: 0.0,
     };
-    let crt_array = Array2::new(rows, cols, empty_crt);
-    crt_array
+    Array2::new(rows, cols, empty_crt)
 }
 
 
-// set 2x2 blocks of crts in crt array to have corresponding average pb and pr values
-// input: Array2<Quantized>, Array2<Crt>
+// set the crts in crt array to the average pb and pr values they share
+// input: Array2<Quantized> the size of the chroma planes, Array2<Crt>, chroma subsampling
 // output: Array2<Crt> (updated)
-pub fn set_pb_pr(decoded_quanta_array: Array2<Quantized>, crt_array: Array2<Crt>) -> Array2<Crt> {
-    
+pub fn set_pb_pr_in(decoded_quanta_array: Array2<Quantized>, crt_array: Array2<Crt>, subsampling: Subsampling) -> Result<Array2<Crt>, RpegError> {
+
+    // each quantized struct covers fx x fy crts: 2x2 for 4:2:0, 2x1 for 4:2:2, 1x1 for 4:4:4
+    let (fx, fy) = subsampling.factors();
+    expect_dimensions("crt array", (decoded_quanta_array.width() * fx, decoded_quanta_array.height() * fy), (crt_array.width(), crt_array.height()))?;
+
     // define new array2 of crt structs
     let mut new_crt_array = crt_array.clone();
 
-    // iterate through quantized_array in 2x2 blocks
+    // iterate through quantized_array, replicating each average over the crts it covers
     for (c, r, decoded_quanta) in decoded_quanta_array.iter_row_major() {
+        for dr in 0..fy {
+            for dc in 0..fx {
+                // update crt_array with new pb and pr values while keeping y values the same
+                let crt = new_crt_array.get_mut(c * fx + dc, r * fy + dr).unwrap();
+                crt.pb = decoded_quanta.pb_avg;
+                crt.pr = decoded_quanta.pr_avg;
+            }
+        }
+    }
+    Ok(new_crt_array)
+}
 
-        // update crt_array with new pb and pr values while keeping y values the same
-        // crt array is twice the size of the quantized array, so multiply c and r by 2
-        *new_crt_array.get_mut(c * 2, r * 2).unwrap() = Crt {
-            y: crt_array.get(c * 2, r * 2).unwrap().y,
-            pb: decoded_quanta.pb_avg,
-            pr: decoded_quanta.pr_avg,
-        };
-        *new_crt_array.get_mut(c * 2 + 1, r * 2).unwrap() = Crt {
-            y: crt_array.get(c * 2 + 1, r * 2).unwrap().y,
-            pb: decoded_quanta.pb_avg,
-            pr: decoded_quanta.pr_avg,
-        };
-        *new_crt_array.get_mut(c * 2, r * 2 + 1).unwrap() = Crt {
-            y: crt_array.get(c * 2, r * 2 + 1).unwrap().y,
-            pb: decoded_quanta.pb_avg,
-            pr: decoded_quanta.pr_avg,
-        };
-        *new_crt_array.get_mut(c * 2 + 1, r * 2 + 1).unwrap() = Crt {
-            y: crt_array.get(c * 2 + 1, r * 2 + 1).unwrap().y,
-            pb: decoded_quanta.pb_avg,
-            pr: decoded_quanta.pr_avg,
-        };
+
+// set_pb_pr_in with 4:2:0 subsampling, kept for the baseline tests
+// panics if the arrays do not line up
+#[cfg(test)]
+pub(crate) fn set_pb_pr(decoded_quanta_array: Array2<Quantized>, crt_array: Array2<Crt>) -> Array2<Crt> {
+    set_pb_pr_in(decoded_quanta_array, crt_array, Subsampling::Yuv420).unwrap()
+}
+
+
+// standard deviation, in luma units, of the luma difference weighting of guided upsampling
+const GUIDE_SIGMA: f32 = 0.05;
+
+
+// spread each chroma sample over nearby crts by bilinear interpolation between the
+// centres of the cells that share a sample, clamped at the image edges. luma_guided
+// also weights each sample by how close the mean luma of its cell is to the crt's own
+// luma (a joint bilateral filter), so color stays on its side of edges the luma shows
+// input: Array2<Quantized> the size of the chroma planes, Array2<Crt> with luma set,
+// chroma subsampling, whether to follow the luma
+// output: Array2<Crt> (updated)
+pub fn upsample_pb_pr(decoded_quanta_array: Array2<Quantized>, crt_array: Array2<Crt>, subsampling: Subsampling, luma_guided: bool) -> Result<Array2<Crt>, RpegError> {
+
+    let (fx, fy) = subsampling.factors();
+    let (plane_width, plane_height) = (decoded_quanta_array.width(), decoded_quanta_array.height());
+    expect_dimensions("crt array", (plane_width * fx, plane_height * fy), (crt_array.width(), crt_array.height()))?;
+    if (fx, fy) == (1, 1) || plane_width == 0 || plane_height == 0 {
+        // every crt has its own sample
+        return set_pb_pr_in(decoded_quanta_array, crt_array, subsampling);
+    }
+
+    // mean luma of the cell behind each chroma sample
+    let mut cell_luma = vec![0.0f32; plane_width * plane_height];
+    if luma_guided {
+        for (x, y, crt) in crt_array.iter_row_major() {
+            cell_luma[(y / fy) * plane_width + x / fx] += crt.y / (fx * fy) as f32;
+        }
     }
-    new_crt_array
+
+    // the two samples either side of pixel x along an axis, and their weights
+    let taps = |x: usize, factor: usize, samples: usize| {
+        let position = (x as f32 + 0.5) / factor as f32 - 0.5;
+        let low = position.floor();
+        let t = position - low;
+        let clamp = |i: f32| (i.max(0.0) as usize).min(samples - 1);
+        [(clamp(low), 1.0 - t), (clamp(low + 1.0), t)]
+    };
+
+    let mut crts = Vec::with_capacity(crt_array.get_data().len());
+    for (x, y, crt) in crt_array.iter_row_major() {
+        // (pb, pr, total weight) without and with the luma guide
+        let mut plain = (0.0, 0.0, 0.0);
+        let mut guided = (0.0, 0.0, 0.0);
+        for (c, wx) in taps(x, fx, plane_width) {
+            for (r, wy) in taps(y, fy, plane_height) {
+                let sample = decoded_quanta_array.get(c, r).unwrap();
+                let weight = wx * wy;
+                plain = (plain.0 + weight * sample.pb_avg, plain.1 + weight * sample.pr_avg, plain.2 + weight);
+                if luma_guided {
+                    let difference = crt.y - cell_luma[r * plane_width + c];
+                    let weight = weight * (-difference * difference / (2.0 * GUIDE_SIGMA * GUIDE_SIGMA)).exp();
+                    guided = (guided.0 + weight * sample.pb_avg, guided.1 + weight * sample.pr_avg, guided.2 + weight);
+                }
+            }
+        }
+        // a pixel unlike every cell around it falls back to plain interpolation
+        let (pb, pr, total) = if guided.2 > f32::EPSILON { guided } else { plain };
+        crts.push(Crt { y: crt.y, pb: pb / total, pr: pr / total });
+    }
+    Array2::from_row_major(crt_array.width(), crt_array.height(), crts).map_err(RpegError::DimensionMismatch)
 }
 
 
 // perform inverse dct
 // input: Array2<Quantized>, Array2<Crt>
 // output: Array2<Crt> (updated)
-pub fn inverse_dct(quantized_array: Array2<Quantized>, crt_array: Array2<Crt>) -> Array2<Crt> {
+pub fn try_inverse_dct(quantized_array: Array2<Quantized>, crt_array: Array2<Crt>) -> Result<Array2<Crt>, RpegError> {
+
+    // crt array is twice the size of the quantized array in each direction
+    expect_dimensions("crt array", (quantized_array.width() * 2, quantized_array.height() * 2), (crt_array.width(), crt_array.height()))?;
 
     // define new array2 of crt structs
     let mut new_crt_array = crt_array.clone();
@@ -171,10 +316,10 @@ pub fn inverse_dct(quantized_array: Array2<Quantized>, crt_array: Array2<Crt>) -
     for (c, r, quantized) in quantized_array.iter_row_major() {
 
         // get coefficients from current block
-        let a_coeff = quantized.a as f32;
-        let b_coeff = quantized.b as f32;
-        let c_coeff = quantized.c as f32;
-        let d_coeff = quantized.d as f32;
+        let a_coeff = quantized.a;
+        let b_coeff = quantized.b;
+        let c_coeff = quantized.c;
+        let d_coeff = quantized.d;
 
         // calculate y values from coefficients
         let y1 = a_coeff - b_coeff - c_coeff + d_coeff;
@@ -205,56 +350,281 @@ pub fn inverse_dct(quantized_array: Array2<Quantized>, crt_array: Array2<Crt>) -
             pr: crt_array.get(c * 2 + 1, r * 2 + 1).unwrap().pr
         };
     }
-    new_crt_array
+    Ok(new_crt_array)
+}
+
+
+// try_inverse_dct, kept for the baseline tests
+// panics if the arrays do not line up
+#[cfg(test)]
+pub(crate) fn inverse_dct(quantized_array: Array2<Quantized>, crt_array: Array2<Crt>) -> Array2<Crt> {
+    try_inverse_dct(quantized_array, crt_array).unwrap()
+}
+
+
+// dequantize 8x8 dct blocks and perform the inverse dct into Y
+// input: Array2<DctBlock>, quantization matrix, Array2<Crt> 8 times the size of the block array
+// output: Array2<Crt> (updated)
+pub fn inverse_dct_8x8(blocks: Array2<DctBlock>, matrix: &QuantMatrix, crt_array: Array2<Crt>) -> Result<Array2<Crt>, RpegError> {
+
+    expect_dimensions("crt array", (blocks.width() * 8, blocks.height() * 8), (crt_array.width(), crt_array.height()))?;
+
+    let basis = dct_basis();
+    let mut new_crt_array = crt_array;
+
+    for (bc, br, block) in blocks.iter_row_major() {
+
+        // dequantize, rows of the result are vertical frequencies
+        let mut coefficients = [[0.0f64; 8]; 8];
+        for (i, level) in block.levels.iter().enumerate() {
+            coefficients[i / 8][i % 8] = (*level * matrix.steps[i] as i64) as f64;
+        }
+
+        // separable inverse: along each row of frequencies, then down the columns
+        let rows = coefficients.map(|row| {
+            let mut samples = [0.0f64; 8];
+            for (x, sample) in samples.iter_mut().enumerate() {
+                *sample = (0..8).map(|u| basis[u][x] * row[u]).sum();
+            }
+            samples
+        });
+        for i in 0..64 {
+            let (x, y) = (i % 8, i / 8);
+            let sample: f64 = (0..8).map(|v| basis[v][y] * rows[v][x]).sum();
+            // undo the level shift back to Y in [0, 1]
+            new_crt_array.get_mut(bc * 8 + x, br * 8 + y).unwrap().y = ((sample + 128.0) / 255.0) as f32;
+        }
+    }
+    Ok(new_crt_array)
+}
+
+
+// how much ac detail each 2x2 block has, in luma units
+// input: Array2<Quantized> of decoded coefficients
+// output: Array2<f32> of |b| + |c| + |d| per block
+pub fn quanta_detail(quantized_array: &Array2<Quantized>) -> Array2<f32> {
+    let detail = quantized_array.get_data().iter().map(|q| q.b.abs() + q.c.abs() + q.d.abs()).collect();
+    Array2::from_row_major(quantized_array.width(), quantized_array.height(), detail).unwrap()
+}
+
+
+// how much ac detail each 8x8 block has, in luma units
+// input: Array2<DctBlock> and the matrix it was quantized with
+// output: Array2<f32> of the summed ac magnitudes per block, each scaled to the
+// largest swing its basis function makes in Y
+pub fn dct_block_detail(blocks: &Array2<DctBlock>, matrix: &QuantMatrix) -> Array2<f32> {
+    let detail = blocks.get_data().iter().map(|block| {
+        // saturate rather than trust the levels to be in range
+        let ac = block.levels.iter().zip(matrix.steps).skip(1)
+            .map(|(level, step)| level.unsigned_abs().saturating_mul(step as u64))
+            .fold(0u64, u64::saturating_add);
+        (ac as f64 / (4.0 * 255.0)) as f32
+    }).collect();
+    Array2::from_row_major(blocks.width(), blocks.height(), detail).unwrap()
+}
+
+
+// filter the edges between `cell` sized blocks of a row-major plane. `limits` gives
+// the quantization step and the flatness (1 for a flat block, less with more detail)
+// of the edge between the blocks at two block positions. an edge is only touched if
+// its jump is under twice the step, so real edges survive, and if neither side varies
+// by a step next to it; its two nearest samples then move towards each other by
+// (4(q0 - p0) + (p1 - q1)) / 8, at most step * flatness, all scaled by `strength`
+fn deblock_plane(plane: &mut [f32], width: usize, height: usize, cell: (usize, usize), strength: f32, limits: impl Fn((usize, usize), (usize, usize)) -> (f32, f32)) {
+    let filter = |plane: &mut [f32], [p1, p0, q0, q1]: [usize; 4], (step, flatness): (f32, f32)| {
+        let (p1v, p0v, q0v, q1v) = (plane[p1], plane[p0], plane[q0], plane[q1]);
+        let step = step * strength;
+        if (q0v - p0v).abs() >= 2.0 * step || (p1v - p0v).abs() >= step || (q1v - q0v).abs() >= step {
+            return;
+        }
+        let limit = step * flatness;
+        let delta = ((4.0 * (q0v - p0v) + (p1v - q1v)) / 8.0).clamp(-limit, limit);
+        plane[p0] = p0v + delta;
+        plane[q0] = q0v - delta;
+    };
+
+    // vertical edges, then horizontal edges
+    let (cell_width, cell_height) = cell;
+    if cell_width > 1 {
+        for x in (cell_width..width.saturating_sub(1)).step_by(cell_width) {
+            for y in 0..height {
+                let at = |x: usize| y * width + x;
+                let blocks = ((x / cell_width - 1, y / cell_height), (x / cell_width, y / cell_height));
+                filter(plane, [at(x - 2), at(x - 1), at(x), at(x + 1)], limits(blocks.0, blocks.1));
+            }
+        }
+    }
+    if cell_height > 1 {
+        for y in (cell_height..height.saturating_sub(1)).step_by(cell_height) {
+            for x in 0..width {
+                let at = |y: usize| y * width + x;
+                let blocks = ((x / cell_width, y / cell_height - 1), (x / cell_width, y / cell_height));
+                filter(plane, [at(y - 2), at(y - 1), at(y), at(y + 1)], limits(blocks.0, blocks.1));
+            }
+        }
+    }
+}
+
+
+// smooth luma across the edges of transform blocks
+// input: Array2<Crt> after the inverse transform, the ac detail of each block (see
+// quanta_detail and dct_block_detail), the block size in pixels, the quantization
+// step in luma units, and the filter strength (1.0 as designed, 0 to turn it off)
+// output: Array2<Crt> (updated)
+pub fn deblock_luma(crt_array: Array2<Crt>, detail: &Array2<f32>, block_size: usize, step: f32, strength: f32) -> Result<Array2<Crt>, RpegError> {
+    expect_dimensions("crt array", (detail.width() * block_size, detail.height() * block_size), (crt_array.width(), crt_array.height()))?;
+    let (width, height) = (crt_array.width(), crt_array.height());
+    let mut plane: Vec<f32> = crt_array.get_data().iter().map(|crt| crt.y).collect();
+
+    // blocks with more detail than a step are likelier to have real edges
+    let flatness = |block: (usize, usize)| {
+        let detail = *detail.get(block.0, block.1).unwrap();
+        if detail <= step { 1.0 } else { step / detail }
+    };
+    deblock_plane(&mut plane, width, height, (block_size, block_size), strength, |p, q| (step, flatness(p).min(flatness(q))));
+
+    let crts = crt_array.get_data().iter().zip(plane).map(|(crt, y)| Crt { y, ..*crt }).collect();
+    Array2::from_row_major(width, height, crts).map_err(RpegError::DimensionMismatch)
+}
+
+
+// smooth pb and pr across the edges of the cells that share a chroma sample
+// input: Array2<Crt> with chroma set by set_pb_pr, the subsampling, the chroma
+// quantization step, and the filter strength
+// output: Array2<Crt> (updated)
+pub fn deblock_chroma(crt_array: Array2<Crt>, subsampling: Subsampling, step: f32, strength: f32) -> Result<Array2<Crt>, RpegError> {
+    let (width, height) = (crt_array.width(), crt_array.height());
+    let mut pb: Vec<f32> = crt_array.get_data().iter().map(|crt| crt.pb).collect();
+    let mut pr: Vec<f32> = crt_array.get_data().iter().map(|crt| crt.pr).collect();
+    for plane in [&mut pb, &mut pr] {
+        deblock_plane(plane, width, height, subsampling.factors(), strength, |_, _| (step, 1.0));
+    }
+
+    let crts = crt_array.get_data().iter().zip(pb).zip(pr).map(|((crt, pb), pr)| Crt { pb, pr, ..*crt }).collect();
+    Array2::from_row_major(width, height, crts).map_err(RpegError::DimensionMismatch)
 }
 
 
-// turn crt into rgb float
-fn crt_to_rgb_float(pixel: Crt) -> RgbFloat {
-    RgbFloat {
-        red: pixel.y + 1.402 * pixel.pr,
-        green: pixel.y - 0.344136 * pixel.pb - 0.714136 * pixel.pr,
-        blue: pixel.y + 1.772 * pixel.pb,
+// turn crt into rgb float, reversing rgb_float_to_crt
+fn crt_to_rgb_float(pixel: Crt, colorspace: ColorSpace) -> RgbFloat {
+    match (colorspace, colorspace.luma_weights()) {
+        (ColorSpace::Bt601, _) => RgbFloat {
+            red: pixel.y + 1.402 * pixel.pr,
+            green: pixel.y - 0.344136 * pixel.pb - 0.714136 * pixel.pr,
+            blue: pixel.y + 1.772 * pixel.pb,
+        },
+        (_, Some((kr, kb))) => {
+            let red = pixel.y + 2.0 * (1.0 - kr) * pixel.pr;
+            let blue = pixel.y + 2.0 * (1.0 - kb) * pixel.pb;
+            RgbFloat {
+                red,
+                green: (pixel.y - kr * red - kb * blue) / (1.0 - kr - kb),
+                blue,
+            }
+        }
+        // ycocg, with co in pb and cg in pr
+        (_, None) => {
+            let base = pixel.y - pixel.pr;
+            RgbFloat {
+                red: base + pixel.pb,
+                green: pixel.y + pixel.pr,
+                blue: base - pixel.pb,
+            }
+        }
     }
 }
 
-// turn crt array into rgb float array
+// turn crt array into rgb float array with the bt.601 matrix
 pub fn crt_array_to_rgb_float_array(crt_array: Array2<Crt>) -> Array2<RgbFloat> {
+    crt_array_to_rgb_float_array_in(crt_array, ColorSpace::Bt601)
+}
+
+// turn crt array into rgb float array with the given color matrix
+pub fn crt_array_to_rgb_float_array_in(crt_array: Array2<Crt>, colorspace: ColorSpace) -> Array2<RgbFloat> {
     
     // define new array2 of rgb floats
     let mut float_array = Array2::new(crt_array.width(), crt_array.height(), RgbFloat { red: 0.0, green: 0.0, blue: 0.0 });
 
     // iterate through crt_array and convert each pixel to rgb float using helper function
     for (c, r, pixel) in crt_array.iter_row_major() {
-        *float_array.get_mut(c, r).unwrap() = crt_to_rgb_float(*pixel);
+        *float_array.get_mut(c, r).unwrap() = crt_to_rgb_float(*pixel, colorspace);
     }
     float_array
 }
 
-// rgb float to rgb int
-fn rgb_float_to_rgb_int(pixel: RgbFloat) -> Rgb {
-    Rgb {
-        red: (pixel.red * 255.0) as u16,
-        green: (pixel.green * 255.0) as u16,
-        blue: (pixel.blue * 255.0) as u16,
+// thresholds of ordered dithering, in 64ths of a level
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+
+// round one channel of an image, scaled to 0..=maxval, to integer levels
+// input: row-major samples, width, height, maxval, dithering
+// output: row-major levels, number of samples that rounded to outside 0..=maxval
+fn quantize_plane(mut plane: Vec<f32>, width: usize, height: usize, maxval: u16, dither: Dither) -> (Vec<u16>, usize) {
+    let top = maxval as f32;
+    // clipping is counted on the samples as decoded, before any dither moves them
+    let clipped = plane.iter().filter(|&&value| value.round() < 0.0 || value.round() > top).count();
+    let mut levels = Vec::with_capacity(plane.len());
+    for r in 0..height {
+        for c in 0..width {
+            let value = plane[r * width + c];
+            let value = match dither {
+                Dither::None | Dither::FloydSteinberg => value,
+                Dither::Ordered => value + (BAYER_8X8[r % 8][c % 8] as f32 + 0.5) / 64.0 - 0.5,
+            };
+            let level = value.round().clamp(0.0, top);
+            levels.push(level as u16);
+            if dither == Dither::FloydSteinberg {
+                // out of range values only pass on their rounding error, not the clipping
+                let error = value.clamp(0.0, top) - level;
+                let mut spread = |dc: isize, dr: usize, weight: f32| {
+                    let column = c as isize + dc;
+                    if column >= 0 && (column as usize) < width && r + dr < height {
+                        plane[(r + dr) * width + column as usize] += error * weight / 16.0;
+                    }
+                };
+                spread(1, 0, 7.0);
+                spread(-1, 1, 3.0);
+                spread(0, 1, 5.0);
+                spread(1, 1, 1.0);
+            }
+        }
     }
+    (levels, clipped)
 }
 
 
-// converts a whole array2 of rgb floats to an array2 of rgb ints
+// converts a whole array2 of rgb floats to an array2 of rgb ints at the given maxval,
+// rounding to the nearest level (after dithering, if any) and clamping to 0..=maxval
+// output: Array2<Rgb>, number of red, green and blue samples that had to be clamped
+pub fn quantize_rgb_float_array(float_array: Array2<RgbFloat>, maxval: u16, dither: Dither) -> (Array2<Rgb>, [usize; 3]) {
+    let (width, height) = (float_array.width(), float_array.height());
+    let scale = maxval as f32;
+    let channel = |sample: fn(&RgbFloat) -> f32| {
+        let plane = float_array.get_data().iter().map(|pixel| sample(pixel) * scale).collect();
+        quantize_plane(plane, width, height, maxval, dither)
+    };
+    let (red, red_clipped) = channel(|pixel| pixel.red);
+    let (green, green_clipped) = channel(|pixel| pixel.green);
+    let (blue, blue_clipped) = channel(|pixel| pixel.blue);
+
+    let pixels = red.into_iter().zip(green).zip(blue)
+        .map(|((red, green), blue)| Rgb { red, green, blue })
+        .collect();
+    let int_array = Array2::from_row_major(width, height, pixels).unwrap();
+    (int_array, [red_clipped, green_clipped, blue_clipped])
+}
+
+
+// converts a whole array2 of rgb floats to an array2 of 8-bit rgb ints, rounding without dithering
 pub fn rgb_float_array_to_rgb_int_array(float_array: Array2<RgbFloat>) -> Array2<Rgb> {
-    
-    // define new array2 of empty rgb ints
-    let mut int_array = Array2::new(float_array.width(), float_array.height(), Rgb { red: 0, green: 0, blue: 0 });
-
-    // iterate through each pixel in the array2, converting each to an rgb int using helper function
-    for (c, r, pixel) in float_array.iter_row_major() {
-        let pixel_clone = RgbFloat {
-            red: (*pixel).red,
-            green: (*pixel).green,
-            blue: (*pixel).blue,
-        };
-        *int_array.get_mut(c, r).unwrap() = rgb_float_to_rgb_int(pixel_clone);
-    }
-    int_array
-}
\ No newline at end of file
+    quantize_rgb_float_array(float_array, 255, Dither::None).0
+}
@@ -0,0 +1,428 @@
+use crate::error::RpegError;
+
+
+/*
+zlib / deflate (rfc 1950, rfc 1951)
+-------------------------
+png stores its pixel data as a zlib stream: a two byte header, deflate
+blocks, and the adler-32 of the uncompressed data, big-endian
+
+deflate packs bits least significant first, but huffman codes are
+stored from their first (most significant) bit. each block starts with a
+final-block flag and a 2-bit type:
+- 0 stored: aligned to a byte, then length, its complement, and the bytes
+- 1 fixed huffman codes
+- 2 dynamic huffman codes, whose code lengths are themselves huffman coded
+literal/length symbols 0-255 are bytes, 256 ends the block, and 257-285
+start a match whose distance symbol follows
+
+inflate handles all three block types. compression writes a single
+fixed-huffman block, with matches found through hash chains over the
+last 32k of input, which is plenty for png scanlines
+*/
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 64;
+
+// base lengths and extra bits of length symbols 257-285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// base distances and extra bits of distance symbols 0-29
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// order in which code length code lengths are stored in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+
+// adler-32 checksum of zlib streams
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before b could overflow
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+
+fn malformed(msg: &str) -> RpegError {
+    RpegError::MalformedHeader(format!("deflate stream {}", msg))
+}
+
+
+// reads deflate's least-significant-first bits
+struct InflateReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> InflateReader<'a> {
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, RpegError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.bytes.get(self.pos).ok_or_else(|| malformed("ended early"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    // skip to the next byte boundary
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+
+// a canonical huffman code given by code lengths: the number of codes of
+// each length, and the symbols ordered by code
+struct InflateTable {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl InflateTable {
+
+    fn new(lengths: &[u8]) -> Result<Self, RpegError> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        // more codes of a length than there is room for can never decode
+        let mut left = 1i32;
+        for &count in counts.iter().skip(1) {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(malformed("has an over-subscribed huffman code"));
+            }
+        }
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for length in 1..16 {
+            for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l as usize == length) {
+                symbols.push(symbol as u16);
+            }
+        }
+        Ok(InflateTable { counts, symbols })
+    }
+
+    // read a code one bit at a time; the codes of each length are consecutive
+    fn decode(&self, reader: &mut InflateReader) -> Result<u16, RpegError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in self.counts.iter().skip(1) {
+            code |= reader.read_bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(malformed("contains an invalid huffman code"))
+    }
+}
+
+
+// the fixed literal/length and distance codes of block type 1
+fn fixed_tables() -> Result<(InflateTable, InflateTable), RpegError> {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((InflateTable::new(&lengths)?, InflateTable::new(&[5; 30])?))
+}
+
+
+// read the code lengths of a dynamic block and build its two tables
+fn dynamic_tables(reader: &mut InflateReader) -> Result<(InflateTable, InflateTable), RpegError> {
+    let literals = reader.read_bits(5)? as usize + 257;
+    let distances = reader.read_bits(5)? as usize + 1;
+    let code_lengths = reader.read_bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(malformed("has too many codes in a dynamic block"));
+    }
+
+    let mut length_lengths = [0u8; 19];
+    for &symbol in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        length_lengths[symbol] = reader.read_bits(3)? as u8;
+    }
+    let length_table = InflateTable::new(&length_lengths)?;
+
+    // 16 repeats the previous length, 17 and 18 are runs of zeros
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let symbol = length_table.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| malformed("repeats a code length before the first"))?;
+                (previous, 3 + reader.read_bits(2)?)
+            }
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() > literals + distances {
+        return Err(malformed("has code lengths that run past the end of the table"));
+    }
+    if lengths[256] == 0 {
+        return Err(malformed("has no end of block code"));
+    }
+    Ok((InflateTable::new(&lengths[..literals])?, InflateTable::new(&lengths[literals..])?))
+}
+
+
+// decode the symbols of one huffman-coded block onto the output, which may not grow past `max_output` bytes
+fn inflate_block(reader: &mut InflateReader, output: &mut Vec<u8>, max_output: usize, literal_table: &InflateTable, distance_table: &InflateTable) -> Result<(), RpegError> {
+    loop {
+        let symbol = literal_table.decode(reader)? as usize;
+        if symbol != 256 && output.len() >= max_output {
+            return Err(malformed("inflates past its expected size"));
+        }
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let length = LENGTH_BASE[symbol - 257] as usize + reader.read_bits(LENGTH_EXTRA[symbol - 257] as u32)? as usize;
+                let code = distance_table.decode(reader)? as usize;
+                if code >= 30 {
+                    return Err(malformed("has an invalid distance code"));
+                }
+                let distance = DIST_BASE[code] as usize + reader.read_bits(DIST_EXTRA[code] as u32)? as usize;
+                if distance > output.len() {
+                    return Err(malformed("refers back past its start"));
+                }
+                // copy byte by byte, since a match may overlap what it produces
+                let start = output.len() - distance;
+                if length > max_output - output.len() {
+                    return Err(malformed("inflates past its expected size"));
+                }
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+            _ => return Err(malformed("has an invalid length code")),
+        }
+    }
+}
+
+
+// decompress a raw deflate stream of at most `max_output` bytes
+// output: the decompressed bytes and the number of input bytes used
+pub fn inflate(bytes: &[u8], max_output: usize) -> Result<(Vec<u8>, usize), RpegError> {
+    let mut reader = InflateReader { bytes, pos: 0, bit: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            0 => {
+                reader.align();
+                let header = bytes.get(reader.pos..reader.pos + 4).ok_or_else(|| malformed("ended early"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(malformed("has a stored block with a corrupt length"));
+                }
+                let start = reader.pos + 4;
+                let data = bytes.get(start..start + length as usize).ok_or_else(|| malformed("ended early"))?;
+                if data.len() > max_output - output.len() {
+                    return Err(malformed("inflates past its expected size"));
+                }
+                output.extend_from_slice(data);
+                reader.pos = start + length as usize;
+            }
+            1 => {
+                let (literals, distances) = fixed_tables()?;
+                inflate_block(&mut reader, &mut output, max_output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &mut output, max_output, &literals, &distances)?;
+            }
+            _ => return Err(malformed("has an invalid block type")),
+        }
+        if last {
+            break;
+        }
+    }
+    reader.align();
+    Ok((output, reader.pos))
+}
+
+
+// decompress a zlib stream of at most `max_output` bytes, checking its header and adler-32
+pub fn zlib_decompress(bytes: &[u8], max_output: usize) -> Result<Vec<u8>, RpegError> {
+    if bytes.len() < 2 || bytes[0] & 15 != 8 || !(bytes[0] as u16 * 256 + bytes[1] as u16).is_multiple_of(31) {
+        return Err(RpegError::MalformedHeader("invalid zlib header".to_string()));
+    }
+    if bytes[1] & 0x20 != 0 {
+        return Err(RpegError::UnsupportedFormat("zlib stream with a preset dictionary".to_string()));
+    }
+    let (output, used) = inflate(&bytes[2..], max_output)?;
+    let checksum = bytes.get(2 + used..6 + used).ok_or_else(|| malformed("is missing its adler-32"))?;
+    if u32::from_be_bytes(checksum.try_into().unwrap()) != adler32(&output) {
+        return Err(RpegError::MalformedHeader("zlib checksum does not match its data".to_string()));
+    }
+    Ok(output)
+}
+
+
+// writes deflate's least-significant-first bits
+#[derive(Default)]
+struct DeflateWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl DeflateWriter {
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.pending |= (value as u64) << self.pending_bits;
+        self.pending_bits += count;
+        while self.pending_bits >= 8 {
+            self.bytes.push(self.pending as u8);
+            self.pending >>= 8;
+            self.pending_bits -= 8;
+        }
+    }
+
+    // huffman codes go first bit first, the reverse of other fields
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.bytes.push(self.pending as u8);
+        }
+        self.bytes
+    }
+}
+
+
+// write a literal/length symbol with the fixed code
+fn write_fixed_symbol(writer: &mut DeflateWriter, symbol: usize) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+
+// write a match as its length and distance symbols and their extra bits
+fn write_match(writer: &mut DeflateWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_fixed_symbol(writer, 257 + code);
+    writer.write_bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+    let code = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+    writer.write_code(code as u32, 5);
+    writer.write_bits((distance - DIST_BASE[code] as usize) as u32, DIST_EXTRA[code] as u32);
+}
+
+
+const HASH_SIZE: usize = 1 << 15;
+
+
+// hash of the three bytes starting at pos
+fn hash(bytes: &[u8], pos: usize) -> usize {
+    let value = (bytes[pos] as usize) << 16 | (bytes[pos + 1] as usize) << 8 | bytes[pos + 2] as usize;
+    (value.wrapping_mul(2654435761) >> 7) & (HASH_SIZE - 1)
+}
+
+
+// record pos as the most recent position with its hash
+fn insert_hash(bytes: &[u8], pos: usize, head: &mut [usize], previous: &mut [usize]) {
+    if pos + MIN_MATCH <= bytes.len() {
+        let h = hash(bytes, pos);
+        previous[pos] = head[h];
+        head[h] = pos;
+    }
+}
+
+
+// compress bytes into a single fixed-huffman deflate block
+pub fn deflate(bytes: &[u8]) -> Vec<u8> {
+    // most recent position with each hash, and the one before it for each position
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut previous = vec![usize::MAX; bytes.len()];
+
+    let mut writer = DeflateWriter::default();
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut pos = 0;
+    while pos < bytes.len() {
+        // longest match among the recent positions with the same hash
+        let (mut best_length, mut best_distance) = (0, 0);
+        if pos + MIN_MATCH <= bytes.len() {
+            let mut candidate = head[hash(bytes, pos)];
+            let longest = MAX_MATCH.min(bytes.len() - pos);
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > WINDOW {
+                    break;
+                }
+                let length = (0..longest).take_while(|&i| bytes[candidate + i] == bytes[pos + i]).count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, pos - candidate);
+                    if length == longest {
+                        break;
+                    }
+                }
+                candidate = previous[candidate];
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut writer, best_length, best_distance);
+            for i in pos..pos + best_length {
+                insert_hash(bytes, i, &mut head, &mut previous);
+            }
+            pos += best_length;
+        } else {
+            write_fixed_symbol(&mut writer, bytes[pos] as usize);
+            insert_hash(bytes, pos, &mut head, &mut previous);
+            pos += 1;
+        }
+    }
+    write_fixed_symbol(&mut writer, 256);
+    writer.finish()
+}
+
+
+// compress bytes into a zlib stream
+pub fn zlib_compress(bytes: &[u8]) -> Vec<u8> {
+    // deflate with a 32k window, default compression level
+    let mut output = vec![0x78, 0x9C];
+    output.extend(deflate(bytes));
+    output.extend_from_slice(&adler32(bytes).to_be_bytes());
+    output
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::io;
+
+
+// errors that can occur while encoding or decoding an image
+#[derive(Debug)]
+pub enum RpegError {
+    // reading or writing the underlying stream failed
+    Io(io::Error),
+    // the input did not start with a header we understand
+    MalformedHeader(String),
+    // the amount of data does not match the dimensions in the header,
+    // or two arrays passed to a pipeline step do not line up
+    DimensionMismatch(String),
+    // a value does not fit in the bits reserved for it in a codeword
+    FieldOverflow { field: &'static str, value: i64, width: u64 },
+    // the input is a format (or variant of a format) that is not supported
+    UnsupportedFormat(String),
+    // an encoder or decoder option is out of range
+    InvalidConfig(String),
+    // no encoder settings meet a size or quality target
+    TargetNotMet(String),
+}
+
+
+impl fmt::Display for RpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpegError::Io(err) => write!(f, "i/o error: {}", err),
+            RpegError::MalformedHeader(msg) => write!(f, "malformed header: {}", msg),
+            RpegError::DimensionMismatch(msg) => write!(f, "dimension mismatch: {}", msg),
+            RpegError::FieldOverflow { field, value, width } => {
+                write!(f, "value {} of field {} does not fit in {} bits", value, field, width)
+            }
+            RpegError::UnsupportedFormat(msg) => write!(f, "unsupported format: {}", msg),
+            RpegError::InvalidConfig(msg) => write!(f, "invalid configuration: {}", msg),
+            RpegError::TargetNotMet(msg) => write!(f, "target not met: {}", msg),
+        }
+    }
+}
+
+
+impl std::error::Error for RpegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpegError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for RpegError {
+    fn from(err: io::Error) -> Self {
+        RpegError::Io(err)
+    }
+}
+
+
+// check that an array has the dimensions a pipeline step expects
+pub(crate) fn expect_dimensions(what: &str, expected: (usize, usize), found: (usize, usize)) -> Result<(), RpegError> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(RpegError::DimensionMismatch(format!(
+            "expected {} to be {}x{}, found {}x{}",
+            what, expected.0, expected.1, found.0, found.1
+        )))
+    }
+}
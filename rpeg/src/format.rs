@@ -0,0 +1,1044 @@
+use std::io::Write;
+use crate::checksum::crc32;
+use crate::error::RpegError;
+use crate::layout::{CodewordLayout, Field};
+use array2::Array2;
+use crate::bitio::{BitReader, BitWriter};
+use crate::structures::{BlockTransform, ChromaQuantizer, CHROMA_BITS, ColorSpace, DcPrediction, DctBlock, EncodedQuanta, EntropyCoding, QuantMatrix, QuantParams, RunLengthQuanta, Subsampling};
+
+
+/*
+rpeg file format, version 2
+-------------------------
+offset  size  contents
+0       4     magic "RPEG"
+4       1     version (2)
+5       4     length n of the parameter block (big-endian)
+9       n     parameter block
+9+n     4     length p of the payload (big-endian)
+13+n    p     payload
+13+n+p  4     crc32 of the parameter block and payload (big-endian)
+
+the parameter block is a sequence of records, each a one-byte tag, a
+two-byte big-endian length and that many bytes of value:
+
+tag  record       value
+1    dimensions   width u32, height u32 of the original image; odd dimensions
+                  are padded to a whole number of 2x2 blocks by repeating the
+                  last row or column, and cropped again on decompression
+2    quantizer    a_scale u64, bcd_range f32 bits u32, bcd_levels u32
+3    layout       word_bits u8, then width u8 and lsb u8 for a, b, c, d, pb, pr
+4    colorspace   u8 (0 = bt.601, 1 = bt.709, 2 = bt.2020, 3 = ycocg, with
+                  co and cg in place of pb and pr)
+5    subsampling  u8 (0 = 4:2:0, 1 = 4:2:2, 2 = 4:4:4); must be 4:2:0 for
+                  grayscale images and with run-length coding
+6    transform    u8 (0 = 2x2, 1 = 8x8 dct); absent means 2x2
+7    dct matrix   64 u16 quantizer steps, row-major; only with the 8x8 dct
+8    entropy      u8 (0 = fixed-width, 1 = huffman, 2 = range coder); absent
+                  means fixed-width
+9    prediction   u8 (0 = none, 1 = med); absent means none; only with
+                  huffman or range coding
+10   run length   u8 (1 = runs of flat blocks are collapsed); absent means
+                  not; only with the 2x2 transform and huffman or range coding
+11   grayscale    u8 (1 = luma only, no chroma is stored); absent means color;
+                  the layout's pb and pr fields have width 0 exactly when set
+12   chroma quant u8 kind (0 = csc411, 1 = uniform, 2 = adaptive), u8 bits per
+                  index (4 to 8); then for adaptive, for pb and then pr, a
+                  u16 count and that many f32 levels (as bits u32) in
+                  increasing order; absent means csc411 (4 bits); not with
+                  grayscale images, and the layout's pb and pr fields must be
+                  at least bits wide
+13   maxval       u16 largest sample value of the original image (1 to
+                  65535); absent means 255. decoders write images back out
+                  with this maxval
+
+the pb and pr planes hold one chroma index per 2x2 block (4:2:0),
+per horizontal pair of pixels (4:2:2) or per pixel (4:4:4); "chroma
+samples" below means every sample of the planes, in row-major order
+
+with the 2x2 transform the payload is one codeword per 2x2 block, in
+row-major order, each taking as many bytes as the layout's word size. a
+codeword's pb and pr are the chroma sample at the top left of its block;
+with 4:2:2 and 4:4:4 the other chroma samples follow the codewords as a
+bit stream (see bitio) of a pb index and pr index each, as wide as the
+chroma quantizer's bits
+
+with the 8x8 dct, dimensions are padded to a multiple of 8 instead, and
+the payload is a bit stream (see bitio) of
+- the 64 levels of each 8x8 luma block, blocks in row-major order, each
+  level in two's complement using the widths from QuantMatrix::level_widths
+- then the pb index and pr index of each chroma sample, as wide as the
+  chroma quantizer's bits (left out of grayscale images)
+
+with huffman or range coding the payload is instead the coded streams
+(see huffman and rangecoder) of each field, in order:
+- 2x2 transform: a, b, c, d, one value per 2x2 block, then pb, pr,
+  one value per chroma sample
+- 8x8 dct: dc (level 0 of each 8x8 block), ac (levels 1 to 63 of each
+  8x8 block, block after block), pb, pr (one per chroma sample)
+grayscale images leave out the pb and pr streams.
+with med prediction the first stream (a, or dc) holds each value minus
+its prediction from the blocks to its left and above
+
+with run-length coding (see RunLengthQuanta) the payload starts with the
+number of literal blocks n as a big-endian u32, and the streams are a
+(one per 2x2 block), runs, b, c, d, pb, pr (n values each)
+
+legacy files, as written by csc411_rpegio, are also read:
+"Compressed image format 2\n"
+"{width} {height}\n"
+one big-endian 32-bit codeword per 2x2 block
+they use the default quantizer parameters and 32-bit layout
+*/
+
+const MAGIC: &[u8] = b"RPEG";
+const VERSION: u8 = 2;
+const LEGACY_MAGIC: &[u8] = b"Compressed image format 2";
+
+const TAG_DIMENSIONS: u8 = 1;
+const TAG_QUANT: u8 = 2;
+const TAG_LAYOUT: u8 = 3;
+const TAG_COLORSPACE: u8 = 4;
+const TAG_SUBSAMPLING: u8 = 5;
+const TAG_TRANSFORM: u8 = 6;
+const TAG_DCT_MATRIX: u8 = 7;
+const TAG_ENTROPY: u8 = 8;
+const TAG_PREDICTION: u8 = 9;
+const TAG_RUN_LENGTH: u8 = 10;
+const TAG_GRAYSCALE: u8 = 11;
+const TAG_CHROMA_QUANT: u8 = 12;
+const TAG_MAXVAL: u8 = 13;
+
+// maxval of images whose header has no maxval record
+pub const DEFAULT_MAXVAL: u16 = 255;
+
+
+// everything in an rpeg header besides the payload itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub width: usize,
+    pub height: usize,
+    pub quant: QuantParams,
+    pub layout: CodewordLayout,
+    pub colorspace: ColorSpace,
+    pub subsampling: Subsampling,
+    pub transform: BlockTransform,
+    // quantization matrix of the 8x8 dct; None with the 2x2 transform
+    pub dct_matrix: Option<QuantMatrix>,
+    pub entropy: EntropyCoding,
+    pub prediction: DcPrediction,
+    pub run_length: bool,
+    pub grayscale: bool,
+    pub chroma_quant: ChromaQuantizer,
+    // largest sample value of the image that was encoded, and is decoded
+    pub maxval: u16,
+}
+
+
+impl Header {
+
+    // bits in each chroma index of the payload; none for grayscale images
+    pub fn chroma_bits(&self) -> u64 {
+        if self.grayscale { 0 } else { self.chroma_quant.bits() }
+    }
+}
+
+
+// append one tagged record to a parameter block
+fn push_record(block: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    block.push(tag);
+    block.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    block.extend_from_slice(value);
+}
+
+
+// serialize the header into a parameter block
+fn parameter_block(header: &Header) -> Result<Vec<u8>, RpegError> {
+
+    let too_large = |what: &str| RpegError::DimensionMismatch(format!("{} is too large for an rpeg file", what));
+    let width = u32::try_from(header.width).map_err(|_| too_large("width"))?;
+    let height = u32::try_from(header.height).map_err(|_| too_large("height"))?;
+
+    let mut block = Vec::new();
+
+    let mut dimensions = Vec::new();
+    dimensions.extend_from_slice(&width.to_be_bytes());
+    dimensions.extend_from_slice(&height.to_be_bytes());
+    push_record(&mut block, TAG_DIMENSIONS, &dimensions);
+
+    let mut quant = Vec::new();
+    quant.extend_from_slice(&header.quant.a_scale.to_be_bytes());
+    quant.extend_from_slice(&header.quant.bcd_range.to_bits().to_be_bytes());
+    quant.extend_from_slice(&(header.quant.bcd_levels as u32).to_be_bytes());
+    push_record(&mut block, TAG_QUANT, &quant);
+
+    let mut layout = vec![header.layout.word_bits as u8];
+    for (_, field) in header.layout.fields() {
+        layout.push(field.width as u8);
+        layout.push(field.lsb as u8);
+    }
+    push_record(&mut block, TAG_LAYOUT, &layout);
+
+    push_record(&mut block, TAG_COLORSPACE, &[colorspace_code(header.colorspace)]);
+    push_record(&mut block, TAG_SUBSAMPLING, &[subsampling_code(header.subsampling)]);
+
+    // 2x2 files leave the transform out, so they read the same as before it existed
+    if header.transform != BlockTransform::Haar2x2 {
+        push_record(&mut block, TAG_TRANSFORM, &[transform_code(header.transform)]);
+    }
+    if let Some(matrix) = header.dct_matrix {
+        let steps: Vec<u8> = matrix.steps.iter().flat_map(|step| step.to_be_bytes()).collect();
+        push_record(&mut block, TAG_DCT_MATRIX, &steps);
+    }
+    if header.entropy != EntropyCoding::Fixed {
+        push_record(&mut block, TAG_ENTROPY, &[entropy_code(header.entropy)]);
+    }
+    if header.prediction != DcPrediction::None {
+        push_record(&mut block, TAG_PREDICTION, &[prediction_code(header.prediction)]);
+    }
+    if header.run_length {
+        push_record(&mut block, TAG_RUN_LENGTH, &[1]);
+    }
+    if header.grayscale {
+        push_record(&mut block, TAG_GRAYSCALE, &[1]);
+    }
+    if header.chroma_quant != ChromaQuantizer::Csc411 {
+        push_record(&mut block, TAG_CHROMA_QUANT, &chroma_quant_record(&header.chroma_quant));
+    }
+    if header.maxval != DEFAULT_MAXVAL {
+        push_record(&mut block, TAG_MAXVAL, &header.maxval.to_be_bytes());
+    }
+
+    Ok(block)
+}
+
+
+// write a header and payload in the rpeg v2 container
+pub fn write_rpeg<W: Write>(writer: &mut W, header: &Header, payload: &[u8]) -> Result<(), RpegError> {
+
+    let block = parameter_block(header)?;
+    let payload_len = u32::try_from(payload.len())
+        .map_err(|_| RpegError::DimensionMismatch("payload is too large for an rpeg file".to_string()))?;
+
+    let mut bytes = Vec::with_capacity(block.len() + payload.len() + 17);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&block);
+    bytes.extend_from_slice(&payload_len.to_be_bytes());
+    bytes.extend_from_slice(payload);
+
+    // the checksum covers everything after the length of the parameter block
+    let mut checked = block;
+    checked.extend_from_slice(payload);
+    bytes.extend_from_slice(&crc32(&checked).to_be_bytes());
+
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+
+// sequential reader over the bytes of a container
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+
+    fn take(&mut self, count: usize, what: &str) -> Result<&'a [u8], RpegError> {
+        let end = self.pos.checked_add(count).filter(|&end| end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let taken = &self.bytes[self.pos..end];
+                self.pos = end;
+                Ok(taken)
+            }
+            None => Err(RpegError::MalformedHeader(format!("file ends in the middle of the {}", what))),
+        }
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, RpegError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, RpegError> {
+        let bytes = self.take(2, what)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, RpegError> {
+        let bytes = self.take(4, what)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64, RpegError> {
+        let high = self.u32(what)? as u64;
+        let low = self.u32(what)? as u64;
+        Ok((high << 32) | low)
+    }
+
+    fn finished(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+
+// check that a record's value was exactly the expected size
+fn expect_record_len(tag: u8, value: &[u8], expected: usize) -> Result<(), RpegError> {
+    if value.len() != expected {
+        return Err(RpegError::MalformedHeader(format!(
+            "record {} should be {} bytes, found {}",
+            tag,
+            expected,
+            value.len()
+        )));
+    }
+    Ok(())
+}
+
+
+// parse a parameter block into a header
+fn parse_parameter_block(block: &[u8]) -> Result<Header, RpegError> {
+
+    let mut reader = ByteReader { bytes: block, pos: 0 };
+    let mut dimensions = None;
+    let mut quant = None;
+    let mut layout = None;
+    let mut colorspace = ColorSpace::default();
+    let mut subsampling = Subsampling::default();
+    let mut transform = BlockTransform::default();
+    let mut dct_matrix = None;
+    let mut entropy = EntropyCoding::default();
+    let mut prediction = DcPrediction::default();
+    let mut run_length = false;
+    let mut grayscale = false;
+    let mut chroma_quant = ChromaQuantizer::default();
+    let mut maxval = DEFAULT_MAXVAL;
+
+    while !reader.finished() {
+        let tag = reader.u8("parameter block")?;
+        let len = reader.u16("parameter block")? as usize;
+        let value = reader.take(len, "parameter block")?;
+        let mut fields = ByteReader { bytes: value, pos: 0 };
+
+        match tag {
+            TAG_DIMENSIONS => {
+                expect_record_len(tag, value, 8)?;
+                let width = fields.u32("dimensions")? as usize;
+                let height = fields.u32("dimensions")? as usize;
+                dimensions = Some((width, height));
+            }
+            TAG_QUANT => {
+                expect_record_len(tag, value, 16)?;
+                let params = QuantParams {
+                    a_scale: fields.u64("quantizer")?,
+                    bcd_range: f32::from_bits(fields.u32("quantizer")?),
+                    bcd_levels: fields.u32("quantizer")? as i64,
+                };
+                if params.a_scale == 0 || params.bcd_levels == 0 || params.bcd_range.is_nan() || params.bcd_range <= 0.0 {
+                    return Err(RpegError::MalformedHeader(format!("invalid quantizer parameters {:?}", params)));
+                }
+                quant = Some(params);
+            }
+            TAG_LAYOUT => {
+                expect_record_len(tag, value, 13)?;
+                let word_bits = fields.u8("layout")? as u64;
+                let mut field = || -> Result<Field, RpegError> {
+                    Ok(Field { width: fields.u8("layout")? as u64, lsb: fields.u8("layout")? as u64 })
+                };
+                let parsed = CodewordLayout {
+                    word_bits,
+                    a: field()?,
+                    b: field()?,
+                    c: field()?,
+                    d: field()?,
+                    pb: field()?,
+                    pr: field()?,
+                };
+                parsed.validate().map_err(|err| RpegError::MalformedHeader(format!("{}", err)))?;
+                layout = Some(parsed);
+            }
+            TAG_COLORSPACE => {
+                expect_record_len(tag, value, 1)?;
+                colorspace = colorspace_from_code(value[0])?;
+            }
+            TAG_SUBSAMPLING => {
+                expect_record_len(tag, value, 1)?;
+                subsampling = subsampling_from_code(value[0])?;
+            }
+            TAG_TRANSFORM => {
+                expect_record_len(tag, value, 1)?;
+                transform = transform_from_code(value[0])?;
+            }
+            TAG_DCT_MATRIX => {
+                expect_record_len(tag, value, 128)?;
+                let mut steps = [0; 64];
+                for step in steps.iter_mut() {
+                    *step = fields.u16("dct matrix")?;
+                }
+                if steps.contains(&0) {
+                    return Err(RpegError::MalformedHeader("dct matrix has a zero step".to_string()));
+                }
+                dct_matrix = Some(QuantMatrix { steps });
+            }
+            TAG_ENTROPY => {
+                expect_record_len(tag, value, 1)?;
+                entropy = entropy_from_code(value[0])?;
+            }
+            TAG_PREDICTION => {
+                expect_record_len(tag, value, 1)?;
+                prediction = prediction_from_code(value[0])?;
+            }
+            TAG_RUN_LENGTH => {
+                expect_record_len(tag, value, 1)?;
+                run_length = match value[0] {
+                    0 => false,
+                    1 => true,
+                    flag => return Err(RpegError::MalformedHeader(format!("invalid run length flag {}", flag))),
+                };
+            }
+            TAG_GRAYSCALE => {
+                expect_record_len(tag, value, 1)?;
+                grayscale = match value[0] {
+                    0 => false,
+                    1 => true,
+                    flag => return Err(RpegError::MalformedHeader(format!("invalid grayscale flag {}", flag))),
+                };
+            }
+            TAG_CHROMA_QUANT => {
+                chroma_quant = parse_chroma_quant(&mut fields)?;
+                if !fields.finished() {
+                    return Err(RpegError::MalformedHeader("chroma quantizer record is too long".to_string()));
+                }
+            }
+            TAG_MAXVAL => {
+                expect_record_len(tag, value, 2)?;
+                maxval = fields.u16("maxval")?;
+                if maxval == 0 {
+                    return Err(RpegError::MalformedHeader("maxval must be at least 1".to_string()));
+                }
+            }
+            _ => {
+                return Err(RpegError::UnsupportedFormat(format!("unknown parameter record {}", tag)));
+            }
+        }
+    }
+
+    let missing = |what: &str| RpegError::MalformedHeader(format!("parameter block has no {} record", what));
+    let (width, height) = dimensions.ok_or_else(|| missing("dimensions"))?;
+    if (transform == BlockTransform::Dct8x8) != dct_matrix.is_some() {
+        return Err(RpegError::MalformedHeader("a dct matrix is required with, and only with, the 8x8 dct".to_string()));
+    }
+    if prediction != DcPrediction::None && entropy == EntropyCoding::Fixed {
+        return Err(RpegError::MalformedHeader("dc prediction needs huffman or range coding".to_string()));
+    }
+    if run_length && (entropy == EntropyCoding::Fixed || transform != BlockTransform::Haar2x2) {
+        return Err(RpegError::MalformedHeader("run-length coding needs the 2x2 transform and huffman or range coding".to_string()));
+    }
+    let layout = layout.ok_or_else(|| missing("layout"))?;
+    if subsampling != Subsampling::Yuv420 && (grayscale || run_length) {
+        return Err(RpegError::MalformedHeader("grayscale images and run-length coding need 4:2:0 subsampling".to_string()));
+    }
+    if grayscale == layout.has_chroma() {
+        return Err(RpegError::MalformedHeader("the layout has pb and pr fields exactly when the image is in color".to_string()));
+    }
+    if grayscale && chroma_quant != ChromaQuantizer::Csc411 {
+        return Err(RpegError::MalformedHeader("grayscale images have no chroma quantizer".to_string()));
+    }
+    if layout.has_chroma() && layout.pb.width.min(layout.pr.width) < chroma_quant.bits() {
+        return Err(RpegError::MalformedHeader(format!("the layout's pb and pr fields cannot hold {}-bit chroma indices", chroma_quant.bits())));
+    }
+    Ok(Header {
+        width,
+        height,
+        quant: quant.ok_or_else(|| missing("quantizer"))?,
+        layout,
+        colorspace,
+        subsampling,
+        transform,
+        dct_matrix,
+        entropy,
+        prediction,
+        run_length,
+        grayscale,
+        chroma_quant,
+        maxval,
+    })
+}
+
+
+// serialize a chroma quantizer other than csc411 into its record
+fn chroma_quant_record(quantizer: &ChromaQuantizer) -> Vec<u8> {
+    match quantizer {
+        ChromaQuantizer::Csc411 => vec![0, 4],
+        ChromaQuantizer::Uniform { bits } => vec![1, *bits as u8],
+        ChromaQuantizer::Adaptive { bits, pb_levels, pr_levels } => {
+            let mut record = vec![2, *bits as u8];
+            for levels in [pb_levels, pr_levels] {
+                record.extend_from_slice(&(levels.len() as u16).to_be_bytes());
+                for level in levels {
+                    record.extend_from_slice(&level.to_bits().to_be_bytes());
+                }
+            }
+            record
+        }
+    }
+}
+
+
+// parse and check a chroma quantizer record
+fn parse_chroma_quant(fields: &mut ByteReader) -> Result<ChromaQuantizer, RpegError> {
+    let kind = fields.u8("chroma quantizer")?;
+    let bits = fields.u8("chroma quantizer")? as u64;
+    let bad = |what: String| RpegError::MalformedHeader(format!("invalid chroma quantizer: {}", what));
+    if kind != 0 && !CHROMA_BITS.contains(&bits) {
+        return Err(bad(format!("{} bits per index", bits)));
+    }
+
+    match kind {
+        0 if bits == 4 => Ok(ChromaQuantizer::Csc411),
+        0 => Err(bad(format!("csc411 indices have 4 bits, not {}", bits))),
+        1 => Ok(ChromaQuantizer::Uniform { bits }),
+        2 => {
+            let mut tables = Vec::new();
+            for _ in 0..2 {
+                let count = fields.u16("chroma quantizer")? as usize;
+                if count == 0 || count > 1 << bits {
+                    return Err(bad(format!("{} levels for {}-bit indices", count, bits)));
+                }
+                let mut levels = Vec::with_capacity(count);
+                for _ in 0..count {
+                    levels.push(f32::from_bits(fields.u32("chroma quantizer")?));
+                }
+                if levels.iter().any(|level| !level.is_finite()) || levels.windows(2).any(|pair| pair[0] > pair[1]) {
+                    return Err(bad("levels must be finite and in increasing order".to_string()));
+                }
+                tables.push(levels);
+            }
+            let pr_levels = tables.pop().unwrap();
+            let pb_levels = tables.pop().unwrap();
+            Ok(ChromaQuantizer::Adaptive { bits, pb_levels, pr_levels })
+        }
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown chroma quantizer {}", kind))),
+    }
+}
+
+
+fn colorspace_code(colorspace: ColorSpace) -> u8 {
+    match colorspace {
+        ColorSpace::Bt601 => 0,
+        ColorSpace::Bt709 => 1,
+        ColorSpace::Bt2020 => 2,
+        ColorSpace::YCoCg => 3,
+    }
+}
+
+fn colorspace_from_code(code: u8) -> Result<ColorSpace, RpegError> {
+    match code {
+        0 => Ok(ColorSpace::Bt601),
+        1 => Ok(ColorSpace::Bt709),
+        2 => Ok(ColorSpace::Bt2020),
+        3 => Ok(ColorSpace::YCoCg),
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown colorspace {}", code))),
+    }
+}
+
+fn subsampling_code(subsampling: Subsampling) -> u8 {
+    match subsampling {
+        Subsampling::Yuv420 => 0,
+        Subsampling::Yuv422 => 1,
+        Subsampling::Yuv444 => 2,
+    }
+}
+
+fn transform_code(transform: BlockTransform) -> u8 {
+    match transform {
+        BlockTransform::Haar2x2 => 0,
+        BlockTransform::Dct8x8 => 1,
+    }
+}
+
+fn transform_from_code(code: u8) -> Result<BlockTransform, RpegError> {
+    match code {
+        0 => Ok(BlockTransform::Haar2x2),
+        1 => Ok(BlockTransform::Dct8x8),
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown block transform {}", code))),
+    }
+}
+
+fn entropy_code(entropy: EntropyCoding) -> u8 {
+    match entropy {
+        EntropyCoding::Fixed => 0,
+        EntropyCoding::Huffman => 1,
+        EntropyCoding::RangeCoder => 2,
+    }
+}
+
+fn entropy_from_code(code: u8) -> Result<EntropyCoding, RpegError> {
+    match code {
+        0 => Ok(EntropyCoding::Fixed),
+        1 => Ok(EntropyCoding::Huffman),
+        2 => Ok(EntropyCoding::RangeCoder),
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown entropy coding {}", code))),
+    }
+}
+
+fn prediction_code(prediction: DcPrediction) -> u8 {
+    match prediction {
+        DcPrediction::None => 0,
+        DcPrediction::Med => 1,
+    }
+}
+
+fn prediction_from_code(code: u8) -> Result<DcPrediction, RpegError> {
+    match code {
+        0 => Ok(DcPrediction::None),
+        1 => Ok(DcPrediction::Med),
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown dc prediction {}", code))),
+    }
+}
+
+fn subsampling_from_code(code: u8) -> Result<Subsampling, RpegError> {
+    match code {
+        0 => Ok(Subsampling::Yuv420),
+        1 => Ok(Subsampling::Yuv422),
+        2 => Ok(Subsampling::Yuv444),
+        _ => Err(RpegError::UnsupportedFormat(format!("unknown chroma subsampling {}", code))),
+    }
+}
+
+
+// read an rpeg v2 container
+fn read_container(bytes: &[u8]) -> Result<(Header, Vec<u8>), RpegError> {
+
+    let mut reader = ByteReader { bytes, pos: MAGIC.len() };
+    let version = reader.u8("header")?;
+    if version != VERSION {
+        return Err(RpegError::UnsupportedFormat(format!("rpeg version {} is not supported", version)));
+    }
+
+    let block_len = reader.u32("header")? as usize;
+    let block = reader.take(block_len, "parameter block")?;
+    let payload_len = reader.u32("header")? as usize;
+    let payload = reader.take(payload_len, "payload")?;
+    let stored_crc = reader.u32("checksum")?;
+    if !reader.finished() {
+        return Err(RpegError::MalformedHeader("unexpected data after the checksum".to_string()));
+    }
+
+    let mut checked = block.to_vec();
+    checked.extend_from_slice(payload);
+    let crc = crc32(&checked);
+    if crc != stored_crc {
+        return Err(RpegError::MalformedHeader(format!(
+            "checksum mismatch (stored {:08x}, computed {:08x})",
+            stored_crc, crc
+        )));
+    }
+
+    Ok((parse_parameter_block(block)?, payload.to_vec()))
+}
+
+
+// read one line of a legacy header, accepting both \n and \r\n endings
+// output: (line without its ending, position of the next line)
+fn read_line(bytes: &[u8], pos: usize) -> Result<(&str, usize), RpegError> {
+    let rest = bytes.get(pos..).unwrap_or(&[]);
+    let end = rest
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| RpegError::MalformedHeader("unexpected end of header".to_string()))?;
+    let line = rest[..end].strip_suffix(b"\r").unwrap_or(&rest[..end]);
+    let line = std::str::from_utf8(line)
+        .map_err(|_| RpegError::MalformedHeader("header is not ascii".to_string()))?;
+    Ok((line, pos + end + 1))
+}
+
+
+// read a legacy "Compressed image format 2" file
+fn read_legacy(bytes: &[u8]) -> Result<(Header, Vec<u8>), RpegError> {
+
+    let (_, pos) = read_line(bytes, 0)?;
+    let (line, pos) = read_line(bytes, pos)?;
+    let dimensions: Vec<usize> = line
+        .split(' ')
+        .map(|part| part.parse())
+        .collect::<Result<_, _>>()
+        .ok()
+        .filter(|fields: &Vec<usize>| fields.len() == 2)
+        .ok_or_else(|| RpegError::MalformedHeader(format!("expected \"width height\", found {:?}", line)))?;
+
+    let header = Header {
+        width: dimensions[0],
+        height: dimensions[1],
+        quant: QuantParams::default(),
+        layout: CodewordLayout::default(),
+        colorspace: ColorSpace::default(),
+        subsampling: Subsampling::default(),
+        transform: BlockTransform::Haar2x2,
+        dct_matrix: None,
+        entropy: EntropyCoding::Fixed,
+        prediction: DcPrediction::None,
+        run_length: false,
+        grayscale: false,
+        chroma_quant: ChromaQuantizer::Csc411,
+        maxval: DEFAULT_MAXVAL,
+    };
+    Ok((header, bytes[pos..].to_vec()))
+}
+
+
+// read the header and payload from rpeg data, in either the v2 or legacy format
+pub fn read_rpeg(bytes: &[u8]) -> Result<(Header, Vec<u8>), RpegError> {
+
+    if bytes.starts_with(MAGIC) {
+        read_container(bytes)
+    } else if bytes.starts_with(LEGACY_MAGIC) {
+        read_legacy(bytes)
+    } else {
+        Err(RpegError::MalformedHeader("input is not a compressed rpeg image".to_string()))
+    }
+}
+
+
+// serialize codewords as big-endian words of the layout's size
+pub fn codewords_to_bytes(codewords: &[u64], layout: &CodewordLayout) -> Vec<u8> {
+    let word_bytes = layout.word_bytes();
+    let mut bytes = Vec::with_capacity(codewords.len() * word_bytes);
+    for codeword in codewords {
+        // keep only the low word_bytes bytes of each codeword
+        bytes.extend_from_slice(&codeword.to_be_bytes()[8 - word_bytes..]);
+    }
+    bytes
+}
+
+
+// parse exactly `count` big-endian codewords of the layout's size
+pub fn bytes_to_codewords(bytes: &[u8], layout: &CodewordLayout, count: usize) -> Result<Vec<u64>, RpegError> {
+    let word_bytes = layout.word_bytes();
+    if bytes.len() != count * word_bytes {
+        return Err(RpegError::DimensionMismatch(format!(
+            "expected {} bytes of codewords, found {}",
+            count * word_bytes,
+            bytes.len()
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(word_bytes)
+        .map(|word| word.iter().fold(0u64, |codeword, &byte| (codeword << 8) | byte as u64))
+        .collect())
+}
+
+
+// whether chroma sample (x, y) is the top-left sample of its 2x2 block, and
+// so the one stored in that block's codeword
+fn in_codeword(x: usize, y: usize, subsampling: Subsampling) -> bool {
+    let (fx, fy) = subsampling.factors();
+    x.is_multiple_of(2 / fx) && y.is_multiple_of(2 / fy)
+}
+
+
+// copy the top-left chroma sample of each 2x2 block into the block's quanta
+pub fn set_block_chroma(quanta: &mut Array2<EncodedQuanta>, chroma: &Array2<EncodedQuanta>, subsampling: Subsampling) {
+    let (fx, fy) = subsampling.factors();
+    for (c, r, sample) in chroma.iter_row_major() {
+        if in_codeword(c, r, subsampling) {
+            let block = quanta.get_mut(c * fx / 2, r * fy / 2).unwrap();
+            block.pb_avg = sample.pb_avg;
+            block.pr_avg = sample.pr_avg;
+        }
+    }
+}
+
+
+// serialize the chroma samples that do not fit in the codewords (none with 4:2:0),
+// with `chroma_bits` bits per index
+pub fn extra_chroma_to_bytes(chroma: &Array2<EncodedQuanta>, subsampling: Subsampling, chroma_bits: u64) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    for (c, r, sample) in chroma.iter_row_major() {
+        if !in_codeword(c, r, subsampling) {
+            writer.write_unsigned(sample.pb_avg, chroma_bits);
+            writer.write_unsigned(sample.pr_avg, chroma_bits);
+        }
+    }
+    writer.finish()
+}
+
+
+// number of cells in a `width` x `height` grid, which header dimensions can make too large to count
+pub fn checked_area(width: usize, height: usize) -> Result<usize, RpegError> {
+    width.checked_mul(height)
+        .ok_or_else(|| RpegError::DimensionMismatch(format!("a {}x{} grid is too large", width, height)))
+}
+
+
+// size of the chroma planes of a `width` x `height` grid of 2x2 blocks
+fn block_chroma_size(width: usize, height: usize, subsampling: Subsampling) -> Result<(usize, usize), RpegError> {
+    let too_large = || RpegError::DimensionMismatch(format!("a {}x{} block grid is too large", width, height));
+    let pixels = (width.checked_mul(2).ok_or_else(too_large)?, height.checked_mul(2).ok_or_else(too_large)?);
+    Ok(subsampling.plane_size(pixels.0, pixels.1))
+}
+
+
+// rebuild the chroma planes from the codeword quanta and the bytes made by extra_chroma_to_bytes
+pub fn bytes_to_chroma(bytes: &[u8], quanta: &Array2<EncodedQuanta>, subsampling: Subsampling, chroma_bits: u64) -> Result<Array2<EncodedQuanta>, RpegError> {
+    let (fx, fy) = subsampling.factors();
+    let (width, height) = block_chroma_size(quanta.width(), quanta.height(), subsampling)?;
+    let mut reader = BitReader::new(bytes);
+
+    let mut chroma = Vec::with_capacity(checked_area(width, height)?);
+    for r in 0..height {
+        for c in 0..width {
+            let (pb_avg, pr_avg) = if in_codeword(c, r, subsampling) {
+                let block = quanta.get(c * fx / 2, r * fy / 2).unwrap();
+                (block.pb_avg, block.pr_avg)
+            } else {
+                (reader.read_unsigned(chroma_bits)?, reader.read_unsigned(chroma_bits)?)
+            };
+            chroma.push(EncodedQuanta { a: 0, b: 0, c: 0, d: 0, pb_avg, pr_avg });
+        }
+    }
+    reader.expect_end()?;
+    Array2::from_row_major(width, height, chroma).map_err(RpegError::DimensionMismatch)
+}
+
+
+// serialize 8x8 dct blocks and the chroma indices of every chroma sample,
+// with `chroma_bits` bits per index (0 leaves chroma out)
+pub fn dct_blocks_to_bytes(blocks: &Array2<DctBlock>, chroma: &Array2<EncodedQuanta>, matrix: &QuantMatrix, chroma_bits: u64) -> Vec<u8> {
+    let widths = matrix.level_widths();
+    let mut writer = BitWriter::new();
+    for block in blocks.get_data() {
+        for (level, width) in block.levels.iter().zip(widths) {
+            writer.write_signed(*level, width);
+        }
+    }
+    for quanta in chroma.get_data() {
+        writer.write_unsigned(quanta.pb_avg, chroma_bits);
+        writer.write_unsigned(quanta.pr_avg, chroma_bits);
+    }
+    writer.finish()
+}
+
+
+// parse the 8x8 dct payload of an image whose padded size is `width` x `height`
+// output: (one DctBlock per 8x8 block, one EncodedQuanta holding pb/pr per chroma sample,
+// all zero if `chroma_bits` is 0)
+pub fn bytes_to_dct_blocks(bytes: &[u8], matrix: &QuantMatrix, width: usize, height: usize, chroma_bits: u64, subsampling: Subsampling) -> Result<(Array2<DctBlock>, Array2<EncodedQuanta>), RpegError> {
+    let widths = matrix.level_widths();
+    let block_count = checked_area(width / 8, height / 8)?;
+    let (chroma_width, chroma_height) = subsampling.plane_size(width, height);
+    let chroma_count = checked_area(chroma_width, chroma_height)?;
+
+    // make sure the payload holds every field before reserving room for them
+    let needed = (block_count as u64).checked_mul(widths.iter().sum())
+        .zip((chroma_count as u64).checked_mul(2 * chroma_bits))
+        .and_then(|(luma, chroma)| luma.checked_add(chroma));
+    if needed.is_none_or(|needed| needed > bytes.len() as u64 * 8) {
+        return Err(RpegError::DimensionMismatch(format!(
+            "a {}x{} image does not fit in {} bytes of payload",
+            width, height, bytes.len()
+        )));
+    }
+    let mut reader = BitReader::new(bytes);
+
+    let mut blocks = Vec::with_capacity(block_count);
+    for _ in 0..block_count {
+        let mut levels = [0; 64];
+        for (level, width) in levels.iter_mut().zip(widths) {
+            *level = reader.read_signed(width)?;
+        }
+        blocks.push(DctBlock { levels });
+    }
+
+    let mut chroma = Vec::with_capacity(chroma_count);
+    for _ in 0..chroma_count {
+        chroma.push(EncodedQuanta {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            pb_avg: reader.read_unsigned(chroma_bits)?,
+            pr_avg: reader.read_unsigned(chroma_bits)?,
+        });
+    }
+    reader.expect_end()?;
+
+    Ok((
+        Array2::from_row_major(width / 8, height / 8, blocks).map_err(RpegError::DimensionMismatch)?,
+        Array2::from_row_major(chroma_width, chroma_height, chroma).map_err(RpegError::DimensionMismatch)?,
+    ))
+}
+
+
+// check that every decoded level is one the encoder could have written, so
+// that dequantizing it cannot overflow
+pub fn check_dct_levels(blocks: &Array2<DctBlock>, matrix: &QuantMatrix) -> Result<(), RpegError> {
+    let max_levels = matrix.max_levels();
+    for (c, r, block) in blocks.iter_row_major() {
+        if block.levels.iter().zip(max_levels).any(|(level, max)| level.unsigned_abs() > max as u64) {
+            return Err(RpegError::MalformedHeader(format!("dct block ({}, {}) has a level out of range", c, r)));
+        }
+    }
+    Ok(())
+}
+
+
+// check that decoded streams hold the number of values the image needs
+fn expect_stream_counts(streams: &[Vec<i64>], expected: &[usize]) -> Result<(), RpegError> {
+    let found: Vec<usize> = streams.iter().map(|stream| stream.len()).collect();
+    if found != expected {
+        return Err(RpegError::DimensionMismatch(format!(
+            "expected streams of {:?} values, found {:?}",
+            expected, found
+        )));
+    }
+    Ok(())
+}
+
+
+// one stream per field of a list of quanta: a, b, c, d, pb, pr
+fn quanta_fields(quanta: &[EncodedQuanta]) -> Vec<Vec<i64>> {
+    let mut streams: Vec<Vec<i64>> = (0..6).map(|_| Vec::with_capacity(quanta.len())).collect();
+    for quanta in quanta {
+        let fields = [quanta.a as i64, quanta.b, quanta.c, quanta.d, quanta.pb_avg as i64, quanta.pr_avg as i64];
+        for (stream, value) in streams.iter_mut().zip(fields) {
+            stream.push(value);
+        }
+    }
+    streams
+}
+
+
+// split encoded quanta and chroma samples into one stream per field: a, b, c, d
+// (one per 2x2 block), pb, pr (one per chroma sample)
+pub fn quanta_to_streams(quanta: &Array2<EncodedQuanta>, chroma: &Array2<EncodedQuanta>) -> Vec<Vec<i64>> {
+    let mut streams = quanta_fields(quanta.get_data());
+    streams.truncate(4);
+    streams.extend(quanta_fields(chroma.get_data()).into_iter().skip(4));
+    streams
+}
+
+
+// number of values in each stream of quanta_to_streams for a `width` x `height` block grid
+pub fn quanta_stream_counts(width: usize, height: usize, subsampling: Subsampling) -> Result<[usize; 6], RpegError> {
+    let blocks = checked_area(width, height)?;
+    let (chroma_width, chroma_height) = block_chroma_size(width, height, subsampling)?;
+    let chroma = checked_area(chroma_width, chroma_height)?;
+    Ok([blocks, blocks, blocks, blocks, chroma, chroma])
+}
+
+
+// reassemble encoded quanta (with the chroma of each block's top-left sample)
+// and the chroma samples from the streams made by quanta_to_streams
+pub fn streams_to_quanta(streams: &[Vec<i64>], width: usize, height: usize, subsampling: Subsampling) -> Result<(Array2<EncodedQuanta>, Array2<EncodedQuanta>), RpegError> {
+    let counts = quanta_stream_counts(width, height, subsampling)?;
+    expect_stream_counts(streams, &counts)?;
+    let quanta = (0..counts[0])
+        .map(|i| EncodedQuanta {
+            a: streams[0][i] as u64,
+            b: streams[1][i],
+            c: streams[2][i],
+            d: streams[3][i],
+            pb_avg: 0,
+            pr_avg: 0,
+        })
+        .collect();
+    let chroma = streams[4]
+        .iter()
+        .zip(streams[5].iter())
+        .map(|(pb, pr)| EncodedQuanta { a: 0, b: 0, c: 0, d: 0, pb_avg: *pb as u64, pr_avg: *pr as u64 })
+        .collect();
+
+    let (chroma_width, chroma_height) = block_chroma_size(width, height, subsampling)?;
+    let mut quanta = Array2::from_row_major(width, height, quanta).map_err(RpegError::DimensionMismatch)?;
+    let chroma = Array2::from_row_major(chroma_width, chroma_height, chroma).map_err(RpegError::DimensionMismatch)?;
+    set_block_chroma(&mut quanta, &chroma, subsampling);
+    Ok((quanta, chroma))
+}
+
+
+// split run-length coded quanta into a, runs, b, c, d, pb and pr streams
+pub fn run_length_to_streams(rle: &RunLengthQuanta) -> Vec<Vec<i64>> {
+    let mut streams = vec![
+        rle.a.iter().map(|a| *a as i64).collect(),
+        rle.runs.iter().map(|run| *run as i64).collect(),
+    ];
+    streams.extend(quanta_fields(&rle.literals).into_iter().skip(1));
+    streams
+}
+
+
+// number of values in each stream of run_length_to_streams
+pub fn run_length_stream_counts(blocks: usize, literals: usize) -> [usize; 7] {
+    [blocks, literals, literals, literals, literals, literals, literals]
+}
+
+
+// reassemble run-length coded quanta from the streams made by run_length_to_streams
+pub fn streams_to_run_length(streams: &[Vec<i64>], blocks: usize, literals: usize) -> Result<RunLengthQuanta, RpegError> {
+    expect_stream_counts(streams, &run_length_stream_counts(blocks, literals))?;
+    Ok(RunLengthQuanta {
+        a: streams[0].iter().map(|a| *a as u64).collect(),
+        runs: streams[1].iter().map(|run| *run as u64).collect(),
+        literals: (0..literals)
+            .map(|i| EncodedQuanta {
+                a: 0,
+                b: streams[2][i],
+                c: streams[3][i],
+                d: streams[4][i],
+                pb_avg: streams[5][i] as u64,
+                pr_avg: streams[6][i] as u64,
+            })
+            .collect(),
+    })
+}
+
+
+// split 8x8 dct blocks and chroma indices into dc, ac, pb and pr streams
+pub fn dct_blocks_to_streams(blocks: &Array2<DctBlock>, chroma: &Array2<EncodedQuanta>) -> Vec<Vec<i64>> {
+    let dc = blocks.get_data().iter().map(|block| block.levels[0]).collect();
+    let ac = blocks.get_data().iter().flat_map(|block| block.levels[1..].to_vec()).collect();
+    let pb = chroma.get_data().iter().map(|quanta| quanta.pb_avg as i64).collect();
+    let pr = chroma.get_data().iter().map(|quanta| quanta.pr_avg as i64).collect();
+    vec![dc, ac, pb, pr]
+}
+
+
+// number of values in each stream of dct_blocks_to_streams for an image
+// whose padded size is `width` x `height`
+pub fn dct_stream_counts(width: usize, height: usize, subsampling: Subsampling) -> Result<[usize; 4], RpegError> {
+    let blocks = checked_area(width / 8, height / 8)?;
+    let (chroma_width, chroma_height) = subsampling.plane_size(width, height);
+    let chroma = checked_area(chroma_width, chroma_height)?;
+    let ac = checked_area(blocks, 63)?;
+    Ok([blocks, ac, chroma, chroma])
+}
+
+
+// reassemble 8x8 dct blocks and chroma indices from the streams made by dct_blocks_to_streams
+pub fn streams_to_dct_blocks(streams: &[Vec<i64>], width: usize, height: usize, subsampling: Subsampling) -> Result<(Array2<DctBlock>, Array2<EncodedQuanta>), RpegError> {
+    expect_stream_counts(streams, &dct_stream_counts(width, height, subsampling)?)?;
+    let blocks = streams[0]
+        .iter()
+        .zip(streams[1].chunks(63))
+        .map(|(dc, ac)| {
+            let mut levels = [0; 64];
+            levels[0] = *dc;
+            levels[1..].copy_from_slice(ac);
+            DctBlock { levels }
+        })
+        .collect();
+    let (chroma_width, chroma_height) = subsampling.plane_size(width, height);
+    let chroma = streams[2]
+        .iter()
+        .zip(streams[3].iter())
+        .map(|(pb, pr)| EncodedQuanta { a: 0, b: 0, c: 0, d: 0, pb_avg: *pb as u64, pr_avg: *pr as u64 })
+        .collect();
+
+    Ok((
+        Array2::from_row_major(width / 8, height / 8, blocks).map_err(RpegError::DimensionMismatch)?,
+        Array2::from_row_major(chroma_width, chroma_height, chroma).map_err(RpegError::DimensionMismatch)?,
+    ))
+}
@@ -0,0 +1,263 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::Reverse;
+use crate::bitio::{BitReader, BitWriter};
+use crate::error::RpegError;
+
+
+/*
+canonical huffman coding of symbol streams
+-------------------------
+each stream gets its own table, built from the frequency of every value
+in it. only the code lengths are stored; codes are assigned canonically
+(shorter codes first, ties broken by increasing symbol), so the decoder
+rebuilds exactly the same codes
+
+a stored table is
+- the number of distinct symbols n, 32 bits
+- the width w of the widest symbol, 7 bits
+- n times: the symbol (zigzag-mapped so small negatives stay small) in
+  w bits, then its code length in 5 bits
+
+a stream with a single distinct symbol still spends one bit per value
+*/
+
+// longest code allowed, so every length fits in the 5 bits of a table entry
+pub const MAX_CODE_LENGTH: u32 = 24;
+
+
+// map signed values onto unsigned ones: 0, -1, 1, -2, 2, ...
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+
+// code lengths for each symbol of a frequency table
+// input: (symbol, count) pairs with nonzero counts, the longest code allowed
+// output: (symbol, length) pairs, no length above max_length
+fn code_lengths(frequencies: &[(i64, u64)], max_length: u32) -> Result<Vec<(i64, u32)>, RpegError> {
+
+    if frequencies.len() == 1 {
+        return Ok(vec![(frequencies[0].0, 1)]);
+    }
+    // flattening below can only reach equal counts, whose codes are
+    // ceil(log2 n) bits long, so more symbols than that can never fit
+    if frequencies.len() as u64 > 1 << max_length {
+        return Err(RpegError::InvalidConfig(format!(
+            "a stream of {} distinct values is too many for {}-bit huffman codes; use range coding",
+            frequencies.len(), max_length
+        )));
+    }
+
+    let mut counts: Vec<u64> = frequencies.iter().map(|(_, count)| *count).collect();
+    loop {
+        // repeatedly merge the two least frequent nodes; each node carries
+        // the indices of the symbols below it, whose codes get one bit longer
+        let mut lengths = vec![0u32; counts.len()];
+        let mut heap: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+        let mut members: Vec<Vec<usize>> = Vec::with_capacity(counts.len() * 2);
+        for (i, count) in counts.iter().enumerate() {
+            heap.push(Reverse((*count, i)));
+            members.push(vec![i]);
+        }
+        while heap.len() > 1 {
+            let Reverse((count_a, a)) = heap.pop().unwrap();
+            let Reverse((count_b, b)) = heap.pop().unwrap();
+            let mut merged = std::mem::take(&mut members[a]);
+            merged.append(&mut members[b]);
+            for &symbol in merged.iter() {
+                lengths[symbol] += 1;
+            }
+            heap.push(Reverse((count_a + count_b, members.len())));
+            members.push(merged);
+        }
+
+        if lengths.iter().all(|&length| length <= max_length) {
+            return Ok(frequencies.iter().zip(lengths).map(|((symbol, _), length)| (*symbol, length)).collect());
+        }
+        // too deep: flatten the distribution and try again
+        for count in counts.iter_mut() {
+            *count = count.div_ceil(2);
+        }
+    }
+}
+
+
+// assign canonical codes to (symbol, length) pairs
+// output: (symbol, length, code) sorted by length then symbol
+fn canonical_codes(lengths: &[(i64, u32)]) -> Vec<(i64, u32, u64)> {
+    let mut sorted = lengths.to_vec();
+    sorted.sort_by_key(|&(symbol, length)| (length, zigzag(symbol)));
+
+    let mut codes = Vec::with_capacity(sorted.len());
+    let mut code = 0u64;
+    let mut previous_length = sorted.first().map_or(0, |&(_, length)| length);
+    for (symbol, length) in sorted {
+        code <<= length - previous_length;
+        codes.push((symbol, length, code));
+        code += 1;
+        previous_length = length;
+    }
+    codes
+}
+
+
+// a canonical huffman code for one stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTable {
+    // (symbol, length, code) sorted by length then symbol
+    codes: Vec<(i64, u32, u64)>,
+    // for each code length, (first code, index of it in `codes`, number of codes)
+    groups: Vec<(u64, usize, usize)>,
+}
+
+impl HuffmanTable {
+
+    fn from_codes(codes: Vec<(i64, u32, u64)>) -> Self {
+        let mut groups = vec![(0, 0, 0); MAX_CODE_LENGTH as usize + 1];
+        for (i, &(_, length, code)) in codes.iter().enumerate().rev() {
+            let group = &mut groups[length as usize];
+            *group = (code, i, group.2 + 1);
+        }
+        HuffmanTable { codes, groups }
+    }
+
+    // build the optimal length-limited table for the values of a stream
+    pub fn from_values(values: &[i64]) -> Result<Self, RpegError> {
+        HuffmanTable::from_values_limited(values, MAX_CODE_LENGTH)
+    }
+
+    // from_values with codes of at most `max_length` (up to MAX_CODE_LENGTH) bits
+    pub(crate) fn from_values_limited(values: &[i64], max_length: u32) -> Result<Self, RpegError> {
+        let mut counts: HashMap<i64, u64> = HashMap::new();
+        for value in values {
+            *counts.entry(*value).or_insert(0) += 1;
+        }
+        let mut frequencies: Vec<(i64, u64)> = counts.into_iter().collect();
+        frequencies.sort_by_key(|&(symbol, _)| zigzag(symbol));
+
+        if frequencies.is_empty() {
+            return Ok(HuffmanTable::from_codes(Vec::new()));
+        }
+        Ok(HuffmanTable::from_codes(canonical_codes(&code_lengths(&frequencies, max_length)?)))
+    }
+
+    // store the symbols and their code lengths
+    pub fn write(&self, writer: &mut BitWriter) {
+        let symbol_width = self.codes.iter()
+            .map(|&(symbol, _, _)| 64 - zigzag(symbol).leading_zeros() as u64)
+            .max()
+            .unwrap_or(0);
+        writer.write_unsigned(self.codes.len() as u64, 32);
+        writer.write_unsigned(symbol_width, 7);
+        for &(symbol, length, _) in self.codes.iter() {
+            // the widest symbols would need a 64-bit write; split them in two
+            let mapped = zigzag(symbol);
+            writer.write_unsigned(mapped >> 32, symbol_width.saturating_sub(32));
+            writer.write_unsigned(mapped & 0xffff_ffff, symbol_width.min(32));
+            writer.write_unsigned(length as u64, 5);
+        }
+    }
+
+    // read a table written by `write`
+    pub fn read(reader: &mut BitReader) -> Result<Self, RpegError> {
+        let malformed = |msg: &str| RpegError::MalformedHeader(format!("huffman table {}", msg));
+
+        let count = reader.read_unsigned(32)? as usize;
+        let symbol_width = reader.read_unsigned(7)?;
+        if symbol_width > 64 {
+            return Err(malformed("has symbols wider than 64 bits"));
+        }
+
+        let mut lengths = Vec::new();
+        for _ in 0..count {
+            let high = reader.read_unsigned(symbol_width.saturating_sub(32))?;
+            let low = reader.read_unsigned(symbol_width.min(32))?;
+            let length = reader.read_unsigned(5)? as u32;
+            if length == 0 || length > MAX_CODE_LENGTH {
+                return Err(malformed("has an invalid code length"));
+            }
+            lengths.push((unzigzag((high << 32) | low), length));
+        }
+
+        // the lengths must describe a complete prefix code (kraft sum of exactly 1),
+        // except for a lone symbol, which gets a 1-bit code
+        let kraft: u64 = lengths.iter().map(|&(_, length)| 1u64 << (MAX_CODE_LENGTH - length)).sum();
+        if count > 1 && kraft != 1 << MAX_CODE_LENGTH {
+            return Err(malformed("does not describe a complete prefix code"));
+        }
+        Ok(HuffmanTable::from_codes(canonical_codes(&lengths)))
+    }
+
+    // lookup from symbol to (code, length) for encoding
+    pub fn encoder(&self) -> HashMap<i64, (u64, u32)> {
+        self.codes.iter().map(|&(symbol, length, code)| (symbol, (code, length))).collect()
+    }
+
+    // read one symbol, one bit at a time; the codes of each length are
+    // consecutive, so a code of that length matches if it falls in its group
+    pub fn decode(&self, reader: &mut BitReader) -> Result<i64, RpegError> {
+        let mut code = 0u64;
+        for (first_code, first_index, count) in self.groups.iter().skip(1) {
+            if self.codes.is_empty() {
+                break;
+            }
+            code = (code << 1) | reader.read_bit()? as u64;
+            if code >= *first_code && code - first_code < *count as u64 {
+                return Ok(self.codes[first_index + (code - first_code) as usize].0);
+            }
+        }
+        Err(RpegError::MalformedHeader("payload contains a code missing from its huffman table".to_string()))
+    }
+}
+
+
+// huffman code several streams: every table first, then the values of
+// each stream in turn
+pub fn encode_streams(streams: &[Vec<i64>]) -> Result<Vec<u8>, RpegError> {
+    let tables = streams.iter().map(|values| HuffmanTable::from_values(values)).collect::<Result<Vec<_>, _>>()?;
+    let mut writer = BitWriter::new();
+    for table in tables.iter() {
+        table.write(&mut writer);
+    }
+    for (table, values) in tables.iter().zip(streams) {
+        let encoder = table.encoder();
+        for value in values {
+            let (code, length) = encoder[value];
+            writer.write_unsigned(code, length as u64);
+        }
+    }
+    Ok(writer.finish())
+}
+
+
+// decode streams written by `encode_streams`, given how many values each holds
+pub fn decode_streams(bytes: &[u8], counts: &[usize]) -> Result<Vec<Vec<i64>>, RpegError> {
+    let mut reader = BitReader::new(bytes);
+    let tables = counts.iter().map(|_| HuffmanTable::read(&mut reader)).collect::<Result<Vec<_>, _>>()?;
+
+    // every value takes at least one bit, so the counts cannot ask for more
+    // values than there are bits left
+    let remaining = bytes.len() as u64 * 8 - reader.bit_pos();
+    let total = counts.iter().try_fold(0u64, |total, &count| total.checked_add(count as u64));
+    if total.is_none_or(|total| total > remaining) {
+        return Err(RpegError::MalformedHeader(format!(
+            "streams of {:?} values do not fit in {} bits of payload",
+            counts, remaining
+        )));
+    }
+
+    let mut streams = Vec::with_capacity(counts.len());
+    for (table, &count) in tables.iter().zip(counts) {
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            values.push(table.decode(&mut reader)?);
+        }
+        streams.push(values);
+    }
+    reader.expect_end()?;
+    Ok(streams)
+}
@@ -0,0 +1,827 @@
+use std::io::Write;
+use array2::Array2;
+use csc411_image::Rgb;
+use crate::bitio::BitWriter;
+use crate::codec::pad_array;
+use crate::compression::{dct_8x8, rgb_float_array_to_crt_array, rgb_int_array_to_rgb_float_array, scale_quant_table, quant_matrix_for_quality};
+use crate::error::RpegError;
+use crate::structures::{dct_basis, Crt, DctBlock};
+
+
+/*
+baseline jfif/jpeg
+-------------------------
+export writes sequential baseline jpeg: 8-bit samples, three components
+(Y, Cb, Cr) with chroma subsampled 4:2:0, one scan, and the example
+huffman tables from annex k of the standard. the conversion to Y/Cb/Cr
+is the same bt.601 matrix the rpeg pipeline uses, with Cb and Cr offset
+by 128, which is exactly what jfif specifies
+
+markers written, in order:
+SOI, APP0 (jfif 1.01), DQT (luma table 0, chroma table 1), SOF0,
+DHT (dc 0, ac 0, dc 1, ac 1), SOS, entropy-coded data, EOI
+
+the entropy-coded data is a sequence of 16x16 minimum coded units, each
+four Y blocks (left to right, top to bottom), then one Cb and one Cr
+block; every block is its dc difference from the previous block of the
+same component, then (run of zeros, size) coded ac coefficients in
+zigzag order. a 0xff byte in the data is followed by a stuffed 0x00
+
+import reads any sequential huffman-coded jpeg with 8-bit samples (SOF0
+or SOF1): one (grayscale) or three (YCbCr) components with any sampling
+factors, interleaved or one scan per component, 8- or 16-bit
+quantization tables, and restart intervals. subsampled chroma is
+replicated back to full size. progressive, arithmetic-coded, lossless
+and hierarchical files are rejected as unsupported rather than decoded
+wrongly
+*/
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const APP0: u8 = 0xE0;
+const DQT: u8 = 0xDB;
+const SOF0: u8 = 0xC0;
+const SOF1: u8 = 0xC1;
+const SOF2: u8 = 0xC2;
+const SOF3: u8 = 0xC3;
+const DHT: u8 = 0xC4;
+const SOF5: u8 = 0xC5;
+const SOF6: u8 = 0xC6;
+const SOF7: u8 = 0xC7;
+const SOF9: u8 = 0xC9;
+const SOF10: u8 = 0xCA;
+const SOF11: u8 = 0xCB;
+const DAC: u8 = 0xCC;
+const SOF13: u8 = 0xCD;
+const SOF14: u8 = 0xCE;
+const SOF15: u8 = 0xCF;
+const RST0: u8 = 0xD0;
+const RST7: u8 = 0xD7;
+const SOS: u8 = 0xDA;
+const DRI: u8 = 0xDD;
+const DHP: u8 = 0xDE;
+const EXP: u8 = 0xDF;
+// standalone marker with no segment
+const TEM: u8 = 0x01;
+
+// chrominance quantization table from annex k, row-major
+const JPEG_CHROMA_STEPS: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+
+// a huffman table as stored in a DHT segment: the number of codes of each
+// length from 1 to 16, then the symbols in code order
+pub struct JpegHuffmanSpec {
+    pub counts: [u8; 16],
+    pub symbols: &'static [u8],
+}
+
+// annex k.3 tables: dc luminance, ac luminance, dc chrominance, ac chrominance
+pub const DC_LUMA: JpegHuffmanSpec = JpegHuffmanSpec {
+    counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+pub const DC_CHROMA: JpegHuffmanSpec = JpegHuffmanSpec {
+    counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+pub const AC_LUMA: JpegHuffmanSpec = JpegHuffmanSpec {
+    counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+    symbols: &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12,
+        0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+        0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08,
+        0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+        0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16,
+        0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+        0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39,
+        0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+        0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59,
+        0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+        0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79,
+        0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+        0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98,
+        0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+        0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+        0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+        0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4,
+        0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+        0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea,
+        0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+        0xf9, 0xfa,
+    ],
+};
+
+pub const AC_CHROMA: JpegHuffmanSpec = JpegHuffmanSpec {
+    counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    symbols: &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21,
+        0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+        0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91,
+        0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+        0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34,
+        0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+        0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38,
+        0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+        0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58,
+        0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+        0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78,
+        0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+        0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96,
+        0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+        0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+        0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+        0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2,
+        0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+        0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9,
+        0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+        0xf9, 0xfa,
+    ],
+};
+
+
+// natural (row-major) index of each coefficient in zigzag order
+pub fn zigzag_order() -> [usize; 64] {
+    let mut order = [0; 64];
+    let mut i = 0;
+    // walk the anti-diagonals, alternating direction
+    for sum in 0..15 {
+        let rows: Vec<usize> = (0..8).filter(|r| sum >= *r && sum - r < 8).collect();
+        let rows: Vec<usize> = if sum % 2 == 0 { rows.into_iter().rev().collect() } else { rows };
+        for r in rows {
+            order[i] = r * 8 + (sum - r);
+            i += 1;
+        }
+    }
+    order
+}
+
+
+// bits needed for the magnitude of a coefficient (its jpeg size category)
+fn size_category(value: i64) -> u64 {
+    64 - value.unsigned_abs().leading_zeros() as u64
+}
+
+
+// codes of a huffman spec, indexed by symbol: (code, length), length 0 if unused
+fn spec_codes(spec: &JpegHuffmanSpec) -> [(u64, u64); 256] {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0u64;
+    let mut symbols = spec.symbols.iter();
+    for (length, count) in spec.counts.iter().enumerate() {
+        for _ in 0..*count {
+            codes[*symbols.next().unwrap() as usize] = (code, length as u64 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+
+// huffman-code one quantized block, updating the dc predictor of its component
+fn encode_block(writer: &mut BitWriter, block: &DctBlock, previous_dc: &mut i64, dc_codes: &[(u64, u64); 256], ac_codes: &[(u64, u64); 256], zigzag: &[usize; 64]) {
+
+    // dc is coded as the difference from the previous block's; negative values
+    // are stored as value - 1 in their size category, i.e. ones' complement
+    let diff = block.levels[0] - *previous_dc;
+    *previous_dc = block.levels[0];
+    let size = size_category(diff);
+    let (code, length) = dc_codes[size as usize];
+    writer.write_unsigned(code, length);
+    writer.write_unsigned(if diff < 0 { (diff - 1) as u64 } else { diff as u64 }, size);
+
+    let mut run = 0;
+    for &index in zigzag.iter().skip(1) {
+        let level = block.levels[index];
+        if level == 0 {
+            run += 1;
+            continue;
+        }
+        // runs longer than 15 zeros are broken up with ZRL (0xf0)
+        while run > 15 {
+            let (code, length) = ac_codes[0xF0];
+            writer.write_unsigned(code, length);
+            run -= 16;
+        }
+        let size = size_category(level);
+        let (code, length) = ac_codes[(run << 4) | size as usize];
+        writer.write_unsigned(code, length);
+        writer.write_unsigned(if level < 0 { (level - 1) as u64 } else { level as u64 }, size);
+        run = 0;
+    }
+    // trailing zeros are covered by an end of block (0x00)
+    if run > 0 {
+        let (code, length) = ac_codes[0x00];
+        writer.write_unsigned(code, length);
+    }
+}
+
+
+// append a marker segment: the marker, a length covering itself and the data, and the data
+fn push_segment(bytes: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    bytes.extend_from_slice(&[0xFF, marker]);
+    bytes.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+
+// a component plane as Y values of a crt array, so dct_8x8 can transform it:
+// chroma in [-0.5, 0.5] is shifted by 128/255 so that dct_8x8's level shift
+// of 128 leaves exactly 255 * chroma, as jfif's offset Cb/Cr would
+fn chroma_plane(crt_array: &Array2<Crt>, chroma: fn(&Crt) -> f32) -> Array2<Crt> {
+
+    // average each 2x2 block of the full-size plane (4:2:0)
+    let width = crt_array.width() / 2;
+    let height = crt_array.height() / 2;
+    let mut plane = Array2::new(width, height, Crt { y: 0.0, pb: 0.0, pr: 0.0 });
+    for r in 0..height {
+        for c in 0..width {
+            let sum: f32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .iter()
+                .map(|(dc, dr)| chroma(crt_array.get(c * 2 + dc, r * 2 + dr).unwrap()))
+                .sum();
+            plane.get_mut(c, r).unwrap().y = sum / 4.0 + 128.0 / 255.0;
+        }
+    }
+    plane
+}
+
+
+// write pixels as a baseline 4:2:0 jpeg
+// input: Array2<Rgb> of 8-bit samples, quality between 1 and 100 scaling the annex k tables
+pub fn write_jpeg<W: Write>(writer: &mut W, pixels: Array2<Rgb>, quality: u8) -> Result<(), RpegError> {
+
+    let (width, height) = (pixels.width(), pixels.height());
+    if width == 0 || height == 0 || width > 65535 || height > 65535 {
+        return Err(RpegError::UnsupportedFormat(format!(
+            "jpeg images must be between 1 and 65535 pixels on each side, not {}x{}",
+            width, height
+        )));
+    }
+    let luma_matrix = quant_matrix_for_quality(quality)?;
+    let chroma_matrix = scale_quant_table(&JPEG_CHROMA_STEPS, quality)?;
+
+    // pad to whole 16x16 mcus and convert to Y/Pb/Pr with the rpeg pipeline
+    let pixels = pad_array(pixels, 16);
+    let crt_array = rgb_float_array_to_crt_array(rgb_int_array_to_rgb_float_array(pixels));
+    let cb_plane = chroma_plane(&crt_array, |crt| crt.pb);
+    let cr_plane = chroma_plane(&crt_array, |crt| crt.pr);
+    let y_blocks = dct_8x8(crt_array, &luma_matrix)?;
+    let cb_blocks = dct_8x8(cb_plane, &chroma_matrix)?;
+    let cr_blocks = dct_8x8(cr_plane, &chroma_matrix)?;
+
+    let mut bytes = vec![0xFF, SOI];
+    push_segment(&mut bytes, APP0, &[b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0]);
+
+    // quantization tables are stored in zigzag order
+    let zigzag = zigzag_order();
+    let mut dqt = Vec::with_capacity(130);
+    for (id, matrix) in [&luma_matrix, &chroma_matrix].iter().enumerate() {
+        dqt.push(id as u8);
+        dqt.extend(zigzag.iter().map(|&i| matrix.steps[i] as u8));
+    }
+    push_segment(&mut bytes, DQT, &dqt);
+
+    // frame: precision, dimensions, then id, sampling factors and table of each component
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.extend_from_slice(&[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+    push_segment(&mut bytes, SOF0, &sof);
+
+    let mut dht = Vec::new();
+    for (class_and_id, spec) in [(0x00, &DC_LUMA), (0x10, &AC_LUMA), (0x01, &DC_CHROMA), (0x11, &AC_CHROMA)] {
+        dht.push(class_and_id);
+        dht.extend_from_slice(&spec.counts);
+        dht.extend_from_slice(spec.symbols);
+    }
+    push_segment(&mut bytes, DHT, &dht);
+
+    // scan: every component, with the dc and ac tables it uses, full spectral range
+    push_segment(&mut bytes, SOS, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+
+    let luma_codes = (spec_codes(&DC_LUMA), spec_codes(&AC_LUMA));
+    let chroma_codes = (spec_codes(&DC_CHROMA), spec_codes(&AC_CHROMA));
+    let mut scan = BitWriter::new();
+    let mut previous_dc = [0i64; 3];
+    for mcu_r in 0..cb_blocks.height() {
+        for mcu_c in 0..cb_blocks.width() {
+            for (dc, dr) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let block = y_blocks.get(mcu_c * 2 + dc, mcu_r * 2 + dr).unwrap();
+                encode_block(&mut scan, block, &mut previous_dc[0], &luma_codes.0, &luma_codes.1, &zigzag);
+            }
+            encode_block(&mut scan, cb_blocks.get(mcu_c, mcu_r).unwrap(), &mut previous_dc[1], &chroma_codes.0, &chroma_codes.1, &zigzag);
+            encode_block(&mut scan, cr_blocks.get(mcu_c, mcu_r).unwrap(), &mut previous_dc[2], &chroma_codes.0, &chroma_codes.1, &zigzag);
+        }
+    }
+    // the last byte is padded with one bits
+    let padding = (8 - scan.bit_len() % 8) % 8;
+    scan.write_unsigned((1 << padding) - 1, padding);
+    for byte in scan.finish() {
+        bytes.push(byte);
+        if byte == 0xFF {
+            bytes.push(0x00);
+        }
+    }
+
+    bytes.extend_from_slice(&[0xFF, EOI]);
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+
+// a huffman table read from a DHT segment, in the form of annex f.2.2.3:
+// for each code length, the smallest and largest code and where its symbols start
+struct JpegHuffmanTable {
+    min_code: [i32; 17],
+    max_code: [i32; 17],
+    first_symbol: [usize; 17],
+    symbols: Vec<u8>,
+}
+
+impl JpegHuffmanTable {
+
+    fn new(counts: &[u8], symbols: &[u8]) -> Result<Self, RpegError> {
+        let mut table = JpegHuffmanTable { min_code: [0; 17], max_code: [-1; 17], first_symbol: [0; 17], symbols: symbols.to_vec() };
+        let mut code = 0i32;
+        let mut index = 0;
+        for (length, &count) in counts.iter().enumerate().map(|(i, count)| (i + 1, count)) {
+            if count > 0 {
+                table.min_code[length] = code;
+                table.first_symbol[length] = index;
+                code += count as i32;
+                index += count as usize;
+                table.max_code[length] = code - 1;
+            }
+            if code > 1 << length {
+                return Err(RpegError::MalformedHeader("jpeg huffman table has too many codes".to_string()));
+            }
+            code <<= 1;
+        }
+        Ok(table)
+    }
+
+    // read one symbol, one bit at a time
+    fn decode(&self, reader: &mut ScanReader) -> Result<u8, RpegError> {
+        let mut code = 0i32;
+        for length in 1..17 {
+            code = (code << 1) | reader.read_bit()? as i32;
+            if code <= self.max_code[length] {
+                return Ok(self.symbols[self.first_symbol[length] + (code - self.min_code[length]) as usize]);
+            }
+        }
+        Err(RpegError::MalformedHeader("jpeg scan contains a code missing from its huffman table".to_string()))
+    }
+}
+
+
+// reads the bits of entropy-coded data, dropping the 0x00 stuffed after each 0xff
+struct ScanReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    byte: u8,
+    bits_left: u32,
+}
+
+impl<'a> ScanReader<'a> {
+
+    fn read_bit(&mut self) -> Result<bool, RpegError> {
+        if self.bits_left == 0 {
+            let ended = || RpegError::DimensionMismatch("jpeg scan data ended early".to_string());
+            self.byte = *self.bytes.get(self.pos).ok_or_else(ended)?;
+            if self.byte == 0xFF {
+                // anything but a stuffed zero is a marker, which ends the data
+                if self.bytes.get(self.pos + 1) != Some(&0) {
+                    return Err(ended());
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            self.bits_left = 8;
+        }
+        self.bits_left -= 1;
+        Ok((self.byte >> self.bits_left) & 1 == 1)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<i64, RpegError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as i64;
+        }
+        Ok(value)
+    }
+
+    // read a coefficient of the given size category, undoing the ones' complement of negatives
+    fn read_extended(&mut self, size: u8) -> Result<i64, RpegError> {
+        if size > 15 {
+            return Err(RpegError::MalformedHeader(format!("jpeg coefficient of size {} is too large", size)));
+        }
+        let value = self.read_bits(size)?;
+        if size > 0 && value < 1 << (size - 1) {
+            Ok(value - (1 << size) + 1)
+        } else {
+            Ok(value)
+        }
+    }
+
+    // skip the rest of the current byte and the RSTn marker that must follow it
+    fn restart(&mut self) -> Result<(), RpegError> {
+        self.bits_left = 0;
+        match self.bytes.get(self.pos..self.pos + 2) {
+            Some([0xFF, marker]) if (RST0..RST0 + 8).contains(marker) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(RpegError::MalformedHeader("expected a restart marker in jpeg scan".to_string())),
+        }
+    }
+}
+
+
+// one component of a frame, with its decoded samples
+struct JpegComponent {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant_table: usize,
+    dc_table: usize,
+    ac_table: usize,
+    previous_dc: i64,
+    // samples of whole blocks, covering every mcu of the frame
+    samples: Array2<u8>,
+}
+
+
+// a frame (SOF) with everything needed to decode its scans
+struct JpegFrame {
+    width: usize,
+    height: usize,
+    max_h: usize,
+    max_v: usize,
+    mcus_across: usize,
+    mcus_down: usize,
+    components: Vec<JpegComponent>,
+}
+
+
+// read the segment after a marker: its length covers itself and the data
+fn segment_data(bytes: &[u8], pos: usize) -> Result<&[u8], RpegError> {
+    let truncated = || RpegError::MalformedHeader("jpeg segment runs past the end of the file".to_string());
+    let length = u16::from_be_bytes(bytes.get(pos..pos + 2).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    if length < 2 {
+        return Err(RpegError::MalformedHeader("jpeg segment length is too short".to_string()));
+    }
+    bytes.get(pos + 2..pos + length).ok_or_else(truncated)
+}
+
+
+// read quantization tables into natural order; 16-bit steps are allowed
+fn read_dqt(data: &[u8], tables: &mut [Option<[u16; 64]>; 4]) -> Result<(), RpegError> {
+    let zigzag = zigzag_order();
+    let mut data = data;
+    while let Some(&info) = data.first() {
+        let (wide, id) = (info >> 4, (info & 15) as usize);
+        let size = if wide == 1 { 128 } else { 64 };
+        if wide > 1 || id > 3 || data.len() < 1 + size {
+            return Err(RpegError::MalformedHeader("invalid jpeg quantization table".to_string()));
+        }
+        let mut steps = [0u16; 64];
+        for (k, &index) in zigzag.iter().enumerate() {
+            steps[index] = if wide == 1 {
+                u16::from_be_bytes([data[1 + 2 * k], data[2 + 2 * k]])
+            } else {
+                data[1 + k] as u16
+            };
+        }
+        tables[id] = Some(steps);
+        data = &data[1 + size..];
+    }
+    Ok(())
+}
+
+
+// read huffman tables; dc tables go in the first four slots, ac tables in the last four
+fn read_dht(data: &[u8], tables: &mut [Option<JpegHuffmanTable>; 8]) -> Result<(), RpegError> {
+    let mut data = data;
+    while let Some(&info) = data.first() {
+        let (class, id) = (info >> 4, (info & 15) as usize);
+        if class > 1 || id > 3 || data.len() < 17 {
+            return Err(RpegError::MalformedHeader("invalid jpeg huffman table".to_string()));
+        }
+        let counts = &data[1..17];
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        let symbols = data.get(17..17 + total)
+            .ok_or_else(|| RpegError::MalformedHeader("jpeg huffman table runs past its segment".to_string()))?;
+        tables[class as usize * 4 + id] = Some(JpegHuffmanTable::new(counts, symbols)?);
+        data = &data[17 + total..];
+    }
+    Ok(())
+}
+
+
+// read a baseline or extended sequential frame header
+fn read_sof(data: &[u8]) -> Result<JpegFrame, RpegError> {
+    let malformed = |msg: &str| RpegError::MalformedHeader(format!("jpeg frame header {}", msg));
+
+    if data.len() < 6 {
+        return Err(malformed("is too short"));
+    }
+    if data[0] != 8 {
+        return Err(RpegError::UnsupportedFormat(format!("{}-bit jpeg samples (only 8-bit is supported)", data[0])));
+    }
+    let height = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let width = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if width == 0 || height == 0 {
+        return Err(RpegError::UnsupportedFormat("jpeg without dimensions in its frame header".to_string()));
+    }
+    let count = data[5] as usize;
+    if count != 1 && count != 3 {
+        return Err(RpegError::UnsupportedFormat(format!("jpeg with {} components (only grayscale and YCbCr are supported)", count)));
+    }
+    if data.len() != 6 + 3 * count {
+        return Err(malformed("has the wrong length"));
+    }
+
+    let mut components = Vec::with_capacity(count);
+    for spec in data[6..].chunks_exact(3) {
+        let (h, v) = ((spec[1] >> 4) as usize, (spec[1] & 15) as usize);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) || spec[2] > 3 {
+            return Err(malformed("has an invalid component"));
+        }
+        components.push(JpegComponent {
+            id: spec[0],
+            h,
+            v,
+            quant_table: spec[2] as usize,
+            dc_table: 0,
+            ac_table: 0,
+            previous_dc: 0,
+            samples: Array2::new(0, 0, 0),
+        });
+    }
+
+    // every component covers a whole number of mcus
+    let max_h = components.iter().map(|component| component.h).max().unwrap();
+    let max_v = components.iter().map(|component| component.v).max().unwrap();
+    let mcus_across = width.div_ceil(8 * max_h);
+    let mcus_down = height.div_ceil(8 * max_v);
+    for component in components.iter_mut() {
+        component.samples = Array2::new(mcus_across * component.h * 8, mcus_down * component.v * 8, 0);
+    }
+    Ok(JpegFrame { width, height, max_h, max_v, mcus_across, mcus_down, components })
+}
+
+
+// decode the quantized levels of one block, in natural order
+fn decode_levels(reader: &mut ScanReader, previous_dc: &mut i64, dc: &JpegHuffmanTable, ac: &JpegHuffmanTable, zigzag: &[usize; 64]) -> Result<[i64; 64], RpegError> {
+
+    let mut levels = [0i64; 64];
+    let size = dc.decode(reader)?;
+    *previous_dc += reader.read_extended(size)?;
+    levels[0] = *previous_dc;
+
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(reader)?;
+        let (run, size) = ((symbol >> 4) as usize, symbol & 15);
+        if size == 0 {
+            // end of block, or a run of sixteen zeros (ZRL)
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > 63 {
+            return Err(RpegError::MalformedHeader("jpeg block has more than 64 coefficients".to_string()));
+        }
+        levels[zigzag[k]] = reader.read_extended(size)?;
+        k += 1;
+    }
+    Ok(levels)
+}
+
+
+// dequantize a block and write its samples at block (block_c, block_r) of a plane
+fn write_block(samples: &mut Array2<u8>, levels: &[i64; 64], steps: &[u16; 64], basis: &[[f64; 8]; 8], block_c: usize, block_r: usize) {
+
+    // separable inverse dct: columns of each row of frequencies, then rows
+    let mut rows = [[0.0f64; 8]; 8];
+    for (v, row) in rows.iter_mut().enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = (0..8).map(|u| basis[u][x] * (levels[v * 8 + u] * steps[v * 8 + u] as i64) as f64).sum();
+        }
+    }
+    for i in 0..64 {
+        let (x, y) = (i % 8, i / 8);
+        let value: f64 = (0..8).map(|v| basis[v][y] * rows[v][x]).sum();
+        *samples.get_mut(block_c * 8 + x, block_r * 8 + y).unwrap() = (value + 128.0).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+
+// decode the entropy-coded data of one scan, starting at `pos`
+// output: the position just after the data
+fn read_scan(bytes: &[u8], pos: usize, header: &[u8], frame: &mut JpegFrame, quant_tables: &[Option<[u16; 64]>; 4], huffman_tables: &[Option<JpegHuffmanTable>; 8], restart_interval: usize) -> Result<usize, RpegError> {
+    let malformed = |msg: &str| RpegError::MalformedHeader(format!("jpeg scan {}", msg));
+
+    // which components are in the scan, and the tables each uses
+    let count = *header.first().ok_or_else(|| malformed("header is empty"))? as usize;
+    if count == 0 || header.len() != 4 + 2 * count {
+        return Err(malformed("header has the wrong length"));
+    }
+    let mut members = Vec::with_capacity(count);
+    for spec in header[1..1 + 2 * count].chunks_exact(2) {
+        let index = frame.components.iter().position(|component| component.id == spec[0])
+            .ok_or_else(|| malformed("names a component missing from the frame"))?;
+        let component = &mut frame.components[index];
+        component.dc_table = (spec[1] >> 4) as usize;
+        component.ac_table = 4 + (spec[1] & 15) as usize;
+        component.previous_dc = 0;
+        if component.dc_table > 3 || component.ac_table > 7 {
+            return Err(malformed("uses an invalid huffman table"));
+        }
+        members.push(index);
+    }
+    // sequential scans always cover the whole spectrum in one pass
+    if header[1 + 2 * count..] != [0, 63, 0] {
+        return Err(RpegError::UnsupportedFormat("jpeg scan with spectral selection or successive approximation".to_string()));
+    }
+
+    // the units of the scan: whole mcus when interleaved, otherwise the single
+    // component's blocks that fall inside the image
+    let units: Vec<Vec<(usize, usize, usize)>> = if count == 1 {
+        let index = members[0];
+        let component = &frame.components[index];
+        let across = (frame.width * component.h).div_ceil(frame.max_h).div_ceil(8);
+        let down = (frame.height * component.v).div_ceil(frame.max_v).div_ceil(8);
+        (0..down).flat_map(|r| (0..across).map(move |c| vec![(index, c, r)])).collect()
+    } else {
+        let mut units = Vec::with_capacity(frame.mcus_across * frame.mcus_down);
+        for mcu_r in 0..frame.mcus_down {
+            for mcu_c in 0..frame.mcus_across {
+                let mut unit = Vec::new();
+                for &index in members.iter() {
+                    let component = &frame.components[index];
+                    for r in 0..component.v {
+                        for c in 0..component.h {
+                            unit.push((index, mcu_c * component.h + c, mcu_r * component.v + r));
+                        }
+                    }
+                }
+                units.push(unit);
+            }
+        }
+        units
+    };
+
+    let zigzag = zigzag_order();
+    let basis = dct_basis();
+    let mut reader = ScanReader { bytes, pos, byte: 0, bits_left: 0 };
+    for (i, unit) in units.iter().enumerate() {
+        if restart_interval > 0 && i > 0 && i % restart_interval == 0 {
+            reader.restart()?;
+            for &index in members.iter() {
+                frame.components[index].previous_dc = 0;
+            }
+        }
+        for &(index, block_c, block_r) in unit {
+            let component = &mut frame.components[index];
+            let steps = quant_tables[component.quant_table].as_ref().ok_or_else(|| malformed("uses a missing quantization table"))?;
+            let dc = huffman_tables[component.dc_table].as_ref().ok_or_else(|| malformed("uses a missing huffman table"))?;
+            let ac = huffman_tables[component.ac_table].as_ref().ok_or_else(|| malformed("uses a missing huffman table"))?;
+            let levels = decode_levels(&mut reader, &mut component.previous_dc, dc, ac, &zigzag)?;
+            write_block(&mut component.samples, &levels, steps, &basis, block_c, block_r);
+        }
+    }
+    Ok(reader.pos)
+}
+
+
+// convert the decoded planes to rgb, replicating subsampled chroma
+fn frame_to_pixels(frame: &JpegFrame) -> Array2<Rgb> {
+    let mut pixels = Array2::new(frame.width, frame.height, Rgb { red: 0, green: 0, blue: 0 });
+    let sample = |component: &JpegComponent, c: usize, r: usize| {
+        *component.samples.get(c * component.h / frame.max_h, r * component.v / frame.max_v).unwrap() as f64
+    };
+    let to_int = |value: f64| value.round().clamp(0.0, 255.0) as u16;
+    for r in 0..frame.height {
+        for c in 0..frame.width {
+            let y = sample(&frame.components[0], c, r);
+            *pixels.get_mut(c, r).unwrap() = if frame.components.len() == 1 {
+                Rgb { red: y as u16, green: y as u16, blue: y as u16 }
+            } else {
+                // jfif's Cb/Cr are offset by 128
+                let cb = sample(&frame.components[1], c, r) - 128.0;
+                let cr = sample(&frame.components[2], c, r) - 128.0;
+                Rgb {
+                    red: to_int(y + 1.402 * cr),
+                    green: to_int(y - 0.344136 * cb - 0.714136 * cr),
+                    blue: to_int(y + 1.772 * cb),
+                }
+            };
+        }
+    }
+    pixels
+}
+
+
+// whether data starts like a jpeg file
+pub fn is_jpeg(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0xFF, SOI])
+}
+
+
+// read a baseline (or extended sequential, 8-bit) huffman-coded jpeg
+// output: Array2<Rgb> of 8-bit samples, grayscale images with equal channels
+pub fn read_jpeg(bytes: &[u8]) -> Result<Array2<Rgb>, RpegError> {
+
+    if !is_jpeg(bytes) {
+        return Err(RpegError::UnsupportedFormat("input is not a jpeg image".to_string()));
+    }
+
+    let mut quant_tables: [Option<[u16; 64]>; 4] = [None; 4];
+    let mut huffman_tables: [Option<JpegHuffmanTable>; 8] = Default::default();
+    let mut frame: Option<JpegFrame> = None;
+    let mut restart_interval = 0;
+    let mut scans = 0;
+
+    let mut pos = 2;
+    loop {
+        // markers may be preceded by any number of 0xff fill bytes
+        if bytes.get(pos) != Some(&0xFF) {
+            return Err(RpegError::MalformedHeader(format!("expected a jpeg marker at byte {}", pos)));
+        }
+        while bytes.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos)
+            .ok_or_else(|| RpegError::MalformedHeader("jpeg ends without an EOI marker".to_string()))?;
+        pos += 1;
+
+        match marker {
+            EOI => break,
+            TEM | RST0..=RST7 => continue,
+            SOF0 | SOF1 => {
+                if frame.is_some() {
+                    return Err(RpegError::MalformedHeader("jpeg has more than one frame".to_string()));
+                }
+                frame = Some(read_sof(segment_data(bytes, pos)?)?);
+            }
+            SOF2 | SOF6 | SOF10 | SOF14 => {
+                return Err(RpegError::UnsupportedFormat("progressive jpeg (only baseline sequential is supported)".to_string()));
+            }
+            SOF9 | DAC | SOF13 => {
+                return Err(RpegError::UnsupportedFormat("arithmetic-coded jpeg (only huffman coding is supported)".to_string()));
+            }
+            SOF3 | SOF5 | SOF7 | SOF11 | SOF15 | DHP | EXP => {
+                return Err(RpegError::UnsupportedFormat("lossless or hierarchical jpeg (only baseline sequential is supported)".to_string()));
+            }
+            DQT => read_dqt(segment_data(bytes, pos)?, &mut quant_tables)?,
+            DHT => read_dht(segment_data(bytes, pos)?, &mut huffman_tables)?,
+            DRI => {
+                let data = segment_data(bytes, pos)?;
+                if data.len() != 2 {
+                    return Err(RpegError::MalformedHeader("jpeg restart interval has the wrong length".to_string()));
+                }
+                restart_interval = u16::from_be_bytes([data[0], data[1]]) as usize;
+            }
+            SOS => {
+                let frame = frame.as_mut()
+                    .ok_or_else(|| RpegError::MalformedHeader("jpeg scan comes before its frame header".to_string()))?;
+                let header = segment_data(bytes, pos)?;
+                let start = pos + 2 + header.len();
+                pos = read_scan(bytes, start, header, frame, &quant_tables, &huffman_tables, restart_interval)?;
+                scans += 1;
+                // the data may end with restart markers or padding before the next marker
+                while pos < bytes.len() {
+                    if bytes[pos] == 0xFF && !matches!(bytes.get(pos + 1), Some(0x00) | Some(RST0..=RST7)) {
+                        break;
+                    }
+                    pos += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        // skip the segment (for app, comment and other markers, its contents are ignored)
+        pos += 2 + segment_data(bytes, pos)?.len();
+    }
+
+    match frame {
+        Some(frame) if scans > 0 => Ok(frame_to_pixels(&frame)),
+        _ => Err(RpegError::MalformedHeader("jpeg has no image data".to_string())),
+    }
+}
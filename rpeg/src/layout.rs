@@ -0,0 +1,237 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::error::RpegError;
+
+
+/*
+codeword layout
+-------------------------
+describes where each field of an encoded 2x2 block lives inside a
+codeword. the default is the original 32-bit layout:
+
+bits   31..23  22..18  17..13  12..8  7..4    3..0
+field  a       b       c       d      pb_avg  pr_avg
+
+written (and parsed) as "32 a:9@23 b:5@18 c:5@13 d:5@8 pb:4@4 pr:4@0",
+i.e. the word size in bits followed by name:width@lsb for each field.
+
+grayscale images carry no chroma, so their layouts give pb and pr a width
+of zero. the luma-only default packs a/b/c/d into 24 bits:
+
+bits   23..15  14..10  9..5  4..0
+field  a       b       c     d
+
+written as "24 a:9@15 b:5@10 c:5@5 d:5@0 pb:0@0 pr:0@0"
+*/
+
+
+// one bit field of a codeword
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub width: u64,
+    pub lsb: u64,
+}
+
+
+// width and position of every field in a codeword
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodewordLayout {
+    pub word_bits: u64,
+    pub a: Field,
+    pub b: Field,
+    pub c: Field,
+    pub d: Field,
+    pub pb: Field,
+    pub pr: Field,
+}
+
+const FIELD_NAMES: [&str; 6] = ["a", "b", "c", "d", "pb", "pr"];
+
+
+impl Default for CodewordLayout {
+    fn default() -> Self {
+        CodewordLayout {
+            word_bits: 32,
+            a: Field { width: 9, lsb: 23 },
+            b: Field { width: 5, lsb: 18 },
+            c: Field { width: 5, lsb: 13 },
+            d: Field { width: 5, lsb: 8 },
+            pb: Field { width: 4, lsb: 4 },
+            pr: Field { width: 4, lsb: 0 },
+        }
+    }
+}
+
+
+impl CodewordLayout {
+
+    // the default layout for grayscale images, with no chroma fields
+    pub fn luma_only() -> Self {
+        CodewordLayout {
+            word_bits: 24,
+            a: Field { width: 9, lsb: 15 },
+            b: Field { width: 5, lsb: 10 },
+            c: Field { width: 5, lsb: 5 },
+            d: Field { width: 5, lsb: 0 },
+            pb: Field { width: 0, lsb: 0 },
+            pr: Field { width: 0, lsb: 0 },
+        }
+    }
+
+    // the default layout with `bits`-wide pb/pr fields, rounded up to whole bytes
+    // (the default layout itself for 4 bits)
+    pub fn with_chroma_bits(bits: u64) -> Self {
+        let chroma = 2 * bits;
+        CodewordLayout {
+            word_bits: (chroma + 24).div_ceil(8) * 8,
+            a: Field { width: 9, lsb: chroma + 15 },
+            b: Field { width: 5, lsb: chroma + 10 },
+            c: Field { width: 5, lsb: chroma + 5 },
+            d: Field { width: 5, lsb: chroma },
+            pb: Field { width: bits, lsb: bits },
+            pr: Field { width: bits, lsb: 0 },
+        }
+    }
+
+    // whether codewords carry the pb/pr chroma fields
+    pub fn has_chroma(&self) -> bool {
+        self.pb.width > 0
+    }
+
+    // every field paired with its name, in codeword order
+    pub fn fields(&self) -> [(&'static str, Field); 6] {
+        [
+            (FIELD_NAMES[0], self.a),
+            (FIELD_NAMES[1], self.b),
+            (FIELD_NAMES[2], self.c),
+            (FIELD_NAMES[3], self.d),
+            (FIELD_NAMES[4], self.pb),
+            (FIELD_NAMES[5], self.pr),
+        ]
+    }
+
+    // number of bytes each codeword occupies in a file
+    pub fn word_bytes(&self) -> usize {
+        (self.word_bits / 8) as usize
+    }
+
+    // largest value the unsigned a field can hold
+    pub fn max_a(&self) -> u64 {
+        if self.a.width >= 64 { u64::MAX } else { (1 << self.a.width) - 1 }
+    }
+
+    // largest magnitude every one of the signed b, c and d fields can hold
+    pub fn max_bcd_level(&self) -> i64 {
+        let width = self.b.width.min(self.c.width).min(self.d.width);
+        if width >= 64 { i64::MAX } else { (1 << (width - 1)) - 1 }
+    }
+
+    // check that the word size is usable and the fields fit without overlapping
+    pub fn validate(&self) -> Result<(), RpegError> {
+
+        if self.word_bits == 0 || self.word_bits > 64 || !self.word_bits.is_multiple_of(8) {
+            return Err(RpegError::InvalidConfig(format!(
+                "codeword size must be a multiple of 8 bits between 8 and 64, got {}",
+                self.word_bits
+            )));
+        }
+
+        // chroma is either present in both fields or absent from both
+        if (self.pb.width == 0) != (self.pr.width == 0) {
+            return Err(RpegError::InvalidConfig(
+                "fields pb and pr must both be present or both have width 0".to_string(),
+            ));
+        }
+
+        // a needs at least one bit, b/c/d need a sign bit and one magnitude
+        // bit, and pb/pr must hold a 4-bit chroma index unless they are absent
+        let minimum_widths = [1, 2, 2, 2, 4, 4];
+        for ((name, field), minimum) in self.fields().iter().zip(minimum_widths) {
+            let minimum = if field.width == 0 && !self.has_chroma() { 0 } else { minimum };
+            if field.width < minimum {
+                return Err(RpegError::InvalidConfig(format!(
+                    "field {} needs at least {} bits, got {}",
+                    name, minimum, field.width
+                )));
+            }
+            if field.lsb + field.width > self.word_bits {
+                return Err(RpegError::InvalidConfig(format!(
+                    "field {} ({} bits at lsb {}) does not fit in a {}-bit codeword",
+                    name, field.width, field.lsb, self.word_bits
+                )));
+            }
+        }
+
+        // no two fields may share a bit
+        let fields = self.fields();
+        for (i, (name, field)) in fields.iter().enumerate() {
+            for (other_name, other) in fields.iter().skip(i + 1) {
+                if field.lsb < other.lsb + other.width && other.lsb < field.lsb + field.width {
+                    return Err(RpegError::InvalidConfig(format!(
+                        "fields {} and {} overlap",
+                        name, other_name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for CodewordLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.word_bits)?;
+        for (name, field) in self.fields() {
+            write!(f, " {}:{}@{}", name, field.width, field.lsb)?;
+        }
+        Ok(())
+    }
+}
+
+
+impl FromStr for CodewordLayout {
+    type Err = RpegError;
+
+    // parse and validate a layout written in the format produced by Display
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || RpegError::InvalidConfig(format!(
+            "expected a layout like \"{}\", found {:?}",
+            CodewordLayout::default(),
+            text
+        ));
+
+        let mut parts = text.split_whitespace();
+        let word_bits = parts.next().and_then(|part| part.parse().ok()).ok_or_else(bad)?;
+
+        let mut fields = [Field { width: 0, lsb: 0 }; 6];
+        for (name, field) in FIELD_NAMES.iter().zip(fields.iter_mut()) {
+            let part = parts.next().ok_or_else(bad)?;
+            let (part_name, spec) = part.split_once(':').ok_or_else(bad)?;
+            let (width, lsb) = spec.split_once('@').ok_or_else(bad)?;
+            if part_name != *name {
+                return Err(bad());
+            }
+            *field = Field {
+                width: width.parse().map_err(|_| bad())?,
+                lsb: lsb.parse().map_err(|_| bad())?,
+            };
+        }
+        if parts.next().is_some() {
+            return Err(bad());
+        }
+
+        let layout = CodewordLayout {
+            word_bits,
+            a: fields[0],
+            b: fields[1],
+            c: fields[2],
+            d: fields[3],
+            pb: fields[4],
+            pr: fields[5],
+        };
+        layout.validate()?;
+        Ok(layout)
+    }
+}
@@ -1,7 +1,19 @@
+pub mod bitio;
+pub mod checksum;
 pub mod codec;
 pub mod structures;
 pub mod compression;
 pub mod decompression;
+pub mod deflate;
+pub mod error;
+pub mod format;
+pub mod huffman;
+pub mod jpeg;
+pub mod layout;
+pub mod metrics;
+pub mod png;
+pub mod pnm;
+pub mod rangecoder;
 
 // tests
 #[cfg(test)]
@@ -14,6 +26,8 @@ mod tests {
     use crate::decompression::*;
     use crate::codec::*;
 
+    // kept as written before the crate was linted
+    #[allow(clippy::needless_option_as_deref, clippy::unnecessary_cast, clippy::redundant_field_names)]
     #[test]
     fn up_to_dct() {
 
@@ -85,6 +99,8 @@ mod tests {
     }
 
     // round-trip testing for first lossy operation
+    // kept as written before the crate was linted
+    #[allow(clippy::needless_option_as_deref, clippy::unnecessary_cast, clippy::redundant_field_names)]
     #[test]
     fn up_to_averages() {
 
@@ -177,6 +193,1204 @@ mod tests {
         img.write(Some("test.ppm")).unwrap();
 
     }
+
+    // build a binary ppm with a smooth gradient, for in-memory round trips
+    fn gradient_ppm(width: usize, height: usize) -> Vec<u8> {
+        let mut bytes = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for r in 0..height {
+            for c in 0..width {
+                bytes.push((c * 255 / width) as u8);
+                bytes.push((r * 255 / height) as u8);
+                bytes.push(128);
+            }
+        }
+        bytes
+    }
+
+    // mean absolute difference between two images, over all channels
+    fn mean_abs_error(a: &RgbImage, b: &RgbImage) -> f64 {
+        let total: u64 = a.pixels.iter().zip(b.pixels.iter()).map(|(p, q)| {
+            (p.red as i64 - q.red as i64).unsigned_abs()
+                + (p.green as i64 - q.green as i64).unsigned_abs()
+                + (p.blue as i64 - q.blue as i64).unsigned_abs()
+        }).sum();
+        total as f64 / (a.pixels.len() * 3) as f64
+    }
+
+    // round trip through the library api using only in-memory buffers
+    #[test]
+    fn encode_decode_in_memory() {
+        let input = gradient_ppm(32, 24);
+        let mut compressed = Vec::new();
+        let stats = encode(input.as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        assert_eq!((stats.width, stats.height, stats.codewords), (32, 24, 16 * 12));
+        assert_eq!(stats.bytes_written, compressed.len());
+
+        let mut output = Vec::new();
+        let stats = decode(compressed.as_slice(), &mut output).unwrap();
+        assert_eq!((stats.width, stats.height), (32, 24));
+
+        let original = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+        assert_eq!((decoded.width, decoded.height), (32, 24));
+        assert!(mean_abs_error(&original, &decoded) < 8.0);
+    }
+
+    // the quantizer chosen by the quality setting travels in the header
+    #[test]
+    fn quality_is_recorded_in_header() {
+        use crate::format::read_rpeg;
+
+        let input = gradient_ppm(32, 32);
+        let original = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let mut errors = Vec::new();
+        for quality in [100, 50, 1] {
+            let config = EncoderConfig { quality, ..EncoderConfig::default() };
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, &config).unwrap();
+            let (header, _) = read_rpeg(&compressed).unwrap();
+            assert_eq!(header.quant, quant_params_for_quality(quality, &header.layout).unwrap());
+
+            let mut output = Vec::new();
+            decode(compressed.as_slice(), &mut output).unwrap();
+            let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+            errors.push(mean_abs_error(&original, &decoded));
+        }
+        let layout = crate::layout::CodewordLayout::default();
+        assert_eq!(quant_params_for_quality(100, &layout).unwrap(), crate::structures::QuantParams::default());
+        assert!(errors[0] <= errors[2]);
+        assert!(quant_params_for_quality(0, &layout).is_err());
+        assert!(quant_params_for_quality(101, &layout).is_err());
+    }
+
+    // alternate codeword layouts are stored in the header and decode without being told
+    #[test]
+    fn codeword_layout_round_trip() {
+        use crate::format::read_rpeg;
+        use crate::layout::CodewordLayout;
+
+        let wide: CodewordLayout = "64 a:12@40 b:6@34 c:6@28 d:6@22 pb:4@4 pr:4@0".parse().unwrap();
+        let input = gradient_ppm(16, 16);
+        let config = EncoderConfig { layout: Some(wide), ..EncoderConfig::default() };
+        let mut compressed = Vec::new();
+        encode(input.as_slice(), &mut compressed, &config).unwrap();
+        let (header, payload) = read_rpeg(&compressed).unwrap();
+        assert_eq!(header.layout, wide);
+        assert_eq!(header.quant.bcd_levels, 31);
+        assert_eq!(payload.len(), 64 * 8);
+
+        let mut output = Vec::new();
+        decode(compressed.as_slice(), &mut output).unwrap();
+        let original = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+        assert!(mean_abs_error(&original, &decoded) < 8.0);
+
+        assert_eq!(CodewordLayout::default().to_string().parse::<CodewordLayout>().unwrap(), CodewordLayout::default());
+        // b overlaps a
+        assert!("32 a:9@23 b:5@19 c:5@13 d:5@8 pb:4@4 pr:4@0".parse::<CodewordLayout>().is_err());
+        // a runs past the end of the word
+        assert!("32 a:10@23 b:5@18 c:5@13 d:5@8 pb:4@4 pr:4@0".parse::<CodewordLayout>().is_err());
+        // words must be whole bytes
+        assert!("30 a:7@23 b:5@18 c:5@13 d:5@8 pb:4@4 pr:4@0".parse::<CodewordLayout>().is_err());
+    }
+
+    // the v2 container detects corruption, and legacy files still decode
+    #[test]
+    fn container_checksum_and_legacy_files() {
+        use crate::error::RpegError;
+
+        assert_eq!(crate::checksum::crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut compressed = Vec::new();
+        encode(gradient_ppm(8, 8).as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        assert!(compressed.starts_with(b"RPEG\x02"));
+        let last_payload_byte = compressed.len() - 5;
+        compressed[last_payload_byte] ^= 0x40;
+        let result = decode(compressed.as_slice(), Vec::new());
+        assert!(matches!(result, Err(RpegError::MalformedHeader(_))));
+
+        // a 4x2 image in the format written by csc411_rpegio
+        let mut legacy = b"Compressed image format 2\n4 2\n".to_vec();
+        legacy.extend_from_slice(&[0x80, 0x00, 0x00, 0x77, 0x80, 0x00, 0x00, 0x77]);
+        let mut output = Vec::new();
+        let stats = decode(legacy.as_slice(), &mut output).unwrap();
+        assert_eq!((stats.width, stats.height), (4, 2));
+
+        legacy.pop();
+        let result = decode(legacy.as_slice(), Vec::new());
+        assert!(matches!(result, Err(RpegError::DimensionMismatch(_))));
+    }
+
+    // odd dimensions are padded on the way in and cropped on the way out
+    #[test]
+    fn odd_dimensions_are_preserved() {
+        for (width, height) in [(13, 9), (1, 1), (6, 7)] {
+            let input = gradient_ppm(width, height);
+            let mut compressed = Vec::new();
+            let stats = encode(input.as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+            assert_eq!((stats.width, stats.height), (width, height));
+            assert_eq!(stats.codewords, width.div_ceil(2) * height.div_ceil(2));
+
+            let mut output = Vec::new();
+            decode(compressed.as_slice(), &mut output).unwrap();
+            let original = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+            let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+            assert_eq!((decoded.width, decoded.height), (width as u32, height as u32));
+            assert!(mean_abs_error(&original, &decoded) < 12.0);
+        }
+
+        // padding repeats the last column and row
+        let padded = pad_array(Array2::from_row_major(3, 1, vec![1, 2, 3]).unwrap(), 2);
+        assert_eq!(padded.get_data(), &vec![1, 2, 3, 3, 1, 2, 3, 3]);
+        assert_eq!(crop_array(padded, 3, 1).get_data(), &vec![1, 2, 3]);
+    }
+
+    // the 8x8 dct mode records its matrix in the header and round-trips
+    #[test]
+    fn dct_8x8_round_trip() {
+        use crate::format::read_rpeg;
+        use crate::structures::{BlockTransform, Crt, QuantMatrix};
+
+        // with every step 1 the transform only loses the rounding of each level
+        let ones = QuantMatrix { steps: [1; 64] };
+        let mut crt_array = Array2::new(8, 8, Crt { y: 0.0, pb: 0.0, pr: 0.0 });
+        for (c, r, _) in crt_array.clone().iter_row_major() {
+            crt_array.get_mut(c, r).unwrap().y = ((c * 31 + r * 17) % 256) as f32 / 255.0;
+        }
+        let blocks = dct_8x8(crt_array.clone(), &ones).unwrap();
+        let restored = inverse_dct_8x8(blocks, &ones, new_crt_array(8, 8)).unwrap();
+        for (c, r, crt) in restored.iter_row_major() {
+            assert!((crt.y - crt_array.get(c, r).unwrap().y).abs() < 2.0 / 255.0);
+        }
+        assert_eq!(quant_matrix_for_quality(100).unwrap(), ones);
+        assert_eq!(quant_matrix_for_quality(50).unwrap().steps[0], 16);
+
+        let input = gradient_ppm(21, 13);
+        let original = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let mut sizes = Vec::new();
+        for quality in [100, 50] {
+            let config = EncoderConfig { quality, transform: BlockTransform::Dct8x8, ..EncoderConfig::default() };
+            let mut compressed = Vec::new();
+            let stats = encode(input.as_slice(), &mut compressed, &config).unwrap();
+            assert_eq!(stats.codewords, 3 * 2);
+            let (header, _) = read_rpeg(&compressed).unwrap();
+            assert_eq!(header.transform, BlockTransform::Dct8x8);
+            assert_eq!(header.dct_matrix, Some(quant_matrix_for_quality(quality).unwrap()));
+
+            let mut output = Vec::new();
+            decode(compressed.as_slice(), &mut output).unwrap();
+            let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+            assert_eq!((decoded.width, decoded.height), (21, 13));
+            assert!(mean_abs_error(&original, &decoded) < 8.0);
+            sizes.push(compressed.len());
+        }
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    // huffman coding is lossless on top of quantization and shrinks flat images
+    #[test]
+    fn huffman_entropy_coding() {
+        use crate::huffman::{decode_streams, encode_streams};
+        use crate::structures::{BlockTransform, EntropyCoding};
+
+        let streams = vec![vec![0, 0, 0, 0, 0, 0, -1, 3, 0, 7], vec![5; 4], vec![]];
+        let coded = encode_streams(&streams).unwrap();
+        assert_eq!(decode_streams(&coded, &[10, 4, 0]).unwrap(), streams);
+        assert!(decode_streams(&coded, &[10, 40, 0]).is_err());
+
+        let black = std::fs::read("black.ppm").unwrap();
+        for input in [black, gradient_ppm(67, 45)] {
+            for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+                let mut sizes = Vec::new();
+                let mut outputs = Vec::new();
+                for entropy in [EntropyCoding::Fixed, EntropyCoding::Huffman] {
+                    let config = EncoderConfig { quality: 75, transform, entropy, ..EncoderConfig::default() };
+                    let mut compressed = Vec::new();
+                    encode(input.as_slice(), &mut compressed, &config).unwrap();
+                    let mut output = Vec::new();
+                    decode(compressed.as_slice(), &mut output).unwrap();
+                    sizes.push(compressed.len());
+                    outputs.push(output);
+                }
+                assert_eq!(outputs[0], outputs[1]);
+                assert!(sizes[1] < sizes[0], "{:?} {:?}", transform, sizes);
+            }
+        }
+
+        // the all-black fixture is a single symbol per field, so a bit per value
+        let mut compressed = Vec::new();
+        let config = EncoderConfig { entropy: EntropyCoding::Huffman, ..EncoderConfig::default() };
+        encode(&std::fs::read("black.ppm").unwrap()[..], &mut compressed, &config).unwrap();
+        assert!(compressed.len() < 32 * 24 * 6 / 8 + 200);
+    }
+
+    // the range coder reproduces exactly what the fixed-width codewords hold
+    #[test]
+    fn range_coder_matches_fixed_codewords() {
+        use crate::format::{quanta_to_streams, streams_to_quanta};
+        use crate::layout::CodewordLayout;
+        use crate::rangecoder::{decode_streams, encode_streams};
+        use crate::structures::{BlockTransform, ChromaQuantizer, ColorSpace, EntropyCoding, QuantParams, Subsampling};
+
+        let extremes = vec![vec![i64::MIN, i64::MAX, -1, 0, 1, 0, 0, 1 << 40], vec![], vec![3; 1000]];
+        let coded = encode_streams(&extremes);
+        assert_eq!(decode_streams(&coded, &[8, 0, 1000]).unwrap(), extremes);
+        assert!(decode_streams(&coded[..coded.len() - 1], &[8, 0, 1000]).is_err());
+
+        let input = gradient_ppm(66, 46);
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let pixels = Array2::from_row_major(66, 46, img.pixels).unwrap();
+        let layout = CodewordLayout::default();
+        let (quanta, chroma) = compress_to_quanta(pixels, 255, ColorSpace::Bt601, &QuantParams::default(), false, Subsampling::Yuv420, &ChromaQuantizer::Csc411).unwrap();
+        let unpacked = extract_encoded_data(pack_encoded_quanta(quanta.clone(), &layout).unwrap(), &layout).unwrap();
+
+        let coded = encode_streams(&quanta_to_streams(&quanta, &chroma));
+        let (decoded, _) = streams_to_quanta(&decode_streams(&coded, &[33 * 23; 6]).unwrap(), 33, 23, Subsampling::Yuv420).unwrap();
+        assert_eq!(quanta_to_streams(&decoded, &decoded), quanta_to_streams(&unpacked, &unpacked));
+        assert!(coded.len() < 33 * 23 * 4);
+
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            let mut outputs = Vec::new();
+            for entropy in [EntropyCoding::Fixed, EntropyCoding::RangeCoder] {
+                let config = EncoderConfig { quality: 60, transform, entropy, ..EncoderConfig::default() };
+                let mut compressed = Vec::new();
+                encode(input.as_slice(), &mut compressed, &config).unwrap();
+                let mut output = Vec::new();
+                decode(compressed.as_slice(), &mut output).unwrap();
+                outputs.push(output);
+            }
+            assert_eq!(outputs[0], outputs[1]);
+        }
+    }
+
+    // med prediction of a shrinks smooth images without changing the decoded result
+    #[test]
+    fn dc_prediction_round_trip() {
+        use crate::error::RpegError;
+        use crate::structures::{BlockTransform, DcPrediction, EntropyCoding};
+
+        let values: Vec<i64> = (0..35).map(|i| (i * 37 % 11) * 40 - 100).collect();
+        let residuals = dc_prediction_residuals(&values, 7);
+        assert_eq!(dc_from_prediction_residuals(&residuals, 7), values);
+        assert_eq!(med_predict(10, 20, 5), 20);
+        assert_eq!(med_predict(10, 20, 25), 10);
+        assert_eq!(med_predict(10, 20, 15), 15);
+
+        let input = gradient_ppm(96, 64);
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            for entropy in [EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                let mut sizes = Vec::new();
+                let mut outputs = Vec::new();
+                for prediction in [DcPrediction::None, DcPrediction::Med] {
+                    let config = EncoderConfig { transform, entropy, prediction, ..EncoderConfig::default() };
+                    let mut compressed = Vec::new();
+                    encode(input.as_slice(), &mut compressed, &config).unwrap();
+                    let mut output = Vec::new();
+                    decode(compressed.as_slice(), &mut output).unwrap();
+                    sizes.push(compressed.len());
+                    outputs.push(output);
+                }
+                assert_eq!(outputs[0], outputs[1]);
+                assert!(sizes[1] < sizes[0], "{:?} {:?} {:?}", transform, entropy, sizes);
+            }
+        }
+
+        let config = EncoderConfig { prediction: DcPrediction::Med, ..EncoderConfig::default() };
+        let result = encode(input.as_slice(), Vec::new(), &config);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+    }
+
+    // runs of flat blocks are collapsed and expanded back exactly
+    #[test]
+    fn run_length_coding_of_flat_blocks() {
+        use crate::error::RpegError;
+        use crate::structures::{BlockTransform, EncodedQuanta, EntropyCoding};
+
+        let flat = |a, pb| EncodedQuanta { a, b: 0, c: 0, d: 0, pb_avg: pb, pr_avg: 7 };
+        let detailed = EncodedQuanta { b: 2, ..flat(9, 3) };
+        let blocks = vec![flat(1, 3), flat(2, 3), flat(3, 3), detailed, flat(4, 3), flat(5, 8)];
+        let quanta = Array2::from_row_major(3, 2, blocks).unwrap();
+        let rle = run_length_encode(&quanta);
+        assert_eq!(rle.a, vec![1, 2, 3, 9, 4, 5]);
+        assert_eq!(rle.runs, vec![2, 1, 0]);
+        assert_eq!(expand_runs(rle.clone(), 3, 2).unwrap().get_data(), quanta.get_data());
+        assert!(expand_runs(rle, 2, 2).is_err());
+
+        for input in [std::fs::read("black.ppm").unwrap(), gradient_ppm(40, 30)] {
+            for entropy in [EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                let mut sizes = Vec::new();
+                let mut outputs = Vec::new();
+                for run_length in [false, true] {
+                    let config = EncoderConfig { quality: 50, entropy, run_length, ..EncoderConfig::default() };
+                    let mut compressed = Vec::new();
+                    encode(input.as_slice(), &mut compressed, &config).unwrap();
+                    let mut output = Vec::new();
+                    decode(compressed.as_slice(), &mut output).unwrap();
+                    sizes.push(compressed.len());
+                    outputs.push(output);
+                }
+                assert_eq!(outputs[0], outputs[1]);
+                assert!(sizes[1] <= sizes[0] + 8, "{:?} {:?}", entropy, sizes);
+            }
+        }
+
+        // the all-black fixture is one literal followed by a single run
+        let config = EncoderConfig { entropy: EntropyCoding::Huffman, run_length: true, ..EncoderConfig::default() };
+        let mut compressed = Vec::new();
+        encode(&std::fs::read("black.ppm").unwrap()[..], &mut compressed, &config).unwrap();
+        assert!(compressed.len() < 32 * 24 / 8 + 200);
+
+        let config = EncoderConfig { transform: BlockTransform::Dct8x8, ..config };
+        let result = encode(&std::fs::read("black.ppm").unwrap()[..], Vec::new(), &config);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+    }
+
+    // exported jpegs are well formed baseline files with standard tables
+    #[test]
+    fn jpeg_export_structure() {
+        use crate::error::RpegError;
+        use crate::jpeg::*;
+
+        let order = zigzag_order();
+        assert_eq!(order[..5], [0, 1, 8, 16, 9]);
+        let mut seen = order.to_vec();
+        seen.sort();
+        assert_eq!(seen, (0..64).collect::<Vec<usize>>());
+
+        // every run/size pair appears exactly once in the ac tables
+        for spec in [AC_LUMA, AC_CHROMA] {
+            let total: usize = spec.counts.iter().map(|&count| count as usize).sum();
+            let mut symbols = spec.symbols.to_vec();
+            symbols.sort();
+            symbols.dedup();
+            assert_eq!((total, symbols.len()), (162, 162));
+        }
+
+        let mut compressed = Vec::new();
+        encode(gradient_ppm(37, 21).as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        let mut jpeg = Vec::new();
+        export_jpeg(compressed.as_slice(), &mut jpeg, 90).unwrap();
+        assert_eq!(jpeg[..2], [0xFF, 0xD8]);
+        assert_eq!(jpeg[jpeg.len() - 2..], [0xFF, 0xD9]);
+        let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+        // length, precision, height, width, components
+        assert_eq!(jpeg[sof + 4..sof + 10], [8, 0, 21, 0, 37, 3]);
+
+        let result = export_jpeg(compressed.as_slice(), Vec::new(), 0);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+    }
+
+    // exported jpegs read back close to the image they came from, and can be compressed directly
+    #[test]
+    fn jpeg_import() {
+        use crate::error::RpegError;
+        use crate::jpeg::read_jpeg;
+
+        let mut compressed = Vec::new();
+        encode(gradient_ppm(37, 21).as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        let original = decode_image(compressed.as_slice()).unwrap();
+        let mut jpeg = Vec::new();
+        export_jpeg(compressed.as_slice(), &mut jpeg, 95).unwrap();
+
+        let pixels = read_jpeg(&jpeg).unwrap();
+        assert_eq!((pixels.width(), pixels.height()), (37, 21));
+        let imported = RgbImage { width: 37, height: 21, denominator: 255, pixels: pixels.get_data().to_vec() };
+        assert!(mean_abs_error(&original, &imported) < 3.0);
+
+        // transcode straight from jpeg to rpeg
+        let mut transcoded = Vec::new();
+        let stats = encode(jpeg.as_slice(), &mut transcoded, &EncoderConfig::default()).unwrap();
+        assert_eq!((stats.width, stats.height), (37, 21));
+
+        // the same file marked as progressive or arithmetic-coded is refused
+        let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+        for marker in [0xC2, 0xC9] {
+            let mut unsupported = jpeg.clone();
+            unsupported[sof + 1] = marker;
+            assert!(matches!(read_jpeg(&unsupported), Err(RpegError::UnsupportedFormat(_))));
+        }
+        assert!(read_jpeg(&jpeg[..jpeg.len() / 2]).is_err());
+    }
+
+    // decoded images can be written as png, and png input compresses like the same ppm
+    #[test]
+    fn png_round_trip() {
+        use crate::deflate::{zlib_compress, zlib_decompress};
+        use crate::error::RpegError;
+        use crate::png::read_png;
+
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251 / 7) as u8).collect();
+        let compressed = zlib_compress(&data);
+        assert!(compressed.len() < data.len() / 4);
+        assert_eq!(zlib_decompress(&compressed, data.len()).unwrap(), data);
+        assert!(zlib_decompress(&compressed, data.len() - 1).is_err());
+
+        let ppm = gradient_ppm(29, 17);
+        let mut rpeg = Vec::new();
+        encode(ppm.as_slice(), &mut rpeg, &EncoderConfig::default()).unwrap();
+        let mut png = Vec::new();
+        decode_as(rpeg.as_slice(), &mut png, ImageFormat::from_filename("out.PNG"), &DecoderConfig::default()).unwrap();
+        let decoded = read_png(&png).unwrap();
+        let expected = decode_image(rpeg.as_slice()).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.denominator), (29, 17, 255));
+        // like ppm output, png output is clamped to the denominator
+        assert_eq!(decoded.pixels.iter().map(|p| (p.red, p.green, p.blue)).collect::<Vec<_>>(),
+                   expected.pixels.iter().map(|p| (p.red.min(255), p.green.min(255), p.blue.min(255))).collect::<Vec<_>>());
+
+        // the original image as a png compresses to exactly the same rpeg file
+        let img = crate::pnm::read_ppm(&mut ppm.as_slice()).unwrap();
+        let mut original_png = Vec::new();
+        crate::png::write_png(&mut original_png, &img).unwrap();
+        let mut from_png = Vec::new();
+        encode(original_png.as_slice(), &mut from_png, &EncoderConfig::default()).unwrap();
+        assert_eq!(from_png, rpeg);
+
+        // a flipped bit in the crc of IEND
+        let last = png.len() - 1;
+        png[last] ^= 1;
+        assert!(matches!(read_png(&png), Err(RpegError::MalformedHeader(_))));
+    }
+
+    // grayscale files store luma only, in less space, and decode to pgm
+    #[test]
+    fn grayscale_pgm_round_trip() {
+        use crate::error::RpegError;
+        use crate::layout::CodewordLayout;
+        use crate::pnm::{gray_to_rgb, read_pgm};
+        use crate::structures::{BlockTransform, EntropyCoding};
+
+        let (width, height) = (27, 19);
+        let mut pgm = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+        pgm.extend((0..width * height).map(|i| ((i % width) * 200 / width + (i / width) * 50 / height) as u8));
+        let original = gray_to_rgb(read_pgm(&mut pgm.as_slice()).unwrap());
+
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            for entropy in [EntropyCoding::Fixed, EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                let color = EncoderConfig { transform, entropy, ..EncoderConfig::default() };
+                let gray = EncoderConfig { grayscale: true, ..color.clone() };
+                let mut color_rpeg = Vec::new();
+                encode(pgm.as_slice(), &mut color_rpeg, &color).unwrap();
+                let mut gray_rpeg = Vec::new();
+                encode(pgm.as_slice(), &mut gray_rpeg, &gray).unwrap();
+                assert!(gray_rpeg.len() < color_rpeg.len(), "{:?} {:?}", transform, entropy);
+
+                let mut output = Vec::new();
+                decode(gray_rpeg.as_slice(), &mut output).unwrap();
+                assert!(output.starts_with(b"P5\n27 19\n255\n"));
+                let decoded = gray_to_rgb(read_pgm(&mut output.as_slice()).unwrap());
+                assert!(mean_abs_error(&original, &decoded) < 3.0, "{:?} {:?}", transform, entropy);
+            }
+        }
+
+        // a fixed-width grayscale codeword is three bytes, and the header gains a 4-byte record
+        let mut gray_rpeg = Vec::new();
+        let config = EncoderConfig { grayscale: true, ..EncoderConfig::default() };
+        let stats = encode(pgm.as_slice(), &mut gray_rpeg, &config).unwrap();
+        let mut color_rpeg = Vec::new();
+        encode(pgm.as_slice(), &mut color_rpeg, &EncoderConfig::default()).unwrap();
+        assert_eq!(color_rpeg.len() - gray_rpeg.len() + 4, stats.codewords);
+
+        // color input is reduced to luma, and run-length coding still applies
+        let config = EncoderConfig { entropy: EntropyCoding::Huffman, run_length: true, ..config };
+        let mut compressed = Vec::new();
+        encode(&std::fs::read("black.ppm").unwrap()[..], &mut compressed, &config).unwrap();
+        let mut output = Vec::new();
+        decode(compressed.as_slice(), &mut output).unwrap();
+        assert!(output.starts_with(b"P5\n"));
+
+        // the layout must agree with the mode
+        let config = EncoderConfig { layout: Some(CodewordLayout::luma_only()), ..EncoderConfig::default() };
+        let result = encode(pgm.as_slice(), Vec::new(), &config);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+        let layout = "32 a:9@23 b:5@18 c:5@13 d:5@8 pb:4@0 pr:4@4".parse().unwrap();
+        let config = EncoderConfig { layout: Some(layout), grayscale: true, ..EncoderConfig::default() };
+        let result = encode(pgm.as_slice(), Vec::new(), &config);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+        assert!("24 a:9@15 b:5@10 c:5@5 d:5@0 pb:0@0 pr:4@0".parse::<CodewordLayout>().is_err());
+    }
+
+    // 4:2:2 and 4:4:4 keep more chroma, so sharp colored edges survive better
+    #[test]
+    fn chroma_subsampling_modes() {
+        use crate::error::RpegError;
+        use crate::structures::{BlockTransform, EntropyCoding, Subsampling};
+
+        // colors alternating every row on the left (where horizontal pairs
+        // match) and every pixel on the right
+        let (width, height) = (30, 22);
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for r in 0..height {
+            for c in 0..width {
+                let stripe = if c < 16 { c / 2 + r } else { c + r };
+                ppm.extend(if stripe % 2 == 0 { [220, 30, 40] } else { [20, 60, 210] });
+            }
+        }
+        let original = crate::pnm::read_ppm(&mut ppm.as_slice()).unwrap();
+
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            let mut errors = Vec::new();
+            for subsampling in [Subsampling::Yuv420, Subsampling::Yuv422, Subsampling::Yuv444] {
+                let mut outputs = Vec::new();
+                for entropy in [EntropyCoding::Fixed, EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                    let config = EncoderConfig { transform, entropy, subsampling, ..EncoderConfig::default() };
+                    let mut compressed = Vec::new();
+                    encode(ppm.as_slice(), &mut compressed, &config).unwrap();
+                    outputs.push(decode_image(compressed.as_slice()).unwrap());
+                }
+                // every payload coding stores the same chroma samples
+                assert!(outputs.windows(2).all(|pair| mean_abs_error(&pair[0], &pair[1]) == 0.0));
+                errors.push(mean_abs_error(&original, &outputs[0]));
+            }
+            assert!(errors[0] > errors[1] && errors[1] > errors[2], "{:?} {:?}", transform, errors);
+        }
+
+        // fixed-width 4:4:4 adds three chroma pairs of 4-bit indices per 2x2 block
+        let mut sizes = Vec::new();
+        for subsampling in [Subsampling::Yuv420, Subsampling::Yuv444] {
+            let config = EncoderConfig { subsampling, ..EncoderConfig::default() };
+            let mut compressed = Vec::new();
+            encode(ppm.as_slice(), &mut compressed, &config).unwrap();
+            sizes.push(compressed.len());
+        }
+        assert_eq!(sizes[1] - sizes[0], 15 * 11 * 3);
+
+        let config = EncoderConfig { entropy: EntropyCoding::Huffman, run_length: true, subsampling: Subsampling::Yuv422, ..EncoderConfig::default() };
+        let result = encode(ppm.as_slice(), Vec::new(), &config);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+    }
+
+    // wider and adaptive chroma levels follow a smooth sky-like gradient more closely
+    #[test]
+    fn chroma_quantizer_modes() {
+        use crate::codec::fit_chroma_quantizer;
+        use crate::compression::fit_chroma_levels;
+        use crate::error::RpegError;
+        use crate::layout::CodewordLayout;
+        use crate::structures::{BlockTransform, ChromaQuantizer, ColorSpace, EntropyCoding, Subsampling};
+
+        let (width, height) = (40, 24);
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for r in 0..height {
+            for c in 0..width {
+                ppm.extend([60 + c as u8, 110 + r as u8, 200 + (c + r) as u8 / 2]);
+            }
+        }
+        let original = crate::pnm::read_ppm(&mut ppm.as_slice()).unwrap();
+        let error_with = |config: &EncoderConfig| {
+            let mut compressed = Vec::new();
+            encode(ppm.as_slice(), &mut compressed, config).unwrap();
+            mean_abs_error(&original, &decode_image(compressed.as_slice()).unwrap())
+        };
+
+        let csc411 = error_with(&EncoderConfig::default());
+        let uniform = error_with(&EncoderConfig { chroma_bits: Some(8), ..EncoderConfig::default() });
+        let adaptive = error_with(&EncoderConfig { chroma_bits: Some(8), adaptive_chroma: true, ..EncoderConfig::default() });
+        assert!(uniform < csc411 && adaptive <= uniform, "{} {} {}", csc411, uniform, adaptive);
+        // 16 levels fitted to the narrow range of a sky beat 16 spread over all chroma
+        let uniform = error_with(&EncoderConfig { chroma_bits: Some(4), ..EncoderConfig::default() });
+        let adaptive = error_with(&EncoderConfig { adaptive_chroma: true, ..EncoderConfig::default() });
+        assert!(adaptive < uniform, "{} {}", uniform, adaptive);
+
+        // fitted levels are in order, within the values, and no more than the index allows
+        let values: Vec<f32> = (0..1000).map(|i| ((i * 37) % 1000) as f32 / 4000.0 - 0.1).collect();
+        let levels = fit_chroma_levels(&values, 5);
+        assert!(levels.len() <= 32 && levels.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(levels[0] >= -0.1 && levels[levels.len() - 1] <= 0.15);
+        let pixels = original.pixels.clone();
+        let pixels = array2::Array2::from_row_major(width, height, pixels).unwrap();
+        let quantizer = fit_chroma_quantizer(&pixels, 255, ColorSpace::Bt601, Subsampling::Yuv420, 6).unwrap();
+        assert!(matches!(quantizer, ChromaQuantizer::Adaptive { bits: 6, .. }));
+
+        // the tables survive the header with every payload coding and extra chroma samples
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            let mut outputs = Vec::new();
+            for entropy in [EntropyCoding::Fixed, EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                let config = EncoderConfig {
+                    transform, entropy, subsampling: Subsampling::Yuv444, chroma_bits: Some(6), adaptive_chroma: true, ..EncoderConfig::default()
+                };
+                let mut compressed = Vec::new();
+                encode(ppm.as_slice(), &mut compressed, &config).unwrap();
+                outputs.push(decode_image(compressed.as_slice()).unwrap());
+            }
+            assert!(outputs.windows(2).all(|pair| mean_abs_error(&pair[0], &pair[1]) == 0.0));
+        }
+
+        for config in [
+            EncoderConfig { chroma_bits: Some(9), ..EncoderConfig::default() },
+            EncoderConfig { chroma_bits: Some(6), grayscale: true, ..EncoderConfig::default() },
+            EncoderConfig { chroma_bits: Some(6), layout: Some("32 a:9@23 b:5@18 c:5@13 d:5@8 pb:4@0 pr:4@4".parse().unwrap()), ..EncoderConfig::default() },
+            // a layout given explicitly is never widened, even when it is the default one
+            EncoderConfig { chroma_bits: Some(6), layout: Some(CodewordLayout::default()), ..EncoderConfig::default() },
+        ] {
+            let result = encode(ppm.as_slice(), Vec::new(), &config);
+            assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+        }
+    }
+
+    // size targets pick the highest quality that fits, and say when nothing does
+    #[test]
+    fn encode_to_size_targets() {
+        use crate::error::RpegError;
+        use crate::structures::{BlockTransform, EntropyCoding};
+
+        let input = gradient_ppm(48, 40);
+        let size_at = |config: &EncoderConfig| {
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, config).unwrap();
+            compressed.len()
+        };
+
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            let config = EncoderConfig { transform, entropy: EntropyCoding::Huffman, ..EncoderConfig::default() };
+            let budget = (size_at(&EncoderConfig { quality: 1, ..config.clone() }) + size_at(&config)) / 2;
+            let mut compressed = Vec::new();
+            let (chosen, stats) = encode_to_size(input.as_slice(), &mut compressed, &config, RateTarget::Bytes(budget), false).unwrap();
+            assert_eq!((compressed.len(), stats.bytes_written), (size_at(&chosen), compressed.len()));
+            assert!(compressed.len() <= budget && chosen.quality < 100);
+            assert!(size_at(&EncoderConfig { quality: chosen.quality + 1, ..chosen.clone() }) > budget);
+            assert!(decode_image(compressed.as_slice()).is_ok());
+
+            // searching payload codings never does worse
+            let (searched, _) = encode_to_size(input.as_slice(), Vec::new(), &config, RateTarget::Bytes(budget), true).unwrap();
+            assert!(searched.quality >= chosen.quality);
+        }
+
+        // fixed-width 2x2 files are the same size at every quality, so only a coder switch fits
+        let fixed_size = size_at(&EncoderConfig::default());
+        let target = RateTarget::BitsPerPixel((fixed_size - 1) as f64 * 8.0 / (48 * 40) as f64);
+        assert_eq!(target.max_bytes(48, 40), fixed_size - 1);
+        let result = encode_to_size(input.as_slice(), Vec::new(), &EncoderConfig::default(), target, false);
+        assert!(matches!(result, Err(RpegError::TargetNotMet(_))));
+        let (chosen, stats) = encode_to_size(input.as_slice(), Vec::new(), &EncoderConfig::default(), target, true).unwrap();
+        assert!(chosen.entropy != EntropyCoding::Fixed && stats.bytes_written < fixed_size);
+    }
+
+    // a size target that cannot be met leaves an existing output file untouched
+    #[test]
+    fn unmet_size_target_keeps_output() {
+        use crate::error::RpegError;
+
+        let prefix = std::env::temp_dir().join(format!("rpeg-size-{}", std::process::id()));
+        let (input, output) = (prefix.with_extension("ppm"), prefix.with_extension("rpeg"));
+        std::fs::write(&input, gradient_ppm(48, 40)).unwrap();
+        std::fs::write(&output, b"previous output").unwrap();
+        let result = compress_to_size(input.to_str(), output.to_str(), &EncoderConfig::default(), RateTarget::Bytes(1), false);
+        assert!(matches!(result, Err(RpegError::TargetNotMet(_))));
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+
+        // a target that is met replaces it
+        compress_to_size(input.to_str(), output.to_str(), &EncoderConfig::default(), RateTarget::Bytes(100000), false).unwrap();
+        assert!(decode_image(std::fs::read(&output).unwrap().as_slice()).is_ok());
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    // quality targets pick the lowest quality whose reconstruction measures up
+    #[test]
+    fn encode_to_quality_targets() {
+        use crate::error::RpegError;
+        use crate::metrics::{psnr, ssim};
+        use crate::structures::{BlockTransform, EntropyCoding};
+
+        let input = gradient_ppm(48, 40);
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let original = Array2::from_row_major(48, 40, img.pixels).unwrap();
+        assert_eq!(psnr(&original, &original).unwrap(), f64::INFINITY);
+        assert!((ssim(&original, &original).unwrap() - 1.0).abs() < 1e-9);
+        let decoded_at = |config: &EncoderConfig| {
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, config).unwrap();
+            let img = decode_image(compressed.as_slice()).unwrap();
+            Array2::from_row_major(48, 40, img.pixels).unwrap()
+        };
+
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            let config = EncoderConfig { transform, ..EncoderConfig::default() };
+            let (lowest, highest) = (decoded_at(&EncoderConfig { quality: 1, ..config.clone() }), decoded_at(&config));
+            let midway = |metric: fn(&Array2<Rgb>, &Array2<Rgb>) -> Result<f64, RpegError>| {
+                (metric(&original, &lowest).unwrap() + metric(&original, &highest).unwrap()) / 2.0
+            };
+            for target in [QualityTarget::Psnr(midway(psnr)), QualityTarget::Ssim(midway(ssim))] {
+                let mut compressed = Vec::new();
+                let (chosen, stats, measured) = encode_to_quality(input.as_slice(), &mut compressed, &config, target, true).unwrap();
+                assert_eq!(stats.bytes_written, compressed.len());
+                assert!(target.is_met_by(measured), "{:?} {}", target, measured);
+                assert_eq!(target.measure(&original, &decoded_at(&chosen)).unwrap(), measured);
+                if chosen.quality > 1 {
+                    let lower = decoded_at(&EncoderConfig { quality: chosen.quality - 1, ..chosen.clone() });
+                    assert!(!target.is_met_by(target.measure(&original, &lower).unwrap()));
+                }
+                // entropy coding is lossless, so searching it only makes the file smaller
+                assert!(chosen.entropy != EntropyCoding::Fixed);
+            }
+        }
+
+        let result = encode_to_quality(input.as_slice(), Vec::new(), &EncoderConfig::default(), QualityTarget::Psnr(99.0), false);
+        assert!(matches!(result, Err(RpegError::TargetNotMet(_))));
+    }
+
+    // a failed compress, decompress or export leaves an existing output file untouched
+    #[test]
+    fn failed_commands_keep_output() {
+        let prefix = std::env::temp_dir().join(format!("rpeg-failed-{}", std::process::id()));
+        let (input, output) = (prefix.with_extension("ppm"), prefix.with_extension("out"));
+        std::fs::write(&input, b"P6\n4 4\n255\n").unwrap();
+        std::fs::write(&output, b"previous output").unwrap();
+        assert!(compress(input.to_str(), output.to_str(), &EncoderConfig::default()).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+        assert!(decompress(input.to_str(), output.to_str(), &DecoderConfig::default()).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+        assert!(export(input.to_str(), output.to_str(), 90).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+        let missing = prefix.with_extension("missing");
+        assert!(compress(missing.to_str(), output.to_str(), &EncoderConfig::default()).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+
+        // a compress that succeeds replaces it
+        std::fs::write(&input, gradient_ppm(8, 8)).unwrap();
+        compress(input.to_str(), output.to_str(), &EncoderConfig::default()).unwrap();
+        assert!(decode_image(std::fs::read(&output).unwrap().as_slice()).is_ok());
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    // a quality target that cannot be met leaves an existing output file untouched
+    #[test]
+    fn unmet_quality_target_keeps_output() {
+        use crate::error::RpegError;
+
+        let prefix = std::env::temp_dir().join(format!("rpeg-quality-{}", std::process::id()));
+        let (input, output) = (prefix.with_extension("ppm"), prefix.with_extension("rpeg"));
+        std::fs::write(&input, gradient_ppm(48, 40)).unwrap();
+        std::fs::write(&output, b"previous output").unwrap();
+        let result = compress_to_quality(input.to_str(), output.to_str(), &EncoderConfig::default(), QualityTarget::Psnr(99.0), false);
+        assert!(matches!(result, Err(RpegError::TargetNotMet(_))));
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous output");
+
+        // a target that is met replaces it
+        compress_to_quality(input.to_str(), output.to_str(), &EncoderConfig::default(), QualityTarget::Psnr(20.0), false).unwrap();
+        assert!(decode_image(std::fs::read(&output).unwrap().as_slice()).is_ok());
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    // the metrics agree with hand-worked values and rank reconstructions sensibly
+    #[test]
+    fn image_quality_metrics() {
+        use crate::error::RpegError;
+        use crate::metrics::{channel_psnr, compare, ms_ssim, psnr, rmse, ssim};
+
+        let flat = |red: u16, green: u16, blue: u16| Array2::from_row_major(64, 64, vec![Rgb { red, green, blue }; 64 * 64]).unwrap();
+        // errors of 3 in red and 4 in green only
+        let (a, b) = (flat(100, 100, 100), flat(103, 104, 100));
+        assert!((rmse(&a, &b).unwrap() - (25.0f64 / 3.0).sqrt()).abs() < 1e-9);
+        let [red, green, blue] = channel_psnr(&a, &b).unwrap();
+        assert!((red - 10.0 * (255.0f64 * 255.0 / 9.0).log10()).abs() < 1e-9);
+        assert!((green - 10.0 * (255.0f64 * 255.0 / 16.0).log10()).abs() < 1e-9);
+        assert_eq!(blue, f64::INFINITY);
+        assert!((psnr(&a, &b).unwrap() - 10.0 * (255.0f64 * 255.0 * 3.0 / 25.0).log10()).abs() < 1e-9);
+
+        let input = gradient_ppm(80, 72);
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let original = Array2::from_row_major(80, 72, img.pixels).unwrap();
+        let decoded_at = |quality: u8| {
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, &EncoderConfig { quality, ..EncoderConfig::default() }).unwrap();
+            let img = decode_image(compressed.as_slice()).unwrap();
+            Array2::from_row_major(80, 72, img.pixels).unwrap()
+        };
+        let (good, poor) = (compare(&original, &decoded_at(100)).unwrap(), compare(&original, &decoded_at(1)).unwrap());
+        assert!(good.rmse < poor.rmse && good.psnr > poor.psnr);
+        assert!(good.ssim > poor.ssim && good.ms_ssim > poor.ms_ssim);
+        assert!(poor.ssim > 0.0 && good.ms_ssim <= 1.0);
+        assert_eq!(compare(&original, &original).unwrap().ms_ssim, 1.0);
+        // images smaller than the window still get a measure
+        assert!(ssim(&flat(1, 2, 3), &flat(1, 2, 3)).unwrap() > 0.999);
+        assert!(ms_ssim(&a, &b).unwrap() > 0.99);
+
+        let result = compare(&original, &a);
+        assert!(matches!(result, Err(RpegError::DimensionMismatch(_))));
+    }
+
+    // deblocking evens out block edges of smooth images and is off by default
+    #[test]
+    fn deblocking_filter() {
+        use crate::metrics::psnr;
+        use crate::structures::BlockTransform;
+
+        let input = gradient_ppm(64, 48);
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let original = Array2::from_row_major(64, 48, img.pixels).unwrap();
+        let decoded_with = |compressed: &[u8], deblock: Option<f32>| {
+            let img = decode_image_with(compressed, &DecoderConfig { deblock, ..DecoderConfig::default() }).unwrap();
+            Array2::from_row_major(64, 48, img.pixels).unwrap()
+        };
+        // largest jump between horizontal neighbours across block edges
+        let edge_jump = |pixels: &Array2<Rgb>, block: usize| {
+            pixels.iter_row_major()
+                .filter(|(c, _, _)| c % block == 0 && *c > 0)
+                .map(|(c, r, pixel)| (pixel.green as i32 - pixels.get(c - 1, r).unwrap().green as i32).abs())
+                .max()
+                .unwrap()
+        };
+
+        for (transform, block) in [(BlockTransform::Haar2x2, 2), (BlockTransform::Dct8x8, 8)] {
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, &EncoderConfig { transform, quality: 1, ..EncoderConfig::default() }).unwrap();
+            let plain = decoded_with(&compressed, None);
+            let deblocked = decoded_with(&compressed, Some(1.0));
+            let img = decode_image(compressed.as_slice()).unwrap();
+            let default = Array2::from_row_major(64, 48, img.pixels).unwrap();
+            assert_eq!(psnr(&plain, &default).unwrap(), f64::INFINITY);
+            assert_eq!(psnr(&plain, &decoded_with(&compressed, Some(0.0))).unwrap(), f64::INFINITY);
+            assert!(psnr(&original, &deblocked).unwrap() > psnr(&original, &plain).unwrap(), "{:?}", transform);
+            assert!(edge_jump(&deblocked, block) <= edge_jump(&plain, block), "{:?}", transform);
+        }
+
+        // grayscale images only have luma to filter
+        let mut compressed = Vec::new();
+        encode(input.as_slice(), &mut compressed, &EncoderConfig { grayscale: true, quality: 1, ..EncoderConfig::default() }).unwrap();
+        let deblocked = decoded_with(&compressed, Some(1.0));
+        assert!(deblocked.get_data().iter().all(|pixel| pixel.red == pixel.green && pixel.green == pixel.blue));
+    }
+
+    // interpolated chroma follows color edges more closely than replicated chroma
+    #[test]
+    fn chroma_upsampling() {
+        use crate::metrics::psnr;
+        use crate::structures::{ChromaUpsampling, Subsampling};
+
+        // a red and blue image split along a diagonal, so the edge crosses chroma cells
+        let (width, height) = (32, 32);
+        let mut input = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for r in 0..height {
+            for c in 0..width {
+                input.extend_from_slice(if 2 * c + r < 40 { &[200, 40, 40] } else { &[40, 60, 200] });
+            }
+        }
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let original = Array2::from_row_major(width, height, img.pixels).unwrap();
+        let decoded_with = |compressed: &[u8], upsampling| {
+            let img = decode_image_with(compressed, &DecoderConfig { upsampling, ..DecoderConfig::default() }).unwrap();
+            Array2::from_row_major(width, height, img.pixels).unwrap()
+        };
+
+        let mut compressed = Vec::new();
+        encode(input.as_slice(), &mut compressed, &EncoderConfig { quality: 100, ..EncoderConfig::default() }).unwrap();
+        let img = decode_image(compressed.as_slice()).unwrap();
+        let default = Array2::from_row_major(width, height, img.pixels).unwrap();
+        let replicated = decoded_with(&compressed, ChromaUpsampling::Replicate);
+        let bilinear = decoded_with(&compressed, ChromaUpsampling::Bilinear);
+        let guided = decoded_with(&compressed, ChromaUpsampling::LumaGuided);
+        assert_eq!(psnr(&default, &replicated).unwrap(), f64::INFINITY);
+        assert!(psnr(&original, &bilinear).unwrap() > psnr(&original, &replicated).unwrap());
+        assert!(psnr(&original, &guided).unwrap() > psnr(&original, &bilinear).unwrap());
+
+        // full-resolution chroma has nothing to interpolate
+        let mut compressed = Vec::new();
+        encode(input.as_slice(), &mut compressed, &EncoderConfig { subsampling: Subsampling::Yuv444, ..EncoderConfig::default() }).unwrap();
+        let replicated = decoded_with(&compressed, ChromaUpsampling::Replicate);
+        assert_eq!(psnr(&replicated, &decoded_with(&compressed, ChromaUpsampling::LumaGuided)).unwrap(), f64::INFINITY);
+    }
+
+    // pixels are rounded to the nearest level and clamped, with clipping counted per channel
+    #[test]
+    fn pixel_rounding_and_dithering() {
+        use crate::structures::{Dither, RgbFloat};
+
+        let samples = vec![
+            RgbFloat { red: -0.01, green: 254.4 / 255.0, blue: 0.3 / 255.0 },
+            RgbFloat { red: 1.02, green: 254.6 / 255.0, blue: 0.7 / 255.0 },
+        ];
+        let (pixels, clipped) = quantize_rgb_float_array(Array2::from_row_major(2, 1, samples).unwrap(), 255, Dither::None);
+        let levels: Vec<_> = pixels.get_data().iter().map(|p| (p.red, p.green, p.blue)).collect();
+        assert_eq!(levels, vec![(0, 254, 0), (255, 255, 1)]);
+        assert_eq!(clipped, [2, 0, 0]);
+
+        // higher maxvals get finer levels
+        let (pixels, _) = quantize_rgb_float_array(Array2::new(1, 1, RgbFloat { red: 0.5, green: 1.0, blue: 0.0 }), 65535, Dither::None);
+        let pixel = pixels.get(0, 0).unwrap();
+        assert_eq!((pixel.red, pixel.green, pixel.blue), (32768, 65535, 0));
+
+        // a flat shade between two levels dithers to a mix of them with the same mean
+        let shade = RgbFloat { red: 100.25 / 255.0, green: 100.5 / 255.0, blue: 100.75 / 255.0 };
+        for dither in [Dither::Ordered, Dither::FloydSteinberg] {
+            let (pixels, clipped) = quantize_rgb_float_array(Array2::new(16, 16, shade), 255, dither);
+            assert_eq!(clipped, [0, 0, 0]);
+            let mean = |channel: fn(&Rgb) -> u16| pixels.get_data().iter().map(|p| channel(p) as f64).sum::<f64>() / 256.0;
+            for (mean, expected) in [(mean(|p| p.red), 100.25), (mean(|p| p.green), 100.5), (mean(|p| p.blue), 100.75)] {
+                assert!((mean - expected).abs() < 0.05, "{:?} {} {}", dither, mean, expected);
+            }
+            assert!(pixels.get_data().iter().all(|p| (100..=101).contains(&p.red)));
+        }
+
+        // decoding reports the samples it had to clamp
+        let mut input = b"P6\n16 16\n255\n".to_vec();
+        let mut seed = 1u32;
+        for _ in 0..16 * 16 * 3 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            input.push(if seed >> 16 & 1 == 0 { 0 } else { 255 });
+        }
+        let mut compressed = Vec::new();
+        encode(input.as_slice(), &mut compressed, &EncoderConfig { quality: 1, ..EncoderConfig::default() }).unwrap();
+        let stats = decode(compressed.as_slice(), Vec::new()).unwrap();
+        assert!(stats.clipped.iter().sum::<usize>() > 0);
+    }
+
+    // images keep their maxval through encoding and decoding, including 16-bit ones
+    #[test]
+    fn maxval_round_trip() {
+        use crate::format::read_rpeg;
+        use crate::metrics::psnr;
+
+        // the same gradient at 8 bits and at a deeper maxval
+        let gradient = |maxval: u32| {
+            let mut bytes = format!("P6\n32 24\n{}\n", maxval).into_bytes();
+            for r in 0..24u32 {
+                for c in 0..32u32 {
+                    for sample in [c * maxval / 32, r * maxval / 24, maxval / 2] {
+                        if maxval < 256 {
+                            bytes.push(sample as u8);
+                        } else {
+                            bytes.extend_from_slice(&(sample as u16).to_be_bytes());
+                        }
+                    }
+                }
+            }
+            bytes
+        };
+        let eight_bit = gradient(255);
+        let mut compressed = Vec::new();
+        encode(eight_bit.as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        let expected = decode_image(compressed.as_slice()).unwrap();
+        assert_eq!(expected.denominator, 255);
+        assert_eq!(read_rpeg(&compressed).unwrap().0.maxval, 255);
+
+        for maxval in [1023, 65535] {
+            let input = gradient(maxval);
+            let mut compressed = Vec::new();
+            encode(input.as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+            assert_eq!(read_rpeg(&compressed).unwrap().0.maxval as u32, maxval);
+
+            let mut output = Vec::new();
+            decode(compressed.as_slice(), &mut output).unwrap();
+            assert!(output.starts_with(format!("P6\n32 24\n{}\n", maxval).as_bytes()));
+            let decoded = crate::pnm::read_ppm(&mut output.as_slice()).unwrap();
+            assert_eq!(decoded.denominator as u32, maxval);
+
+            // deeper samples decode to the same picture as the 8-bit ones
+            let scale = |sample: u16| (sample as u32 * 255 + maxval / 2) / maxval;
+            let rescaled: Vec<Rgb> = decoded.pixels.iter()
+                .map(|p| Rgb { red: scale(p.red) as u16, green: scale(p.green) as u16, blue: scale(p.blue) as u16 })
+                .collect();
+            let a = Array2::from_row_major(32, 24, expected.pixels.clone()).unwrap();
+            let b = Array2::from_row_major(32, 24, rescaled).unwrap();
+            assert!(psnr(&a, &b).unwrap() > 40.0, "{}", maxval);
+        }
+    }
+
+    // every color matrix inverts, is stored in the header and decodes close to the input
+    #[test]
+    fn color_spaces() {
+        use crate::format::read_rpeg;
+        use crate::metrics::psnr;
+        use crate::structures::{ColorSpace, RgbFloat};
+
+        let colors: Vec<RgbFloat> = [(0.0, 0.0, 0.0), (1.0, 1.0, 1.0), (0.5, 0.5, 0.5), (1.0, 0.0, 0.0), (0.2, 0.7, 0.4), (0.0, 0.1, 0.9)]
+            .iter()
+            .map(|&(red, green, blue)| RgbFloat { red, green, blue })
+            .collect();
+        let input = gradient_ppm(32, 24);
+        let img = crate::pnm::read_ppm(&mut input.as_slice()).unwrap();
+        let original = Array2::from_row_major(32, 24, img.pixels).unwrap();
+
+        for colorspace in [ColorSpace::Bt601, ColorSpace::Bt709, ColorSpace::Bt2020, ColorSpace::YCoCg] {
+            let crts = rgb_float_array_to_crt_array_in(Array2::from_row_major(6, 1, colors.clone()).unwrap(), colorspace);
+            // grays have no chroma, and chroma stays within [-0.5, 0.5]
+            for crt in &crts.get_data()[..3] {
+                assert!(crt.pb.abs() < 1e-6 && crt.pr.abs() < 1e-6, "{:?}", colorspace);
+            }
+            assert!(crts.get_data().iter().all(|crt| crt.pb.abs() <= 0.5 && crt.pr.abs() <= 0.5));
+            let back = crt_array_to_rgb_float_array_in(crts, colorspace);
+            for (a, b) in colors.iter().zip(back.get_data()) {
+                let error = (a.red - b.red).abs().max((a.green - b.green).abs()).max((a.blue - b.blue).abs());
+                assert!(error < 1e-5, "{:?} {:?} {:?}", colorspace, a, b);
+            }
+
+            let mut compressed = Vec::new();
+            // the csc411 chroma table suits bt.601, so compare the matrices with even levels
+            encode(input.as_slice(), &mut compressed, &EncoderConfig { colorspace, chroma_bits: Some(6), ..EncoderConfig::default() }).unwrap();
+            assert_eq!(read_rpeg(&compressed).unwrap().0.colorspace, colorspace);
+            let img = decode_image(compressed.as_slice()).unwrap();
+            let decoded = Array2::from_row_major(32, 24, img.pixels).unwrap();
+            assert!(psnr(&original, &decoded).unwrap() > 32.0, "{:?}", colorspace);
+        }
+
+        // the default matrix is bt.601
+        let mut default = Vec::new();
+        encode(input.as_slice(), &mut default, &EncoderConfig::default()).unwrap();
+        let mut bt601 = Vec::new();
+        encode(input.as_slice(), &mut bt601, &EncoderConfig { colorspace: ColorSpace::Bt601, ..EncoderConfig::default() }).unwrap();
+        assert_eq!(default, bt601);
+    }
+
+    // the checked pipeline steps report arrays of the wrong size and take any subsampling
+    #[test]
+    fn pipeline_steps_check_dimensions() {
+        use crate::error::RpegError;
+        use crate::structures::{Quantized, Subsampling};
+
+        let img = crate::pnm::read_ppm(&mut gradient_ppm(8, 4).as_slice()).unwrap();
+        let pixels = Array2::from_row_major(8, 4, img.pixels).unwrap();
+        let crt_array = rgb_float_array_to_crt_array(rgb_int_array_to_rgb_float_array(pixels));
+
+        let quantized_array = try_dct(crt_array.clone(), new_quantized_array(4, 2)).unwrap();
+        assert!(matches!(try_dct(crt_array.clone(), new_quantized_array(3, 2)), Err(RpegError::DimensionMismatch(_))));
+        assert!(matches!(try_inverse_dct(quantized_array.clone(), new_crt_array(8, 2)), Err(RpegError::DimensionMismatch(_))));
+        let restored = try_inverse_dct(quantized_array, new_crt_array(8, 4)).unwrap();
+        for (c, r, crt) in restored.iter_row_major() {
+            assert!((crt.y - crt_array.get(c, r).unwrap().y).abs() < 1e-5);
+        }
+
+        // the unchecked steps are 4:2:0
+        let averages = average_pb_pr_in(crt_array.clone(), new_quantized_array(4, 2), Subsampling::Yuv420).unwrap();
+        let chroma_of = |quantized: &Array2<Quantized>| quantized.get_data().iter().map(|q| (q.pb_avg, q.pr_avg)).collect::<Vec<_>>();
+        assert_eq!(chroma_of(&averages), chroma_of(&average_pb_pr(crt_array.clone(), new_quantized_array(4, 2))));
+        let spread = set_pb_pr_in(averages.clone(), new_crt_array(8, 4), Subsampling::Yuv420).unwrap();
+        let replicated = set_pb_pr(averages, new_crt_array(8, 4));
+        for (c, r, crt) in spread.iter_row_major() {
+            assert_eq!((crt.pb, crt.pr), (replicated.get(c, r).unwrap().pb, replicated.get(c, r).unwrap().pr));
+        }
+
+        let averages = average_pb_pr_in(crt_array.clone(), new_quantized_array(4, 4), Subsampling::Yuv422).unwrap();
+        assert!(matches!(average_pb_pr_in(crt_array, new_quantized_array(4, 2), Subsampling::Yuv422), Err(RpegError::DimensionMismatch(_))));
+        assert!(matches!(set_pb_pr_in(averages.clone(), new_crt_array(8, 8), Subsampling::Yuv422), Err(RpegError::DimensionMismatch(_))));
+        assert!(set_pb_pr_in(averages, new_crt_array(8, 4), Subsampling::Yuv422).is_ok());
+    }
+
+    // sizes and levels read from a file are not trusted: dimensions that overflow, payloads
+    // too short for the image they claim and levels no encoder writes are errors, not
+    // overflows or allocations of what the header asks for
+    #[test]
+    fn hostile_inputs_return_errors() {
+        use crate::checksum::{crc32, crc32_update};
+        use crate::error::RpegError;
+        use crate::format::{dct_stream_counts, read_rpeg, write_rpeg};
+        use crate::png::read_png;
+        use crate::structures::{BlockTransform, EntropyCoding, Subsampling};
+
+        // the same payload under a header with other dimensions, and a valid crc
+        let rewrap = |compressed: &[u8], width: usize, height: usize, payload: Option<Vec<u8>>| {
+            let (mut header, original) = read_rpeg(compressed).unwrap();
+            (header.width, header.height) = (width, height);
+            let mut bytes = Vec::new();
+            write_rpeg(&mut bytes, &header, &payload.unwrap_or(original)).unwrap();
+            bytes
+        };
+        let input = gradient_ppm(16, 16);
+        for transform in [BlockTransform::Haar2x2, BlockTransform::Dct8x8] {
+            for entropy in [EntropyCoding::Fixed, EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+                let mut compressed = Vec::new();
+                encode(input.as_slice(), &mut compressed, &EncoderConfig { transform, entropy, ..EncoderConfig::default() }).unwrap();
+                for (width, height) in [(0xFFFF_FFFF, 0xFFFF_FFFF), (1 << 20, 1 << 20), (0xFFFF_FFFF, 16)] {
+                    let result = decode(rewrap(&compressed, width, height, None).as_slice(), Vec::new());
+                    assert!(result.is_err(), "{:?} {:?} {}x{}", transform, entropy, width, height);
+                }
+            }
+        }
+
+        // dct levels past what the matrix allows, which would overflow when dequantized
+        for entropy in [EntropyCoding::Huffman, EntropyCoding::RangeCoder] {
+            let mut compressed = Vec::new();
+            let config = EncoderConfig { transform: BlockTransform::Dct8x8, entropy, ..EncoderConfig::default() };
+            encode(input.as_slice(), &mut compressed, &config).unwrap();
+            let (_, payload) = read_rpeg(&compressed).unwrap();
+            let counts = dct_stream_counts(16, 16, Subsampling::Yuv420).unwrap();
+            for level in [i64::MAX, i64::MIN, -(1 << 40)] {
+                let mut streams = match entropy {
+                    EntropyCoding::Huffman => crate::huffman::decode_streams(&payload, &counts).unwrap(),
+                    _ => crate::rangecoder::decode_streams(&payload, &counts).unwrap(),
+                };
+                streams[1][0] = level;
+                let payload = match entropy {
+                    EntropyCoding::Huffman => crate::huffman::encode_streams(&streams).unwrap(),
+                    _ => crate::rangecoder::encode_streams(&streams),
+                };
+                let result = decode(rewrap(&compressed, 16, 16, Some(payload)).as_slice(), Vec::new());
+                assert!(matches!(result, Err(RpegError::MalformedHeader(_))), "{:?} {}", entropy, level);
+            }
+        }
+
+        // pnm headers claiming more samples than can be counted, or than are there
+        for ppm in [
+            &b"P6\n4294967296 4294967296\n255\n"[..],
+            &b"P6\n18446744073709551615 2\n255\n"[..],
+            &b"P6 100000 100000 255\n\x01\x02\x03"[..],
+            &b"P3 100000 100000 255\n1 2 3"[..],
+        ] {
+            assert!(encode(ppm, Vec::new(), &EncoderConfig::default()).is_err());
+        }
+        assert!(encode(&b"P5 4294967295 4294967295 65535\n\x01\x02"[..], Vec::new(), &EncoderConfig::default()).is_err());
+
+        // a png whose IHDR claims a huge image over a few bytes of pixel data
+        let mut rpeg = Vec::new();
+        encode(gradient_ppm(4, 4).as_slice(), &mut rpeg, &EncoderConfig::default()).unwrap();
+        let mut png = Vec::new();
+        decode_as(rpeg.as_slice(), &mut png, ImageFormat::Png, &DecoderConfig::default()).unwrap();
+        assert_eq!(&png[12..16], b"IHDR");
+        for (width, height) in [(0xFFFF_FFFFu32, 0xFFFF_FFFFu32), (1 << 16, 1 << 16), (0xFFFF_FFFF, 1)] {
+            let mut huge = png.clone();
+            huge[16..20].copy_from_slice(&width.to_be_bytes());
+            huge[20..24].copy_from_slice(&height.to_be_bytes());
+            let crc = crc32_update(crc32(b"IHDR"), &huge[16..29]);
+            huge[29..33].copy_from_slice(&crc.to_be_bytes());
+            assert!(read_png(&huge).is_err(), "{}x{}", width, height);
+            assert!(encode(huge.as_slice(), Vec::new(), &EncoderConfig::default()).is_err());
+        }
+    }
+
+    // huffman codes stay within their length limit, and streams with more distinct
+    // values than such codes can tell apart are refused rather than retried forever
+    #[test]
+    fn huffman_code_length_limit() {
+        use crate::error::RpegError;
+        use crate::huffman::{HuffmanTable, MAX_CODE_LENGTH};
+
+        // fibonacci-like counts make the deepest unlimited tree
+        let mut skewed = Vec::new();
+        let (mut a, mut b) = (1, 1);
+        for symbol in 0..8 {
+            skewed.extend(std::iter::repeat_n(symbol, a));
+            (a, b) = (b, a + b);
+        }
+        for (values, max_length) in [(skewed.clone(), 3), (skewed, MAX_CODE_LENGTH), ((0..8).collect::<Vec<i64>>(), 3)] {
+            let table = HuffmanTable::from_values_limited(&values, max_length).unwrap();
+            assert!(table.encoder().values().all(|&(_, length)| length <= max_length));
+        }
+        let result = HuffmanTable::from_values_limited(&(0..9).collect::<Vec<i64>>(), 3);
+        assert!(matches!(result, Err(RpegError::InvalidConfig(_))));
+    }
+
+    // malformed input is reported as an error rather than a panic
+    #[test]
+    fn bad_inputs_return_errors() {
+        use crate::error::RpegError;
+
+        let result = decode(&b"not an rpeg file\n"[..], Vec::new());
+        assert!(matches!(result, Err(RpegError::MalformedHeader(_))));
+
+        let mut compressed = Vec::new();
+        encode(gradient_ppm(8, 8).as_slice(), &mut compressed, &EncoderConfig::default()).unwrap();
+        compressed.truncate(compressed.len() - 3);
+        let result = decode(compressed.as_slice(), Vec::new());
+        assert!(matches!(result, Err(RpegError::MalformedHeader(_))));
+
+        let result = encode(&b"P6\n4 4\n255\n"[..], Vec::new(), &EncoderConfig::default());
+        assert!(matches!(result, Err(RpegError::DimensionMismatch(_))));
+
+        let result = encode(&b"GIF89a"[..], Vec::new(), &EncoderConfig::default());
+        assert!(matches!(result, Err(RpegError::UnsupportedFormat(_))));
+    }
 }        
 
 // test to use each compression function and print information to standard error
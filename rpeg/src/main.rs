@@ -1,18 +1,350 @@
 use std::env;
-use rpeg::codec::{compress, decompress};
+use std::process;
+use rpeg::codec::{compare, compress, compress_to_quality, compress_to_size, decompress, export, DecoderConfig, EncodeStats, EncoderConfig, QualityTarget, RateTarget};
+use rpeg::metrics::Comparison;
+use rpeg::structures::{BlockTransform, ChromaUpsampling, ColorSpace, DcPrediction, Dither, EntropyCoding, Subsampling};
+
+// jpeg export quality when --quality is not given, as in libjpeg
+const JPEG_QUALITY: u8 = 75;
+
+// deblocking strength when --deblock is given without --deblock-strength
+const DEBLOCK_STRENGTH: f32 = 1.0;
+
+const USAGE: &str = "Usage: rpeg -c [-o output] [--quality 1..100] [--layout \"32 a:9@23 b:5@18 c:5@13 d:5@8 pb:4@4 pr:4@0\"] [--transform 2x2|8x8] [--entropy fixed|huffman|range] [--predict none|med] [--rle] [--gray] [--subsampling 4:2:0|4:2:2|4:4:4] [--chroma-bits 4..8] [--adaptive-chroma] [--colorspace bt601|bt709|bt2020|ycocg] [--target-size bytes|--target-bpp bits|--target-psnr db|--target-ssim 0..1] [filename]\nrpeg -d [-o output.ppm|output.pgm|output.png] [--deblock] [--deblock-strength value] [--upsample replicate|bilinear|guided] [--dither none|ordered|fs] [--report-clipping] [filename]\nrpeg export --jpeg [-o output] [--quality 1..100] [filename]\nrpeg compare [--max-rmse value] original reconstruction";
+
+
+// what the command line asked for
+enum Mode {
+    Compress,
+    Decompress,
+    Export,
+    Compare,
+}
+
+
+// what a target encoding searches for
+enum Target {
+    Size(RateTarget),
+    Quality(QualityTarget),
+}
+
+
+// print usage and exit with the conventional status for bad arguments
+fn usage_error(msg: &str) -> ! {
+    if !msg.is_empty() {
+        eprintln!("rpeg: {}", msg);
+    }
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+
+// name of a payload coding, for reports
+fn coding_name(entropy: EntropyCoding, prediction: DcPrediction) -> String {
+    let name = match entropy {
+        EntropyCoding::Fixed => "fixed-width",
+        EntropyCoding::Huffman => "huffman",
+        EntropyCoding::RangeCoder => "range",
+    };
+    match prediction {
+        DcPrediction::None => format!("{} coding", name),
+        DcPrediction::Med => format!("{} coding with med prediction", name),
+    }
+}
+
+
+// report what a target search settled on, on stderr so it stays out of image data on stdout
+fn report_target(chosen: &EncoderConfig, stats: &EncodeStats, measured: Option<String>) {
+    eprintln!(
+        "rpeg: {} bytes ({:.3} bits per pixel) at quality {}, {}{}",
+        stats.bytes_written,
+        stats.bytes_written as f64 * 8.0 / (stats.width * stats.height).max(1) as f64,
+        chosen.quality,
+        coding_name(chosen.entropy, chosen.prediction),
+        measured.map(|measured| format!(", {}", measured)).unwrap_or_default()
+    );
+}
+
+
+// print every metric of a comparison, one per line
+fn print_comparison(comparison: &Comparison) {
+    let [red, green, blue] = comparison.channel_psnr;
+    println!("rmse     {:.4}", comparison.rmse);
+    println!("psnr     {:.2} db (red {:.2}, green {:.2}, blue {:.2})", comparison.psnr, red, green, blue);
+    println!("ssim     {:.4}", comparison.ssim);
+    println!("ms-ssim  {:.4}", comparison.ms_ssim);
+}
+
+
+// parse a positive number for a target
+fn target_value(value: &str, what: &str) -> f64 {
+    value
+        .parse()
+        .ok()
+        .filter(|target: &f64| target.is_finite() && *target > 0.0)
+        .unwrap_or_else(|| usage_error(&format!("invalid target {} {:?}", what, value)))
+}
+
+
+// parse the value following an option
+fn option_value<'a>(args: &mut impl Iterator<Item = &'a String>, option: &str) -> &'a str {
+    match args.next() {
+        Some(value) => value,
+        None => usage_error(&format!("{} needs a value", option)),
+    }
+}
 
 
 fn main() {
     // use command line arguments to determine whether to compress or decompress, and to where
     let args: Vec<String> = env::args().collect();
-    let argnum = args.len();
-    assert!(argnum == 2 || argnum == 3);
-    let filename = args.iter().nth(2).unwrap();
-    match args[1].as_str() {
-        "-c" => compress(Some(filename)),
-        "-d" => decompress(Some(filename)),
-        _ => {
-        eprintln!("Usage: rpeg -d [filename]\nrpeg -c [filename]")
+    let mut args = args.iter().skip(1);
+
+    let mode = match args.next().map(|s| s.as_str()) {
+        Some("-c") => Mode::Compress,
+        Some("-d") => Mode::Decompress,
+        Some("export") => Mode::Export,
+        Some("compare") => Mode::Compare,
+        _ => usage_error(""),
+    };
+
+    let mut config = EncoderConfig::default();
+    let mut decoder = DecoderConfig::default();
+    // jpeg is the only export format so far, but it must be asked for by name
+    let mut jpeg = false;
+    let mut quality = None;
+    let mut target = None;
+    // a size target may switch payload codings unless one was asked for
+    let mut coding_given = false;
+    // layout, transform, coding and chroma options are encoder options
+    let mut encoder_given = false;
+    // upsampling, dithering and clipping reports are decoder options
+    let mut decoder_given = false;
+    let mut report_clipping = false;
+    // compare fails when the rmse is above this
+    let mut max_rmse: Option<f64> = None;
+    // read from stdin and write to stdout when no filenames are given
+    let mut filename: Option<&str> = None;
+    // the reconstruction, for compare
+    let mut second: Option<&str> = None;
+    let mut output: Option<&str> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quality" => {
+                let value = option_value(&mut args, arg);
+                quality = Some(value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&format!("invalid quality {:?}", value))));
+            }
+            "-o" | "--output" => output = Some(option_value(&mut args, arg)),
+            "--layout" => {
+                let value = option_value(&mut args, arg);
+                config.layout = Some(value
+                    .parse()
+                    .unwrap_or_else(|err| usage_error(&format!("{}", err))));
+                encoder_given = true;
+            }
+            "--transform" => {
+                config.transform = match option_value(&mut args, arg) {
+                    "2x2" => BlockTransform::Haar2x2,
+                    "8x8" => BlockTransform::Dct8x8,
+                    value => usage_error(&format!("invalid transform {:?}", value)),
+                };
+                encoder_given = true;
+            }
+            "--entropy" => {
+                config.entropy = match option_value(&mut args, arg) {
+                    "fixed" => EntropyCoding::Fixed,
+                    "huffman" => EntropyCoding::Huffman,
+                    "range" => EntropyCoding::RangeCoder,
+                    value => usage_error(&format!("invalid entropy coding {:?}", value)),
+                };
+                coding_given = true;
+                encoder_given = true;
+            }
+            "--predict" => {
+                config.prediction = match option_value(&mut args, arg) {
+                    "none" => DcPrediction::None,
+                    "med" => DcPrediction::Med,
+                    value => usage_error(&format!("invalid prediction {:?}", value)),
+                };
+                coding_given = true;
+                encoder_given = true;
+            }
+            "--rle" => {
+                config.run_length = true;
+                encoder_given = true;
+            }
+            "--gray" | "--grayscale" => {
+                config.grayscale = true;
+                encoder_given = true;
+            }
+            "--subsampling" => {
+                config.subsampling = match option_value(&mut args, arg) {
+                    "4:2:0" | "420" => Subsampling::Yuv420,
+                    "4:2:2" | "422" => Subsampling::Yuv422,
+                    "4:4:4" | "444" => Subsampling::Yuv444,
+                    value => usage_error(&format!("invalid subsampling {:?}", value)),
+                };
+                encoder_given = true;
+            }
+            "--chroma-bits" => {
+                let value = option_value(&mut args, arg);
+                config.chroma_bits = Some(value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&format!("invalid chroma bits {:?}", value))));
+                encoder_given = true;
+            }
+            "--adaptive-chroma" => {
+                config.adaptive_chroma = true;
+                encoder_given = true;
+            }
+            "--colorspace" => {
+                config.colorspace = match option_value(&mut args, arg) {
+                    "bt601" => ColorSpace::Bt601,
+                    "bt709" => ColorSpace::Bt709,
+                    "bt2020" => ColorSpace::Bt2020,
+                    "ycocg" => ColorSpace::YCoCg,
+                    value => usage_error(&format!("invalid colorspace {:?}", value)),
+                };
+                encoder_given = true;
+            }
+            "--target-size" | "--target-bpp" | "--target-psnr" | "--target-ssim" if target.is_some() => {
+                usage_error("only one target can be given")
+            }
+            "--target-size" => {
+                let value = option_value(&mut args, arg);
+                target = Some(Target::Size(RateTarget::Bytes(value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&format!("invalid target size {:?}", value))))));
+            }
+            "--target-bpp" => {
+                let value = option_value(&mut args, arg);
+                target = Some(Target::Size(RateTarget::BitsPerPixel(target_value(value, "bits per pixel"))));
+            }
+            "--target-psnr" => {
+                let value = option_value(&mut args, arg);
+                target = Some(Target::Quality(QualityTarget::Psnr(target_value(value, "psnr"))));
+            }
+            "--target-ssim" => {
+                let value = option_value(&mut args, arg);
+                let ssim = target_value(value, "ssim");
+                if ssim > 1.0 {
+                    usage_error(&format!("invalid target ssim {:?}", value));
+                }
+                target = Some(Target::Quality(QualityTarget::Ssim(ssim)));
+            }
+            "--deblock" => decoder.deblock = decoder.deblock.or(Some(DEBLOCK_STRENGTH)),
+            "--deblock-strength" => {
+                let value = option_value(&mut args, arg);
+                decoder.deblock = Some(value
+                    .parse()
+                    .ok()
+                    .filter(|strength: &f32| strength.is_finite() && *strength >= 0.0)
+                    .unwrap_or_else(|| usage_error(&format!("invalid deblocking strength {:?}", value))));
+            }
+            "--upsample" => {
+                decoder.upsampling = match option_value(&mut args, arg) {
+                    "replicate" => ChromaUpsampling::Replicate,
+                    "bilinear" => ChromaUpsampling::Bilinear,
+                    "guided" => ChromaUpsampling::LumaGuided,
+                    value => usage_error(&format!("invalid chroma upsampling {:?}", value)),
+                };
+                decoder_given = true;
+            }
+            "--dither" => {
+                decoder.dither = match option_value(&mut args, arg) {
+                    "none" => Dither::None,
+                    "ordered" => Dither::Ordered,
+                    "fs" | "floyd-steinberg" => Dither::FloydSteinberg,
+                    value => usage_error(&format!("invalid dithering {:?}", value)),
+                };
+                decoder_given = true;
+            }
+            "--report-clipping" => {
+                report_clipping = true;
+                decoder_given = true;
+            }
+            "--max-rmse" => {
+                let value = option_value(&mut args, arg);
+                max_rmse = Some(value
+                    .parse()
+                    .ok()
+                    .filter(|max: &f64| *max >= 0.0)
+                    .unwrap_or_else(|| usage_error(&format!("invalid rmse threshold {:?}", value))));
+            }
+            "--jpeg" => jpeg = true,
+            _ if filename.is_none() && !arg.starts_with('-') => filename = Some(arg),
+            _ if matches!(mode, Mode::Compare) && second.is_none() && !arg.starts_with('-') => second = Some(arg),
+            _ => usage_error(&format!("unexpected argument {:?}", arg)),
         }
     }
-}
\ No newline at end of file
+
+    if let Some(quality) = quality {
+        config.quality = quality;
+    }
+    if quality.is_some() && !matches!(mode, Mode::Compress | Mode::Export) {
+        usage_error("--quality is only used with -c and export");
+    }
+    if encoder_given && !matches!(mode, Mode::Compress) {
+        usage_error("--layout, --transform, --entropy, --predict, --rle, --gray, --subsampling, --chroma-bits, --adaptive-chroma and --colorspace are only used with -c");
+    }
+    if target.is_some() && !matches!(mode, Mode::Compress) {
+        usage_error("targets are only used with -c");
+    }
+    if target.is_some() && quality.is_some() {
+        usage_error("a target picks the quality itself");
+    }
+    if decoder.deblock.is_some() && !matches!(mode, Mode::Decompress) {
+        usage_error("deblocking is only used with -d");
+    }
+    if decoder_given && !matches!(mode, Mode::Decompress) {
+        usage_error("--upsample, --dither and --report-clipping are only used with -d");
+    }
+    if max_rmse.is_some() && !matches!(mode, Mode::Compare) {
+        usage_error("--max-rmse is only used with compare");
+    }
+    if output.is_some() && matches!(mode, Mode::Compare) {
+        usage_error("compare prints to standard out");
+    }
+    if matches!(mode, Mode::Export) != jpeg {
+        usage_error(if jpeg { "--jpeg is only used with export" } else { "export needs an output format (--jpeg)" });
+    }
+
+    let result = match mode {
+        Mode::Compress => match target {
+            None => compress(filename, output, &config).map(|_| ()),
+            Some(Target::Size(target)) => compress_to_size(filename, output, &config, target, !coding_given)
+                .map(|(chosen, stats)| report_target(&chosen, &stats, None)),
+            Some(Target::Quality(target)) => compress_to_quality(filename, output, &config, target, !coding_given)
+                .map(|(chosen, stats, measured)| {
+                    let measured = match target {
+                        QualityTarget::Psnr(_) => format!("psnr {:.2} db", measured),
+                        QualityTarget::Ssim(_) => format!("ssim {:.4}", measured),
+                    };
+                    report_target(&chosen, &stats, Some(measured))
+                }),
+        },
+        Mode::Decompress => decompress(filename, output, &decoder).map(|stats| {
+            if report_clipping {
+                let [red, green, blue] = stats.clipped;
+                eprintln!("rpeg: clamped {} red, {} green and {} blue samples to the output range", red, green, blue);
+            }
+        }),
+        Mode::Export => export(filename, output, quality.unwrap_or(JPEG_QUALITY)).map(|_| ()),
+        Mode::Compare => match (filename, second) {
+            (Some(original), Some(reconstruction)) => compare(original, reconstruction).map(|comparison| {
+                print_comparison(&comparison);
+                if let Some(max_rmse) = max_rmse.filter(|&max_rmse| comparison.rmse > max_rmse) {
+                    eprintln!("rpeg: rmse {:.4} is above {}", comparison.rmse, max_rmse);
+                    process::exit(1);
+                }
+            }),
+            _ => usage_error("compare needs two images"),
+        },
+    };
+    if let Err(err) = result {
+        eprintln!("rpeg: {}", err);
+        process::exit(1);
+    }
+}
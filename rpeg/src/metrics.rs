@@ -0,0 +1,230 @@
+use array2::Array2;
+use csc411_image::Rgb;
+use crate::error::{RpegError, expect_dimensions};
+
+
+/*
+image quality metrics
+-------------------------
+compare a reconstruction to the image it was made from, both 8-bit rgb.
+rmse and psnr are over the squared error of each channel, or of all three
+together; identical images have an infinite psnr. ssim is the mean
+structural similarity (wang et al. 2004) of the two images' luma (bt.601
+weights), with an 11-tap gaussian window of standard deviation 1.5 clamped
+at the edges, so it runs from about 0 (unrelated) to 1 (identical).
+ms-ssim (wang et al. 2003) repeats the contrast and structure terms over
+five scales, halving the luma each time, with the published weights; small
+images use only the scales at least as big as the window
+*/
+
+
+// largest sample value
+const PEAK: f64 = 255.0;
+
+// ssim window radius and standard deviation
+const SSIM_RADIUS: usize = 5;
+const SSIM_SIGMA: f64 = 1.5;
+
+// ssim stabilizing constants, as fractions of the peak
+const SSIM_K1: f64 = 0.01;
+const SSIM_K2: f64 = 0.03;
+
+// weight of each ms-ssim scale, finest first
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+
+// every metric for one pair of images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    pub rmse: f64,
+    // red, green, blue
+    pub channel_psnr: [f64; 3],
+    pub psnr: f64,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+}
+
+
+// check that two images can be compared
+fn expect_same_size(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<(), RpegError> {
+    expect_dimensions("the second image", (a.width(), a.height()), (b.width(), b.height()))?;
+    if a.get_data().is_empty() {
+        return Err(RpegError::DimensionMismatch("cannot compare empty images".to_string()));
+    }
+    Ok(())
+}
+
+
+// mean squared error of the red, green and blue channels
+pub fn channel_mse(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<[f64; 3], RpegError> {
+    expect_same_size(a, b)?;
+    let mut squared = [0.0; 3];
+    for (x, y) in a.get_data().iter().zip(b.get_data()) {
+        for (total, (p, q)) in squared.iter_mut().zip([(x.red, y.red), (x.green, y.green), (x.blue, y.blue)]) {
+            *total += (p as f64 - q as f64).powi(2);
+        }
+    }
+    Ok(squared.map(|total| total / a.get_data().len() as f64))
+}
+
+
+// psnr in db for a mean squared error
+fn psnr_of_mse(mse: f64) -> f64 {
+    10.0 * (PEAK * PEAK / mse).log10()
+}
+
+
+// root mean squared error over all channels
+pub fn rmse(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<f64, RpegError> {
+    let mse = channel_mse(a, b)?;
+    Ok((mse.iter().sum::<f64>() / 3.0).sqrt())
+}
+
+
+// peak signal-to-noise ratio in db of the red, green and blue channels
+pub fn channel_psnr(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<[f64; 3], RpegError> {
+    Ok(channel_mse(a, b)?.map(psnr_of_mse))
+}
+
+
+// peak signal-to-noise ratio in db over all channels
+pub fn psnr(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<f64, RpegError> {
+    let mse = channel_mse(a, b)?;
+    Ok(psnr_of_mse(mse.iter().sum::<f64>() / 3.0))
+}
+
+
+// luma of every pixel, in row-major order
+fn luma_plane(pixels: &Array2<Rgb>) -> Vec<f64> {
+    pixels.get_data().iter()
+        .map(|p| 0.299 * p.red as f64 + 0.587 * p.green as f64 + 0.114 * p.blue as f64)
+        .collect()
+}
+
+
+// normalized gaussian weights for the ssim window
+fn ssim_window() -> Vec<f64> {
+    let weights: Vec<f64> = (0..=2 * SSIM_RADIUS)
+        .map(|i| {
+            let x = i as f64 - SSIM_RADIUS as f64;
+            (-x * x / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+        })
+        .collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / total).collect()
+}
+
+
+// blur a row-major plane with a separable window, clamping reads at the edges
+fn blur(plane: &[f64], width: usize, height: usize, window: &[f64]) -> Vec<f64> {
+    let radius = window.len() / 2;
+    let clamp = |i: usize, n: usize| (i.saturating_sub(radius)).min(n - 1);
+    let mut rows = vec![0.0; plane.len()];
+    for r in 0..height {
+        for c in 0..width {
+            rows[r * width + c] = window.iter().enumerate()
+                .map(|(k, w)| w * plane[r * width + clamp(c + k, width)])
+                .sum();
+        }
+    }
+    let mut blurred = vec![0.0; plane.len()];
+    for r in 0..height {
+        for c in 0..width {
+            blurred[r * width + c] = window.iter().enumerate()
+                .map(|(k, w)| w * rows[clamp(r + k, height) * width + c])
+                .sum();
+        }
+    }
+    blurred
+}
+
+
+// mean ssim, and mean of its contrast and structure terms alone, of two luma planes
+fn ssim_terms(x: &[f64], y: &[f64], width: usize, height: usize) -> (f64, f64) {
+    let window = ssim_window();
+    let product = |p: &[f64], q: &[f64]| p.iter().zip(q).map(|(s, t)| s * t).collect::<Vec<f64>>();
+
+    let mean_x = blur(x, width, height, &window);
+    let mean_y = blur(y, width, height, &window);
+    let mean_xx = blur(&product(x, x), width, height, &window);
+    let mean_yy = blur(&product(y, y), width, height, &window);
+    let mean_xy = blur(&product(x, y), width, height, &window);
+
+    let c1 = (SSIM_K1 * PEAK).powi(2);
+    let c2 = (SSIM_K2 * PEAK).powi(2);
+    let (mut total, mut total_cs) = (0.0, 0.0);
+    for i in 0..x.len() {
+        let (mx, my) = (mean_x[i], mean_y[i]);
+        let var_x = mean_xx[i] - mx * mx;
+        let var_y = mean_yy[i] - my * my;
+        let covariance = mean_xy[i] - mx * my;
+        let cs = (2.0 * covariance + c2) / (var_x + var_y + c2);
+        total_cs += cs;
+        total += cs * (2.0 * mx * my + c1) / (mx * mx + my * my + c1);
+    }
+    (total / x.len() as f64, total_cs / x.len() as f64)
+}
+
+
+// mean structural similarity of the luma of two images
+pub fn ssim(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<f64, RpegError> {
+    expect_same_size(a, b)?;
+    Ok(ssim_terms(&luma_plane(a), &luma_plane(b), a.width(), a.height()).0)
+}
+
+
+// halve a row-major plane by averaging 2x2 squares, dropping an odd last row or column
+fn downsample(plane: &[f64], width: usize, height: usize) -> (Vec<f64>, usize, usize) {
+    let (half_width, half_height) = (width / 2, height / 2);
+    let mut halved = Vec::with_capacity(half_width * half_height);
+    for r in 0..half_height {
+        for c in 0..half_width {
+            let at = |dc: usize, dr: usize| plane[(2 * r + dr) * width + 2 * c + dc];
+            halved.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0);
+        }
+    }
+    (halved, half_width, half_height)
+}
+
+
+// multi-scale structural similarity of the luma of two images
+pub fn ms_ssim(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<f64, RpegError> {
+    expect_same_size(a, b)?;
+    let (mut x, mut y) = (luma_plane(a), luma_plane(b));
+    let (mut width, mut height) = (a.width(), a.height());
+
+    // the coarsest scale still as big as the window, and at least the first
+    let window = 2 * SSIM_RADIUS + 1;
+    let scales = (1..MS_SSIM_WEIGHTS.len())
+        .take_while(|&scale| width.min(height) >> scale >= window)
+        .count() + 1;
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let weight_total: f64 = weights.iter().sum();
+
+    let mut similarity = 1.0;
+    for (scale, weight) in weights.iter().enumerate() {
+        let (ssim, cs) = ssim_terms(&x, &y, width, height);
+        // luminance only counts at the coarsest scale
+        let term = if scale + 1 == scales { ssim } else { cs };
+        similarity *= term.max(0.0).powf(weight / weight_total);
+        if scale + 1 < scales {
+            (x, _, _) = downsample(&x, width, height);
+            (y, width, height) = downsample(&y, width, height);
+        }
+    }
+    Ok(similarity)
+}
+
+
+// every metric of `b` measured against `a`
+pub fn compare(a: &Array2<Rgb>, b: &Array2<Rgb>) -> Result<Comparison, RpegError> {
+    let mse = channel_mse(a, b)?;
+    let total = mse.iter().sum::<f64>() / 3.0;
+    Ok(Comparison {
+        rmse: total.sqrt(),
+        channel_psnr: mse.map(psnr_of_mse),
+        psnr: psnr_of_mse(total),
+        ssim: ssim(a, b)?,
+        ms_ssim: ms_ssim(a, b)?,
+    })
+}
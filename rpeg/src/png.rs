@@ -0,0 +1,351 @@
+use std::io::Write;
+use csc411_image::{RgbImage, Rgb};
+use crate::bitio::BitReader;
+use crate::checksum::{crc32, crc32_update};
+use crate::deflate::{zlib_compress, zlib_decompress};
+use crate::error::RpegError;
+
+
+/*
+png reading and writing
+-------------------------
+a png file is an 8 byte signature followed by chunks, each a 4 byte
+length, a 4 byte type, the data, and a crc32 of the type and data
+
+reading accepts every standard color type and bit depth:
+- 0 grayscale, 1/2/4/8/16 bits
+- 2 rgb, 8/16 bits
+- 3 palette, 1/2/4/8 bits (PLTE entries are 8-bit rgb)
+- 4 grayscale with alpha, 8/16 bits
+- 6 rgb with alpha, 8/16 bits
+in either scanline order or adam7 interlacing. alpha (and tRNS) is
+dropped, and gray is copied into all three channels. the image keeps the
+file's sample range: its denominator is 2^depth - 1, or 255 for palettes
+
+the IDAT data is one zlib stream of scanlines, each a filter type byte
+(none, sub, up, average, paeth) followed by the filtered bytes of the
+row; sub-byte samples are packed from the high bit down
+
+writing produces 8-bit rgb for images with a denominator up to 255 and
+16-bit rgb above that, not interlaced, each row with whichever filter
+leaves the smallest sum of absolute (signed) bytes
+*/
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// adam7 passes: first column, first row, column step, row step
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+
+// the fields of an IHDR chunk that matter for decoding
+struct PngHeader {
+    width: usize,
+    height: usize,
+    depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.depth as usize
+    }
+
+    // the passes of an interlaced image, or the whole image as one pass
+    fn passes(&self) -> Vec<(usize, usize, usize, usize)> {
+        if self.interlaced { ADAM7.to_vec() } else { vec![(0, 0, 1, 1)] }
+    }
+
+    // bytes of filtered scanlines the image needs, if that can be counted
+    fn scanline_bytes(&self) -> Option<usize> {
+        self.passes().into_iter().try_fold(0usize, |total, (c0, r0, dc, dr)| {
+            let columns = self.width.saturating_sub(c0).div_ceil(dc);
+            let rows = self.height.saturating_sub(r0).div_ceil(dr);
+            if columns == 0 || rows == 0 {
+                return Some(total);
+            }
+            let row_bytes = columns.checked_mul(self.bits_per_pixel())?.div_ceil(8);
+            total.checked_add(rows.checked_mul(row_bytes.checked_add(1)?)?)
+        })
+    }
+}
+
+
+// whether data starts like a png file
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(&SIGNATURE)
+}
+
+
+// check an IHDR chunk and the combination of color type and depth it gives
+fn read_ihdr(data: &[u8]) -> Result<PngHeader, RpegError> {
+    if data.len() != 13 {
+        return Err(RpegError::MalformedHeader("png IHDR chunk has the wrong length".to_string()));
+    }
+    let width = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    let (depth, color_type) = (data[8], data[9]);
+    if width == 0 || height == 0 {
+        return Err(RpegError::MalformedHeader("png has no pixels".to_string()));
+    }
+    let depth_allowed = match color_type {
+        0 => [1, 2, 4, 8, 16].contains(&depth),
+        3 => [1, 2, 4, 8].contains(&depth),
+        2 | 4 | 6 => [8, 16].contains(&depth),
+        _ => return Err(RpegError::UnsupportedFormat(format!("png color type {}", color_type))),
+    };
+    if !depth_allowed {
+        return Err(RpegError::UnsupportedFormat(format!("png color type {} with bit depth {}", color_type, depth)));
+    }
+    if data[10] != 0 || data[11] != 0 || data[12] > 1 {
+        return Err(RpegError::UnsupportedFormat("png compression, filter or interlace method".to_string()));
+    }
+    Ok(PngHeader { width, height, depth, color_type, interlaced: data[12] == 1 })
+}
+
+
+// the paeth predictor: whichever of left, up and up-left is closest to left + up - up-left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+
+// undo the filters of a pass's scanlines
+// input: the filtered rows (each with its filter byte), bytes per row, bytes per pixel (at least 1)
+// output: the raw rows, concatenated
+fn unfilter(data: &[u8], row_bytes: usize, rows: usize, pixel_bytes: usize) -> Result<Vec<u8>, RpegError> {
+    let mut raw = vec![0u8; row_bytes * rows];
+    for r in 0..rows {
+        let filter = data[r * (row_bytes + 1)];
+        let line = &data[r * (row_bytes + 1) + 1..(r + 1) * (row_bytes + 1)];
+        for i in 0..row_bytes {
+            let left = if i >= pixel_bytes { raw[r * row_bytes + i - pixel_bytes] } else { 0 };
+            let up = if r > 0 { raw[(r - 1) * row_bytes + i] } else { 0 };
+            let up_left = if r > 0 && i >= pixel_bytes { raw[(r - 1) * row_bytes + i - pixel_bytes] } else { 0 };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(RpegError::MalformedHeader(format!("png scanline has unknown filter type {}", filter))),
+            };
+            raw[r * row_bytes + i] = line[i].wrapping_add(prediction);
+        }
+    }
+    Ok(raw)
+}
+
+
+// read a png image
+// output: RgbImage at the file's sample depth
+pub fn read_png(bytes: &[u8]) -> Result<RgbImage, RpegError> {
+
+    if !is_png(bytes) {
+        return Err(RpegError::UnsupportedFormat("input is not a png image".to_string()));
+    }
+
+    let mut header: Option<PngHeader> = None;
+    let mut palette: Vec<Rgb> = Vec::new();
+    let mut compressed = Vec::new();
+    let mut pos = SIGNATURE.len();
+    loop {
+        let truncated = || RpegError::MalformedHeader("png chunk runs past the end of the file".to_string());
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+        let kind = bytes.get(pos + 4..pos + 8).ok_or_else(truncated)?;
+        let data = bytes.get(pos + 8..pos + 8 + length).ok_or_else(truncated)?;
+        let crc = bytes.get(pos + 8 + length..pos + 12 + length).ok_or_else(truncated)?;
+        if u32::from_be_bytes(crc.try_into().unwrap()) != crc32_update(crc32(kind), data) {
+            return Err(RpegError::MalformedHeader(format!("png {} chunk fails its crc", String::from_utf8_lossy(kind))));
+        }
+        pos += 12 + length;
+
+        match kind {
+            b"IHDR" => header = Some(read_ihdr(data)?),
+            b"PLTE" => {
+                if !data.len().is_multiple_of(3) || data.len() > 256 * 3 {
+                    return Err(RpegError::MalformedHeader("png PLTE chunk has the wrong length".to_string()));
+                }
+                palette = data.chunks_exact(3)
+                    .map(|entry| Rgb { red: entry[0] as u16, green: entry[1] as u16, blue: entry[2] as u16 })
+                    .collect();
+            }
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            // chunks with an uppercase first letter are critical and cannot be skipped
+            _ if kind[0].is_ascii_uppercase() => {
+                return Err(RpegError::UnsupportedFormat(format!("png {} chunk", String::from_utf8_lossy(kind))));
+            }
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or_else(|| RpegError::MalformedHeader("png has no IHDR chunk".to_string()))?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(RpegError::MalformedHeader("palette png has no PLTE chunk".to_string()));
+    }
+
+    // the dimensions come from the file, so check that the pixel data is really
+    // there before making room for the pixels
+    let too_large = || RpegError::MalformedHeader(format!("png of {}x{} is too large", header.width, header.height));
+    let expected = header.scanline_bytes().ok_or_else(too_large)?;
+    let data = zlib_decompress(&compressed, expected)?;
+    if data.len() < expected {
+        return Err(RpegError::DimensionMismatch(format!("png has too little pixel data for {}x{}", header.width, header.height)));
+    }
+
+    let pixel_bytes = header.bits_per_pixel().div_ceil(8);
+    let mut pixels = vec![Rgb { red: 0, green: 0, blue: 0 }; header.width.checked_mul(header.height).ok_or_else(too_large)?];
+    let mut pos = 0;
+    for (c0, r0, dc, dr) in header.passes() {
+        let columns = header.width.saturating_sub(c0).div_ceil(dc);
+        let rows = header.height.saturating_sub(r0).div_ceil(dr);
+        if columns == 0 || rows == 0 {
+            continue;
+        }
+        let row_bytes = (columns * header.bits_per_pixel()).div_ceil(8);
+        let filtered = data.get(pos..pos + rows * (row_bytes + 1)).ok_or_else(|| {
+            RpegError::DimensionMismatch(format!("png has too little pixel data for {}x{}", header.width, header.height))
+        })?;
+        pos += rows * (row_bytes + 1);
+        let raw = unfilter(filtered, row_bytes, rows, pixel_bytes)?;
+
+        for r in 0..rows {
+            let mut reader = BitReader::new(&raw[r * row_bytes..(r + 1) * row_bytes]);
+            for c in 0..columns {
+                let mut samples = [0u16; 4];
+                for sample in samples.iter_mut().take(header.channels()) {
+                    *sample = reader.read_unsigned(header.depth as u64)? as u16;
+                }
+                let pixel = match header.color_type {
+                    0 | 4 => Rgb { red: samples[0], green: samples[0], blue: samples[0] },
+                    3 => palette.get(samples[0] as usize).ok_or_else(|| {
+                        RpegError::MalformedHeader(format!("png pixel uses palette entry {} of {}", samples[0], palette.len()))
+                    })?.clone(),
+                    _ => Rgb { red: samples[0], green: samples[1], blue: samples[2] },
+                };
+                pixels[(r0 + r * dr) * header.width + c0 + c * dc] = pixel;
+            }
+        }
+    }
+
+    Ok(RgbImage {
+        pixels,
+        width: header.width as u32,
+        height: header.height as u32,
+        denominator: if header.color_type == 3 { 255 } else { ((1u32 << header.depth) - 1) as u16 },
+    })
+}
+
+
+// append a chunk: length, type, data and the crc of type and data
+fn push_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+    bytes.extend_from_slice(&crc32_update(crc32(kind), data).to_be_bytes());
+}
+
+
+// filter a row with each filter type and keep the one with the smallest sum
+// of absolute values, read as signed bytes
+fn filter_row(row: &[u8], previous: &[u8], pixel_bytes: usize, output: &mut Vec<u8>) {
+    let mut best: Option<(u64, u8, Vec<u8>)> = None;
+    for filter in 0..5u8 {
+        let filtered: Vec<u8> = (0..row.len())
+            .map(|i| {
+                let left = if i >= pixel_bytes { row[i - pixel_bytes] } else { 0 };
+                let up_left = if i >= pixel_bytes { previous[i - pixel_bytes] } else { 0 };
+                let prediction = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => previous[i],
+                    3 => ((left as u16 + previous[i] as u16) / 2) as u8,
+                    _ => paeth(left, previous[i], up_left),
+                };
+                row[i].wrapping_sub(prediction)
+            })
+            .collect();
+        let cost = filtered.iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum();
+        if best.as_ref().is_none_or(|(best_cost, _, _)| cost < *best_cost) {
+            best = Some((cost, filter, filtered));
+        }
+    }
+    let (_, filter, filtered) = best.unwrap();
+    output.push(filter);
+    output.extend(filtered);
+}
+
+
+// write an image as a png, 8 or 16 bits per sample depending on its denominator
+pub fn write_png<W: Write>(writer: &mut W, img: &RgbImage) -> Result<(), RpegError> {
+
+    let (width, height) = (img.width as usize, img.height as usize);
+    if width == 0 || height == 0 || img.pixels.len() != width * height {
+        return Err(RpegError::DimensionMismatch(format!(
+            "expected {} pixels for a {}x{} png, found {}",
+            width * height, width, height, img.pixels.len()
+        )));
+    }
+    // samples are rescaled to the full range of the depth written
+    let denominator = img.denominator.max(1) as u32;
+    let (depth, maxval): (u8, u32) = if denominator <= 255 { (8, 255) } else { (16, 65535) };
+    let scale = |sample: u16| ((sample.min(denominator as u16) as u32 * maxval + denominator / 2) / denominator) as u16;
+
+    let pixel_bytes = 3 * depth as usize / 8;
+    let row_bytes = width * pixel_bytes;
+    let mut filtered = Vec::with_capacity((row_bytes + 1) * height);
+    let mut previous = vec![0u8; row_bytes];
+    for row_pixels in img.pixels.chunks_exact(width) {
+        let mut row = Vec::with_capacity(row_bytes);
+        for pixel in row_pixels {
+            for sample in [pixel.red, pixel.green, pixel.blue] {
+                if depth == 8 {
+                    row.push(scale(sample) as u8);
+                } else {
+                    row.extend_from_slice(&scale(sample).to_be_bytes());
+                }
+            }
+        }
+        filter_row(&row, &previous, pixel_bytes, &mut filtered);
+        previous = row;
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[depth, 2, 0, 0, 0]);
+
+    let mut bytes = SIGNATURE.to_vec();
+    push_chunk(&mut bytes, b"IHDR", &ihdr);
+    push_chunk(&mut bytes, b"IDAT", &zlib_compress(&filtered));
+    push_chunk(&mut bytes, b"IEND", &[]);
+    writer.write_all(&bytes)?;
+    Ok(())
+}
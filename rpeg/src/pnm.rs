@@ -0,0 +1,234 @@
+use std::io::{Read, Write};
+use csc411_image::{Gray, GrayImage, RgbImage, Rgb};
+use crate::error::RpegError;
+
+
+/*
+netpbm (ppm and pgm) reading and writing over arbitrary readers and writers
+-------------------------
+csc411_image can only read from a filename or stdin, so the library api
+parses netpbm data itself. both the binary (P6, P5) and plain (P3, P2)
+variants are accepted, with one byte per sample when maxval < 256 and two
+big-endian bytes per sample otherwise.
+*/
+
+
+// skip whitespace and comments in a pnm header, returning the position of the next token
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() {
+        if bytes[pos] == b'#' {
+            // comments run until the end of the line
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+        } else if bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+
+// read an ascii decimal number from a pnm header
+// output: (value, position after the number)
+fn read_number(bytes: &[u8], pos: usize) -> Result<(usize, usize), RpegError> {
+    let start = skip_whitespace(bytes, pos);
+    let mut end = start;
+    let mut value: usize = 0;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((bytes[end] - b'0') as usize))
+            .ok_or_else(|| RpegError::MalformedHeader("number in header is too large".to_string()))?;
+        end += 1;
+    }
+    if end == start {
+        return Err(RpegError::MalformedHeader("expected a number in pnm header".to_string()));
+    }
+    Ok((value, end))
+}
+
+
+// whether data starts with the magic number of a binary or plain pgm image
+pub fn is_pgm(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"P5") || bytes.starts_with(b"P2")
+}
+
+
+// read a pnm image whose binary and plain magic numbers are `binary` and `plain`
+// output: (width, height, maxval, samples), with `channels` samples per pixel
+fn read_pnm<R: Read>(reader: &mut R, binary: u8, plain: u8, channels: usize) -> Result<(usize, usize, usize, Vec<u16>), RpegError> {
+
+    // read the whole contents
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let kind = if channels == 1 { "pgm" } else { "ppm" };
+    if bytes.len() < 2 || bytes[0] != b'P' {
+        return Err(RpegError::UnsupportedFormat(format!("input is not a {} image", kind)));
+    }
+    let is_plain = match bytes[1] {
+        variant if variant == binary => false,
+        variant if variant == plain => true,
+        _ => return Err(RpegError::UnsupportedFormat(format!("unsupported pnm variant P{} for a {} image", bytes[1] as char, kind))),
+    };
+
+    // width, height and maxval follow the magic number
+    let (width, pos) = read_number(&bytes, 2)?;
+    let (height, pos) = read_number(&bytes, pos)?;
+    let (maxval, pos) = read_number(&bytes, pos)?;
+    if maxval == 0 || maxval > 65535 {
+        return Err(RpegError::MalformedHeader(format!("invalid maxval {}", maxval)));
+    }
+
+    // images keep their dimensions as u32, and the sample count must not overflow
+    let count = u32::try_from(width).and(u32::try_from(height)).ok()
+        .and_then(|_| width.checked_mul(height))
+        .and_then(|pixels| pixels.checked_mul(channels))
+        .ok_or_else(|| RpegError::MalformedHeader(format!("a {}x{} image is too large", width, height)))?;
+    let samples = read_samples(&bytes, pos, count, maxval, is_plain)?;
+    Ok((width, height, maxval, samples))
+}
+
+
+// read a ppm image from any reader
+pub fn read_ppm<R: Read>(reader: &mut R) -> Result<RgbImage, RpegError> {
+
+    let (width, height, maxval, samples) = read_pnm(reader, b'6', b'3', 3)?;
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|s| Rgb { red: s[0], green: s[1], blue: s[2] })
+        .collect();
+
+    Ok(RgbImage {
+        pixels,
+        width: width as u32,
+        height: height as u32,
+        denominator: maxval as u16,
+    })
+}
+
+
+// read a pgm image from any reader
+pub fn read_pgm<R: Read>(reader: &mut R) -> Result<GrayImage, RpegError> {
+
+    let (width, height, maxval, samples) = read_pnm(reader, b'5', b'2', 1)?;
+    Ok(GrayImage {
+        pixels: samples.into_iter().map(|value| Gray { value }).collect(),
+        width: width as u32,
+        height: height as u32,
+        denominator: maxval as u16,
+    })
+}
+
+
+// read `count` samples of pixel data starting just after the header
+fn read_samples(bytes: &[u8], pos: usize, count: usize, maxval: usize, plain: bool) -> Result<Vec<u16>, RpegError> {
+
+    if plain {
+        // plain samples are whitespace separated ascii numbers, so each takes
+        // at least two bytes; the header's count alone is not to be trusted
+        let mut samples = Vec::with_capacity(count.min(bytes.len() / 2));
+        let mut pos = pos;
+        for _ in 0..count {
+            let (value, next) = read_number(bytes, pos).map_err(|_| {
+                RpegError::DimensionMismatch(format!("expected {} samples of pixel data", count))
+            })?;
+            samples.push(value.min(maxval) as u16);
+            pos = next;
+        }
+        return Ok(samples);
+    }
+
+    // a single whitespace character separates the header from the raster
+    let start = pos + 1;
+    let bytes_per_sample = if maxval < 256 { 1 } else { 2 };
+    let raster = bytes.get(start..).unwrap_or(&[]);
+    let raster_len = count.checked_mul(bytes_per_sample)
+        .ok_or_else(|| RpegError::MalformedHeader(format!("{} samples of pixel data is too many", count)))?;
+    if raster.len() < raster_len {
+        return Err(RpegError::DimensionMismatch(format!(
+            "expected {} bytes of pixel data, found {}",
+            raster_len,
+            raster.len()
+        )));
+    }
+
+    let mut samples = Vec::with_capacity(count);
+    for i in 0..count {
+        let value = if bytes_per_sample == 1 {
+            raster[i] as u16
+        } else {
+            u16::from_be_bytes([raster[2 * i], raster[2 * i + 1]])
+        };
+        samples.push(value.min(maxval as u16));
+    }
+    Ok(samples)
+}
+
+
+// append one raster sample, clamped to maxval, in the width maxval calls for
+fn push_sample(bytes: &mut Vec<u8>, sample: u16, maxval: u16) {
+    let sample = sample.min(maxval);
+    if maxval < 256 {
+        bytes.push(sample as u8);
+    } else {
+        bytes.extend_from_slice(&sample.to_be_bytes());
+    }
+}
+
+
+// write a binary (P6) ppm image to any writer
+pub fn write_ppm<W: Write>(writer: &mut W, img: &RgbImage) -> Result<(), RpegError> {
+
+    let maxval = img.denominator.max(1);
+    let mut bytes = format!("P6\n{} {}\n{}\n", img.width, img.height, maxval).into_bytes();
+
+    for pixel in img.pixels.iter() {
+        for sample in [pixel.red, pixel.green, pixel.blue] {
+            push_sample(&mut bytes, sample, maxval);
+        }
+    }
+
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+
+// write a binary (P5) pgm image to any writer
+pub fn write_pgm<W: Write>(writer: &mut W, img: &GrayImage) -> Result<(), RpegError> {
+
+    let maxval = img.denominator.max(1);
+    let mut bytes = format!("P5\n{} {}\n{}\n", img.width, img.height, maxval).into_bytes();
+
+    for pixel in img.pixels.iter() {
+        push_sample(&mut bytes, pixel.value, maxval);
+    }
+
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+
+// widen a gray image to rgb with equal channels
+pub fn gray_to_rgb(img: GrayImage) -> RgbImage {
+    RgbImage {
+        pixels: img.pixels.iter().map(|pixel| Rgb { red: pixel.value, green: pixel.value, blue: pixel.value }).collect(),
+        width: img.width,
+        height: img.height,
+        denominator: img.denominator,
+    }
+}
+
+
+// narrow an rgb image whose channels are equal (such as a decoded grayscale image) to gray
+pub fn rgb_to_gray(img: &RgbImage) -> GrayImage {
+    GrayImage {
+        pixels: img.pixels.iter().map(|pixel| Gray { value: pixel.green }).collect(),
+        width: img.width,
+        height: img.height,
+        denominator: img.denominator,
+    }
+}
@@ -0,0 +1,273 @@
+use crate::error::RpegError;
+
+
+/*
+adaptive range coding of symbol streams
+-------------------------
+a binary range coder in the style of lzma: 32-bit range, 11-bit bit
+probabilities that move 1/32 of the way towards each coded bit, and
+carries propagated through a cached byte. the coder is all integer
+arithmetic, so encoder and decoder stay in step on every platform
+
+each stream has its own model. a value v is coded as
+- is v zero?
+- if not: the sign, then the bit length k of |v| in unary
+  (k-1 ones and a terminating zero, omitted at k = 64), then the k-1 bits
+  of |v| below its leading one, most significant first
+the zero and length bits are chosen by the size of the previous value
+in the same stream (zero, 1-3, or larger), and every mantissa bit has
+its own probability per bit length and position
+
+streams are coded one after another through the same coder
+*/
+
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_ONE: u16 = 1 << PROBABILITY_BITS;
+const ADAPT_SHIFT: u32 = 5;
+const TOP: u32 = 1 << 24;
+const CONTEXTS: usize = 3;
+
+
+// probabilities (that the next bit is 0) for coding the values of one stream
+struct ValueModel {
+    zero: [u16; CONTEXTS],
+    sign: u16,
+    // length[ctx][i] codes whether the bit length is more than i
+    length: [[u16; 65]; CONTEXTS],
+    // mantissa[k][b] codes bit b of a value with bit length k
+    mantissa: Vec<[u16; 64]>,
+    // context chosen by the previous value
+    context: usize,
+}
+
+impl ValueModel {
+
+    fn new() -> Self {
+        ValueModel {
+            zero: [PROBABILITY_ONE / 2; CONTEXTS],
+            sign: PROBABILITY_ONE / 2,
+            length: [[PROBABILITY_ONE / 2; 65]; CONTEXTS],
+            mantissa: vec![[PROBABILITY_ONE / 2; 64]; 65],
+            context: 0,
+        }
+    }
+
+    // remember the size of a value for choosing the next context
+    fn update_context(&mut self, length: u32) {
+        self.context = match length {
+            0 => 0,
+            1 | 2 => 1,
+            _ => 2,
+        };
+    }
+}
+
+
+// bit length of a magnitude, 0 for 0
+fn bit_length(magnitude: u64) -> u32 {
+    64 - magnitude.leading_zeros()
+}
+
+
+// range encoder writing to a byte vector
+pub struct RangeEncoder {
+    bytes: Vec<u8>,
+    low: u64,
+    range: u32,
+    // the last byte not yet written, which a carry may still increment,
+    // and how many bytes (it plus pending 0xff bytes) are held back
+    cache: u8,
+    cache_size: u64,
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        RangeEncoder { bytes: Vec::new(), low: 0, range: u32::MAX, cache: 0, cache_size: 1 }
+    }
+}
+
+impl RangeEncoder {
+
+    pub fn new() -> Self {
+        RangeEncoder::default()
+    }
+
+    fn shift_low(&mut self) {
+        // hold bytes back while a carry could still ripple into them
+        if self.low < 0xFF00_0000 || self.low > 0xFFFF_FFFF {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.bytes.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    // code one bit with an adaptive probability
+    fn encode_bit(&mut self, probability: &mut u16, bit: bool) {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += (PROBABILITY_ONE - *probability) >> ADAPT_SHIFT;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    // code one value of a stream with that stream's model
+    fn encode_value(&mut self, model: &mut ValueModel, value: i64) {
+        let context = model.context;
+        let magnitude = value.unsigned_abs();
+        let length = bit_length(magnitude);
+        model.update_context(length);
+
+        self.encode_bit(&mut model.zero[context], value == 0);
+        if value == 0 {
+            return;
+        }
+        self.encode_bit(&mut model.sign, value < 0);
+        for i in 1..length {
+            self.encode_bit(&mut model.length[context][i as usize], true);
+        }
+        if length < 64 {
+            self.encode_bit(&mut model.length[context][length as usize], false);
+        }
+        for b in (0..length - 1).rev() {
+            self.encode_bit(&mut model.mantissa[length as usize][b as usize], (magnitude >> b) & 1 == 1);
+        }
+    }
+
+    // flush the state and return everything written
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.bytes
+    }
+}
+
+
+// range decoder reading from a byte slice
+pub struct RangeDecoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    code: u32,
+    range: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+
+    pub fn new(bytes: &'a [u8]) -> Result<Self, RpegError> {
+        let mut decoder = RangeDecoder { bytes, pos: 0, code: 0, range: u32::MAX };
+        // the first byte is always the encoder's initial empty cache
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte()? as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, RpegError> {
+        let byte = *self.bytes.get(self.pos)
+            .ok_or_else(|| RpegError::DimensionMismatch("range coded payload ended early".to_string()))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn decode_bit(&mut self, probability: &mut u16) -> Result<bool, RpegError> {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *probability += (PROBABILITY_ONE - *probability) >> ADAPT_SHIFT;
+            false
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+            true
+        };
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte()? as u32;
+        }
+        Ok(bit)
+    }
+
+    fn decode_value(&mut self, model: &mut ValueModel) -> Result<i64, RpegError> {
+        let context = model.context;
+        if self.decode_bit(&mut model.zero[context])? {
+            model.update_context(0);
+            return Ok(0);
+        }
+        let negative = self.decode_bit(&mut model.sign)?;
+        let mut length = 1u32;
+        while length < 64 && self.decode_bit(&mut model.length[context][length as usize])? {
+            length += 1;
+        }
+        let mut magnitude = 1u64;
+        for b in (0..length - 1).rev() {
+            magnitude = (magnitude << 1) | self.decode_bit(&mut model.mantissa[length as usize][b as usize])? as u64;
+        }
+        model.update_context(length);
+        // a magnitude of 2^63 only arises from i64::MIN, which wraps back to itself
+        Ok(if negative { (magnitude as i64).wrapping_neg() } else { magnitude as i64 })
+    }
+
+    // check that the whole payload was used
+    pub fn expect_end(&self) -> Result<(), RpegError> {
+        if self.pos != self.bytes.len() {
+            return Err(RpegError::DimensionMismatch(format!(
+                "expected {} bytes of payload, found {}",
+                self.pos,
+                self.bytes.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+
+// range code several streams, each with its own adaptive model
+pub fn encode_streams(streams: &[Vec<i64>]) -> Vec<u8> {
+    let mut encoder = RangeEncoder::new();
+    for values in streams {
+        let mut model = ValueModel::new();
+        for value in values {
+            encoder.encode_value(&mut model, *value);
+        }
+    }
+    encoder.finish()
+}
+
+
+// decode streams written by `encode_streams`, given how many values each holds
+pub fn decode_streams(bytes: &[u8], counts: &[usize]) -> Result<Vec<Vec<i64>>, RpegError> {
+    let mut decoder = RangeDecoder::new(bytes)?;
+    let mut streams = Vec::with_capacity(counts.len());
+    for &count in counts {
+        let mut model = ValueModel::new();
+        // the counts come from the header, and a highly predictable stream can pack
+        // many values into each byte, so only reserve what the payload surely holds
+        // and let a short payload fail when it runs out
+        let mut values = Vec::with_capacity(count.min(bytes.len()));
+        for _ in 0..count {
+            values.push(decoder.decode_value(&mut model)?);
+        }
+        streams.push(values);
+    }
+    decoder.expect_end()?;
+    Ok(streams)
+}
@@ -40,7 +40,7 @@ pub struct Quantized {
     pub pr_avg: f32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(dead_code)]
 pub struct EncodedQuanta {
     pub a: u64,
@@ -49,4 +49,288 @@ pub struct EncodedQuanta {
     pub d: i64, 
     pub pb_avg: u64, 
     pub pr_avg: u64,
-}
\ No newline at end of file
+}
+
+
+// parameters of the coefficient quantizer, recorded in the file header
+// a is stored as round(a * a_scale); b, c and d are clamped to
+// [-bcd_range, bcd_range] and mapped onto [-bcd_levels, bcd_levels]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantParams {
+    pub a_scale: u64,
+    pub bcd_range: f32,
+    pub bcd_levels: i64,
+}
+
+impl Default for QuantParams {
+    // the parameters every rpeg file used before quality was configurable
+    fn default() -> Self {
+        QuantParams {
+            a_scale: 511,
+            bcd_range: 0.3,
+            bcd_levels: 15,
+        }
+    }
+}
+
+
+// quantizer of the pb/pr averages, recorded in the file header
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ChromaQuantizer {
+    // the 16 non-uniform levels of csc411_arith's index_of_chroma, 4-bit indices
+    #[default]
+    Csc411,
+    // 2^bits - 1 levels evenly spaced from -0.5 to 0.5, so gray (zero) is exact
+    Uniform { bits: u64 },
+    // up to 2^bits levels for each of pb and pr, fitted to one image, in increasing order
+    Adaptive { bits: u64, pb_levels: Vec<f32>, pr_levels: Vec<f32> },
+}
+
+// range of bits per chroma index the built-in quantizers accept
+pub const CHROMA_BITS: std::ops::RangeInclusive<u64> = 4..=8;
+
+impl ChromaQuantizer {
+
+    // bits in each chroma index
+    pub fn bits(&self) -> u64 {
+        match self {
+            ChromaQuantizer::Csc411 => 4,
+            ChromaQuantizer::Uniform { bits } | ChromaQuantizer::Adaptive { bits, .. } => *bits,
+        }
+    }
+
+    // rough distance between neighbouring levels: chroma spans [-0.5, 0.5]
+    pub fn step(&self) -> f32 {
+        1.0 / (1u64 << self.bits()) as f32
+    }
+
+    // index of the level closest to a pb (pr = false) or pr average
+    pub fn index_of(&self, value: f32, pr: bool) -> u64 {
+        match self {
+            ChromaQuantizer::Csc411 => csc411_arith::index_of_chroma(value) as u64,
+            ChromaQuantizer::Uniform { bits } => {
+                let top = ((1 << bits) - 2) as f32;
+                ((value + 0.5) * top).round().clamp(0.0, top) as u64
+            }
+            ChromaQuantizer::Adaptive { pb_levels, pr_levels, .. } => {
+                let levels = if pr { pr_levels } else { pb_levels };
+                // levels are sorted, so the closest is next to the insertion point
+                let upper = levels.partition_point(|level| *level < value);
+                if upper == 0 {
+                    0
+                } else if upper == levels.len() || value - levels[upper - 1] <= levels[upper] - value {
+                    (upper - 1) as u64
+                } else {
+                    upper as u64
+                }
+            }
+        }
+    }
+
+    // the pb (pr = false) or pr average an index stands for
+    // output: None if the index has no level
+    pub fn value_of(&self, index: u64, pr: bool) -> Option<f32> {
+        match self {
+            ChromaQuantizer::Csc411 => (index < 16).then(|| csc411_arith::chroma_of_index(index as usize)),
+            ChromaQuantizer::Uniform { bits } => {
+                let top = (1 << bits) - 2;
+                (index <= top).then(|| index as f32 / top as f32 - 0.5)
+            }
+            ChromaQuantizer::Adaptive { pb_levels, pr_levels, .. } => {
+                let levels = if pr { pr_levels } else { pb_levels };
+                levels.get(index as usize).copied()
+            }
+        }
+    }
+}
+
+
+// color matrix used to convert between rgb and y/pb/pr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    // standard definition video and jpeg
+    #[default]
+    Bt601,
+    // high definition video
+    Bt709,
+    // ultra high definition video
+    Bt2020,
+    // luma, orange and green chroma (kept in pb and pr), from adds and shifts
+    YCoCg,
+}
+
+
+impl ColorSpace {
+
+    // weights of red and blue in luma, for the y'cbcr matrices; None for ycocg
+    pub fn luma_weights(self) -> Option<(f32, f32)> {
+        match self {
+            ColorSpace::Bt601 => Some((0.299, 0.114)),
+            ColorSpace::Bt709 => Some((0.2126, 0.0722)),
+            ColorSpace::Bt2020 => Some((0.2627, 0.0593)),
+            ColorSpace::YCoCg => None,
+        }
+    }
+}
+
+
+// resolution of the pb/pr planes relative to y
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Subsampling {
+    // one pb/pr pair per 2x2 block
+    #[default]
+    Yuv420,
+    // one pb/pr pair per horizontal pair of pixels
+    Yuv422,
+    // one pb/pr pair per pixel
+    Yuv444,
+}
+
+impl Subsampling {
+
+    // horizontal and vertical number of pixels sharing each pb/pr pair
+    pub fn factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::Yuv420 => (2, 2),
+            Subsampling::Yuv422 => (2, 1),
+            Subsampling::Yuv444 => (1, 1),
+        }
+    }
+
+    // width and height of the pb/pr planes of a `width` x `height` image
+    pub fn plane_size(self, width: usize, height: usize) -> (usize, usize) {
+        let (x, y) = self.factors();
+        (width / x, height / y)
+    }
+}
+
+
+// how the decoder spreads each chroma sample over the pixels that share it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaUpsampling {
+    // copy it to every pixel of its cell (the fast path)
+    #[default]
+    Replicate,
+    // interpolate between the centres of neighbouring cells
+    Bilinear,
+    // interpolate, favouring cells whose luma is close to the pixel's
+    LumaGuided,
+}
+
+
+// how the decoder spreads rounding error when it turns pixels into integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    // round every sample to the nearest level
+    #[default]
+    None,
+    // offset samples by an 8x8 bayer threshold matrix before rounding
+    Ordered,
+    // carry each sample's rounding error on to its unvisited neighbours
+    FloydSteinberg,
+}
+
+
+// transform applied to luma before quantization
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockTransform {
+    // the original 2x2 transform into a, b, c and d, packed into codewords
+    #[default]
+    Haar2x2,
+    // an 8x8 type-ii dct with a per-frequency quantization matrix
+    Dct8x8,
+}
+
+
+// quantizer step for each frequency of an 8x8 dct, row-major with the
+// horizontal frequency varying fastest; recorded in the file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantMatrix {
+    pub steps: [u16; 64],
+}
+
+
+// quantized coefficients of one 8x8 luma block, in the same order as QuantMatrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DctBlock {
+    pub levels: [i64; 64],
+}
+
+
+// scale factor of frequency k in an orthonormal 8-point dct
+fn dct_norm(k: usize) -> f64 {
+    if k == 0 { (1.0f64 / 8.0).sqrt() } else { (2.0f64 / 8.0).sqrt() }
+}
+
+
+// cosine basis of an orthonormal 8-point dct, indexed [frequency][sample]
+pub fn dct_basis() -> [[f64; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for (k, row) in basis.iter_mut().enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = dct_norm(k) * (((2 * x + 1) * k) as f64 * std::f64::consts::PI / 16.0).cos();
+        }
+    }
+    basis
+}
+
+
+impl QuantMatrix {
+
+    // largest level each frequency can take, for level-shifted samples in [-128, 127]
+    pub fn max_levels(&self) -> [i64; 64] {
+        let basis = dct_basis();
+        // largest gain of each 1-d basis function over samples of magnitude 1
+        let gains: Vec<f64> = basis.iter().map(|row| row.iter().map(|v| v.abs()).sum()).collect();
+        let mut levels = [0; 64];
+        for (i, level) in levels.iter_mut().enumerate() {
+            let bound = 128.0 * gains[i % 8] * gains[i / 8];
+            *level = (bound / self.steps[i] as f64).ceil() as i64;
+        }
+        levels
+    }
+
+    // bits needed to store each frequency's level in two's complement
+    pub fn level_widths(&self) -> [u64; 64] {
+        self.max_levels().map(|level| 65 - level.leading_zeros() as u64)
+    }
+}
+
+
+// how quantized values are stored in the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntropyCoding {
+    // fixed-width fields: codewords, or the 8x8 dct bit stream
+    #[default]
+    Fixed,
+    // one canonical huffman table per field, stored in the payload
+    Huffman,
+    // an adaptive binary range coder with one model per field
+    RangeCoder,
+}
+
+
+// how the a coefficient (or dc level of the 8x8 dct) is coded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DcPrediction {
+    // every block stores its own value
+    #[default]
+    None,
+    // each block stores the difference from the med (loco-i) prediction
+    // made from its left, top and top-left neighbours
+    Med,
+}
+
+
+// encoded quanta with runs of flat blocks collapsed: a block whose b, c
+// and d are zero and whose chroma indices equal the previous block's is
+// folded into the run after the last block that was stored in full
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunLengthQuanta {
+    // a of every block, in row-major order
+    pub a: Vec<u64>,
+    // the blocks stored in full (their a is unused)
+    pub literals: Vec<EncodedQuanta>,
+    // for each literal, how many blocks after it repeat its chroma with zero b, c and d
+    pub runs: Vec<u64>,
+}